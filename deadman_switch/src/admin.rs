@@ -0,0 +1,460 @@
+//! Administrative endpoints and the role model guarding them.
+//!
+//! - Controllers (the canister's IC controllers) can do everything, including
+//!   granting roles and changing the deployment config.
+//! - Operators can pause and resume operations in an emergency and force-process
+//!   single accounts.
+//! - Auditors have read-only access to every account.
+//!
+//! Each role includes the permissions of the ones below it.
+
+use crate::audit::{self, AuditEvent};
+use crate::reconciliation::{self, ReconciliationReport};
+use crate::{
+    certification, deposits, reserves, scheduler, AccountEvent, AccountGuard, CustodyMode, DeadManError,
+    DeadManSwitchState, InitArgs, PauseScope, PayoutLegStatus, Result_, Timestamp, UserAccount, STATE,
+};
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::{is_controller, msg_caller};
+use ic_cdk::{query, update};
+use serde::Serialize;
+use std::ops::Bound;
+
+// Largest page `admin_list_users` returns
+const MAX_PAGE_SIZE: u32 = 100;
+
+/// Access level of a principal, ordered from least to most privileged
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, Serialize, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    Auditor,
+    Operator,
+    /// Implied by being a controller of the canister; cannot be granted
+    Controller,
+}
+
+/// The role of `principal`, if any
+fn role_of(principal: &Principal) -> Option<Role> {
+    if is_controller(principal) {
+        return Some(Role::Controller);
+    }
+    STATE.with(|state| state.borrow().roles.get(principal).copied())
+}
+
+/// Whether `principal` holds at least `required`
+pub fn has_role(principal: &Principal, required: Role) -> bool {
+    role_of(principal).is_some_and(|role| role >= required)
+}
+
+/// Check that the caller holds at least `required`
+fn authorize(required: Role) -> Result<Principal, DeadManError> {
+    let caller = msg_caller();
+    if !has_role(&caller, required) {
+        return Err(DeadManError::Unauthorized {
+            reason: format!("caller {} lacks the {:?} role", caller, required),
+        });
+    }
+    Ok(caller)
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct UserPage {
+    pub users: Vec<(Principal, UserAccount)>,
+    pub next: Option<Principal>, // Pass as `start_after` to fetch the next page
+    pub total: u64,
+}
+
+#[allow(non_camel_case_types)]
+#[derive(CandidType, Deserialize, Debug)]
+pub enum UserPageResult {
+    ok(UserPage),
+    err(DeadManError),
+}
+
+/// What an operator found out about a payout leg held for manual review
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, Serialize)]
+pub enum LegResolution {
+    /// The transfer did not execute; send it again as a new transaction
+    Retry,
+    /// The transfer executed in this ledger block
+    MarkPaid { block_index: u64 },
+}
+
+#[allow(non_camel_case_types)]
+#[derive(CandidType, Deserialize, Debug)]
+pub enum ReconciliationReportResult {
+    ok(Option<ReconciliationReport>), // None until the first run since the last upgrade
+    err(DeadManError),
+}
+
+#[allow(non_camel_case_types)]
+#[derive(CandidType, Deserialize, Debug)]
+pub enum RolesResult {
+    ok(Vec<(Principal, Role)>),
+    err(DeadManError),
+}
+
+/// Page through all accounts ordered by principal (auditors and up)
+#[query]
+fn admin_list_users(start_after: Option<Principal>, limit: u32) -> UserPageResult {
+    if let Err(e) = authorize(Role::Auditor) {
+        return UserPageResult::err(e);
+    }
+    let limit = limit.clamp(1, MAX_PAGE_SIZE) as usize;
+
+    STATE.with(|state| {
+        let s = state.borrow();
+        let range = match start_after {
+            Some(cursor) => s.users.range((Bound::Excluded(cursor), Bound::Unbounded)),
+            None => s.users.range(..),
+        };
+        // Fetch one extra entry to learn whether another page follows
+        let mut users: Vec<(Principal, UserAccount)> = range
+            .take(limit + 1)
+            .map(|(principal, account)| (*principal, account.clone()))
+            .collect();
+        let next = if users.len() > limit {
+            users.truncate(limit);
+            users.last().map(|(principal, _)| *principal)
+        } else {
+            None
+        };
+        UserPageResult::ok(UserPage {
+            users,
+            next,
+            total: s.users.len() as u64,
+        })
+    })
+}
+
+/// Run the timeout check for one account now instead of waiting for its deadline
+/// (operators and up). Timeouts and contestation periods are still respected.
+#[update]
+async fn admin_force_process(user: Principal) -> Result_ {
+    let caller = match authorize(Role::Operator) {
+        Ok(caller) => caller,
+        Err(e) => return Result_::err(e),
+    };
+    if !STATE.with(|state| state.borrow().users.contains_key(&user)) {
+        return Result_::err(DeadManError::not_found("User account"));
+    }
+
+    ic_cdk::println!("Force-processing {} on behalf of {}", user, caller);
+    match scheduler::process_now(user).await {
+        Ok(attempted) => Result_::ok(format!("Processed {}: {} transfer(s) sent", user, attempted)),
+        Err(e) => Result_::err(e),
+    }
+}
+
+/// Settle a payout leg held for manual review after checking the ledger (operators and up).
+/// Legs are held when retrying could pay twice or cannot succeed, e.g. when the outcome
+/// of an earlier attempt is unknown or the source holds less than the leg.
+#[update]
+fn admin_resolve_payout_leg(user: Principal, leg: u32, resolution: LegResolution) -> Result_ {
+    let caller = match authorize(Role::Operator) {
+        Ok(caller) => caller,
+        Err(e) => return Result_::err(e),
+    };
+    // Keeps the checker from sending this payout's transfers meanwhile
+    let _guard = match AccountGuard::acquire(user) {
+        Ok(guard) => guard,
+        Err(e) => return Result_::err(e),
+    };
+
+    let now = Timestamp::now();
+    let resolved = STATE.with(|state| {
+        let mut s = state.borrow_mut();
+        let account = s.users.get_mut(&user).ok_or_else(|| DeadManError::not_found("User account"))?;
+        let plan = account
+            .payout
+            .as_mut()
+            .ok_or_else(|| DeadManError::conflict("The account has no payout in progress"))?;
+        let custody_mode = plan.custody_mode;
+        let payout_leg = plan
+            .legs
+            .get_mut(leg as usize)
+            .ok_or_else(|| DeadManError::not_found("Payout leg"))?;
+        if !matches!(payout_leg.status, PayoutLegStatus::Failed { next_retry_at, .. } if next_retry_at == Timestamp::MAX) {
+            return Err(DeadManError::conflict("Only legs held for manual review can be resolved"));
+        }
+
+        match resolution {
+            LegResolution::Retry => {
+                payout_leg.status = PayoutLegStatus::Pending;
+                Ok(format!("Leg {} of {}'s payout will be sent again", leg, user))
+            }
+            LegResolution::MarkPaid { block_index } => {
+                payout_leg.status = PayoutLegStatus::Paid { block_index };
+                let event = AccountEvent::PayoutTransfer {
+                    amount: payout_leg.amount,
+                    fee: payout_leg.fee,
+                    to: payout_leg.to,
+                    block_index,
+                };
+                // Keep the tracked balance in line with the deposit subaccount
+                if custody_mode == CustodyMode::Custodial {
+                    account.balance = account.balance.saturating_sub(payout_leg.amount + payout_leg.fee);
+                }
+                account.record(now, event);
+                Ok(format!("Leg {} of {}'s payout marked as paid in block {}", leg, user, block_index))
+            }
+        }
+    });
+    let message = match resolved {
+        Ok(message) => message,
+        Err(e) => return Result_::err(e),
+    };
+
+    audit::append(
+        now,
+        AuditEvent::PayoutLegResolved {
+            by: caller,
+            owner: user,
+            leg,
+            resolution,
+        },
+    );
+    scheduler::reschedule(user);
+    certification::refresh(&[user]);
+    ic_cdk::println!("{} (resolved by {})", message, caller);
+    Result_::ok(message)
+}
+
+/// Pause one operation, or every operation if `scope` is None (operators and up).
+/// Pausing heartbeats pushes back every running timeout and contestation period
+/// by the length of the pause.
+#[update]
+fn admin_pause(scope: Option<PauseScope>, reason: String) -> Result_ {
+    let caller = match authorize(Role::Operator) {
+        Ok(caller) => caller,
+        Err(e) => return Result_::err(e),
+    };
+    if reason.trim().is_empty() {
+        return Result_::err(DeadManError::invalid_argument("reason", "a reason is required"));
+    }
+
+    let scopes = scope.map_or(PauseScope::ALL.to_vec(), |scope| vec![scope]);
+    let now = Timestamp::now();
+    let paused: Vec<PauseScope> = STATE.with(|state| {
+        let mut s = state.borrow_mut();
+        scopes
+            .into_iter()
+            .filter(|scope| s.pauses.pause(*scope, caller, reason.clone(), now))
+            .collect()
+    });
+    if paused.is_empty() {
+        return Result_::err(DeadManError::conflict("Already paused"));
+    }
+
+    for scope in &paused {
+        audit::append(
+            now,
+            AuditEvent::Paused {
+                by: caller,
+                scope: *scope,
+                reason: reason.clone(),
+            },
+        );
+    }
+    scheduler::reindex();
+    certification::refresh(&[]);
+    ic_cdk::println!("{:?} paused by {}: {}", paused, caller, reason);
+    Result_::ok(format!("Paused {:?}", paused))
+}
+
+/// Lift the pause on one operation, or on every operation if `scope` is None
+/// (operators and up). Accounts that became due meanwhile are processed right away.
+#[update]
+fn admin_resume(scope: Option<PauseScope>) -> Result_ {
+    let caller = match authorize(Role::Operator) {
+        Ok(caller) => caller,
+        Err(e) => return Result_::err(e),
+    };
+
+    let scopes = scope.map_or(PauseScope::ALL.to_vec(), |scope| vec![scope]);
+    let now = Timestamp::now();
+    let resumed: Vec<PauseScope> = STATE.with(|state| {
+        let mut s = state.borrow_mut();
+        scopes.into_iter().filter(|scope| s.pauses.resume(*scope, now)).collect()
+    });
+    if resumed.is_empty() {
+        return Result_::err(DeadManError::conflict("Not paused"));
+    }
+
+    for scope in &resumed {
+        audit::append(now, AuditEvent::Resumed { by: caller, scope: *scope });
+    }
+    scheduler::reindex();
+    certification::refresh(&[]);
+    ic_cdk::println!("{:?} resumed by {}", resumed, caller);
+    Result_::ok(format!("Resumed {:?}", resumed))
+}
+
+/// Take a reserves snapshot now instead of waiting for the next one (operators and up)
+#[update]
+async fn admin_snapshot_reserves() -> Result_ {
+    if let Err(e) = authorize(Role::Operator) {
+        return Result_::err(e);
+    }
+    match reserves::take_snapshot().await {
+        Ok(snapshot) => Result_::ok(format!(
+            "Snapshot taken: {} ckBTC owed to {} account(s), {} ckBTC on the ledger",
+            snapshot.liabilities, snapshot.accounts, snapshot.ledger_total
+        )),
+        Err(e) => Result_::err(e),
+    }
+}
+
+/// Reconcile tracked balances with the ledger now instead of waiting for the next run,
+/// e.g. after topping up a deposit subaccount (operators and up)
+#[update]
+async fn admin_reconcile() -> Result_ {
+    if let Err(e) = authorize(Role::Operator) {
+        return Result_::err(e);
+    }
+    match reconciliation::reconcile().await {
+        Ok(report) => Result_::ok(format!(
+            "Reconciled {} account(s): {} short, {} resolved, {} skipped, {} unreachable",
+            report.checked,
+            report.shortfalls.len(),
+            report.resolved.len(),
+            report.skipped,
+            report.unreachable
+        )),
+        Err(e) => Result_::err(e),
+    }
+}
+
+/// Poll the index for deposits now instead of waiting for the next poll (operators and up)
+#[update]
+async fn admin_poll_deposits() -> Result_ {
+    if let Err(e) = authorize(Role::Operator) {
+        return Result_::err(e);
+    }
+    match deposits::poll().await {
+        Ok(summary) => Result_::ok(format!(
+            "Polled {} account(s): {} deposit(s), {} ckBTC credited, {} skipped, {} failed",
+            summary.accounts, summary.deposits, summary.credited, summary.skipped, summary.failed
+        )),
+        Err(e) => Result_::err(e),
+    }
+}
+
+/// Latest reconciliation of tracked balances with the ledger (auditors and up)
+#[query]
+fn admin_get_reconciliation_report() -> ReconciliationReportResult {
+    if let Err(e) = authorize(Role::Auditor) {
+        return ReconciliationReportResult::err(e);
+    }
+    ReconciliationReportResult::ok(reconciliation::latest())
+}
+
+/// Check that `args` switch the ledger only while nothing refers to the current one.
+/// Applies to `admin_update_config` and to upgrade arguments alike.
+pub fn check_ledger_switch(s: &DeadManSwitchState, args: &InitArgs) -> Result<(), DeadManError> {
+    if args.ckbtc_ledger_canister_id.is_some_and(|ledger| ledger != s.config.ckbtc_ledger)
+        && s.users.values().any(|a| {
+            a.balance > 0
+                || a.payout.is_some()
+                || a.custody_mode == CustodyMode::Allowance
+                || a.legacy_balance.is_some()
+                || a.pending_withdrawal.is_some()
+        })
+    {
+        return Err(DeadManError::conflict(
+            "The ledger cannot change while funds are deposited, allowances are in use or payouts are running",
+        ));
+    }
+    Ok(())
+}
+
+/// Change the deployment config (controllers only). The ledger can only be switched
+/// while no funds are held and no allowance, payout or withdrawal is in use, since
+/// balances, allowances and transfers refer to the current ledger.
+#[update]
+fn admin_update_config(args: InitArgs) -> Result_ {
+    let caller = match authorize(Role::Controller) {
+        Ok(caller) => caller,
+        Err(e) => return Result_::err(e),
+    };
+
+    STATE.with(|state| {
+        let mut s = state.borrow_mut();
+        if let Err(e) = check_ledger_switch(&s, &args) {
+            return Result_::err(e);
+        }
+
+        let mut config = s.config.clone();
+        if let Err(e) = config.apply(args) {
+            return Result_::err(e);
+        }
+        ic_cdk::println!("Config updated by {}: {:?}", caller, config);
+        audit::append(
+            Timestamp::now(),
+            AuditEvent::ConfigUpdated {
+                by: caller,
+                config: config.clone(),
+            },
+        );
+        s.config = config;
+        Result_::ok("Config updated".to_string())
+    })
+}
+
+/// Give a principal the operator or auditor role (controllers only)
+#[update]
+fn admin_grant_role(principal: Principal, role: Role) -> Result_ {
+    let caller = match authorize(Role::Controller) {
+        Ok(caller) => caller,
+        Err(e) => return Result_::err(e),
+    };
+    if role == Role::Controller {
+        return Result_::err(DeadManError::invalid_argument(
+            "role",
+            "controllers are managed through the canister settings",
+        ));
+    }
+    if principal == Principal::anonymous() {
+        return Result_::err(DeadManError::invalid_argument("principal", "anonymous principal cannot hold a role"));
+    }
+
+    STATE.with(|state| state.borrow_mut().roles.insert(principal, role));
+    audit::append(Timestamp::now(), AuditEvent::RoleGranted { by: caller, principal, role });
+    ic_cdk::println!("{} granted {:?} to {}", caller, role, principal);
+    Result_::ok(format!("Granted {:?} to {}", role, principal))
+}
+
+/// Take away a principal's operator or auditor role (controllers only)
+#[update]
+fn admin_revoke_role(principal: Principal) -> Result_ {
+    let caller = match authorize(Role::Controller) {
+        Ok(caller) => caller,
+        Err(e) => return Result_::err(e),
+    };
+
+    match STATE.with(|state| state.borrow_mut().roles.remove(&principal)) {
+        Some(role) => {
+            audit::append(Timestamp::now(), AuditEvent::RoleRevoked { by: caller, principal, role });
+            ic_cdk::println!("{} revoked {:?} from {}", caller, role, principal);
+            Result_::ok(format!("Revoked {:?} from {}", role, principal))
+        }
+        None => Result_::err(DeadManError::not_found(format!("Role of {}", principal))),
+    }
+}
+
+/// Granted operator and auditor roles (auditors and up)
+#[query]
+fn admin_list_roles() -> RolesResult {
+    if let Err(e) = authorize(Role::Auditor) {
+        return RolesResult::err(e);
+    }
+    STATE.with(|state| {
+        RolesResult::ok(
+            state
+                .borrow()
+                .roles
+                .iter()
+                .map(|(principal, role)| (*principal, *role))
+                .collect(),
+        )
+    })
+}
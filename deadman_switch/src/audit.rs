@@ -0,0 +1,399 @@
+//! Canister-wide audit log, kept in stable memory.
+//!
+//! Payouts, withdrawals, setting changes and operator actions are appended as
+//! ICRC-3 style blocks: every block is an ICRC-3 `Value` map holding the block type
+//! (`btype`), time (`ts`), the event (`tx`) and the hash of the previous block
+//! (`phash`, absent in the first block). The hash of the newest block and its index
+//! are certified, so a client that trusts the certificate can check every block it
+//! fetched back to the tip, and no block can be rewritten without changing the tip.
+//!
+//! Blocks are stored exactly as they were hashed. Unlike the per-account history,
+//! the log is never pruned and survives the removal of the accounts it mentions.
+//! Heartbeats are not logged.
+//!
+//! Like any ICRC-3 log the blocks are public, so a beneficiary can check that a payout
+//! to them happened without holding a role.
+
+use crate::certification::{self, Reveal};
+use crate::admin::LegResolution;
+use crate::{
+    AccountEvent, Beneficiary, CanisterConfig, FeatureFlags, Memory, PauseScope, Role, Timestamp, MEMORY_MANAGER,
+};
+use candid::{Nat, Principal};
+use ic_certification::{fork, labeled, leaf, HashTree};
+use ic_cdk::query;
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{StableBTreeMap, Storable};
+use icrc_ledger_types::icrc::generic_value::{Hash, ICRC3Map, ICRC3Value};
+use icrc_ledger_types::icrc1::account::Account;
+use icrc_ledger_types::icrc3::blocks::{BlockWithId, GetBlocksRequest, GetBlocksResult, ICRC3DataCertificate};
+use serde_bytes::ByteBuf;
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+const AUDIT_MEMORY_ID: MemoryId = MemoryId::new(2);
+
+// Most blocks a single `icrc3_get_blocks` call returns, across all requested ranges
+const MAX_BLOCKS_PER_RESPONSE: u64 = 100;
+
+/// A block exactly as it was hashed
+struct StoredBlock(ICRC3Value);
+
+impl Storable for StoredBlock {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(&self.0).expect("failed to encode audit block"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        StoredBlock(candid::decode_one(&bytes).expect("failed to decode audit block"))
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    static BLOCKS: RefCell<StableBTreeMap<u64, StoredBlock, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(AUDIT_MEMORY_ID))),
+    );
+}
+
+/// Something worth a block in the audit log, besides account events
+#[derive(Clone, Debug)]
+pub enum AuditEvent {
+    /// The account was removed after its payout completed
+    AccountClosed { owner: Principal },
+    ConfigUpdated { by: Principal, config: CanisterConfig },
+    RoleGranted { by: Principal, principal: Principal, role: Role },
+    RoleRevoked { by: Principal, principal: Principal, role: Role },
+    Paused { by: Principal, scope: PauseScope, reason: String },
+    Resumed { by: Principal, scope: PauseScope },
+    PayoutLegResolved { by: Principal, owner: Principal, leg: u32, resolution: LegResolution },
+}
+
+fn nat(n: u64) -> ICRC3Value {
+    ICRC3Value::Nat(Nat::from(n))
+}
+
+fn text(s: impl Into<String>) -> ICRC3Value {
+    ICRC3Value::Text(s.into())
+}
+
+fn principal(p: &Principal) -> ICRC3Value {
+    ICRC3Value::Blob(ByteBuf::from(p.as_slice().to_vec()))
+}
+
+/// An account the way ICRC-3 encodes it: the owner, then the subaccount if there is one
+fn account(account: &Account) -> ICRC3Value {
+    let mut parts = vec![principal(&account.owner)];
+    if let Some(subaccount) = account.subaccount {
+        parts.push(ICRC3Value::Blob(ByteBuf::from(subaccount.to_vec())));
+    }
+    ICRC3Value::Array(parts)
+}
+
+fn beneficiaries(list: &[Beneficiary]) -> ICRC3Value {
+    ICRC3Value::Array(
+        list.iter()
+            .map(|b| {
+                ICRC3Value::Map(map([
+                    ("account", account(&b.account())),
+                    ("percentage", nat(b.percentage as u64)),
+                ]))
+            })
+            .collect(),
+    )
+}
+
+fn map<const N: usize>(fields: [(&str, ICRC3Value); N]) -> ICRC3Map {
+    fields.into_iter().map(|(key, value)| (key.to_string(), value)).collect()
+}
+
+impl AuditEvent {
+    /// Block type and transaction fields of the block
+    fn encode(&self) -> (&'static str, ICRC3Map) {
+        match self {
+            AuditEvent::AccountClosed { owner } => ("dms_closed", map([("owner", principal(owner))])),
+            AuditEvent::ConfigUpdated { by, config } => {
+                // Destructured so that a new config field cannot be left out of the block
+                let CanisterConfig {
+                    ckbtc_ledger,
+                    ckbtc_minter,
+                    ckbtc_index,
+                    check_interval,
+                    default_contestation_period,
+                    features:
+                        FeatureFlags {
+                            allowance_mode,
+                            trusted_party_cancellation,
+                            mock_balance,
+                        },
+                    history_retention,
+                    history_max_entries,
+                } = config;
+                let mut tx = map([
+                    ("by", principal(by)),
+                    ("ckbtc_ledger", principal(ckbtc_ledger)),
+                    ("check_interval", nat(check_interval.as_secs())),
+                    ("default_contestation_period", nat(default_contestation_period.as_secs())),
+                    ("allowance_mode", nat(*allowance_mode as u64)),
+                    ("trusted_party_cancellation", nat(*trusted_party_cancellation as u64)),
+                    ("mock_balance", nat(*mock_balance as u64)),
+                ]);
+                // Unset fields are left out, like an account without a subaccount
+                if let Some(minter) = ckbtc_minter {
+                    tx.insert("ckbtc_minter".to_string(), principal(minter));
+                }
+                if let Some(index) = ckbtc_index {
+                    tx.insert("ckbtc_index".to_string(), principal(index));
+                }
+                if let Some(retention) = history_retention {
+                    tx.insert("history_retention".to_string(), nat(retention.as_secs()));
+                }
+                if let Some(max_entries) = history_max_entries {
+                    tx.insert("history_max_entries".to_string(), nat(*max_entries));
+                }
+                ("dms_config", tx)
+            }
+            AuditEvent::RoleGranted { by, principal: p, role } => (
+                "dms_role_granted",
+                map([("by", principal(by)), ("principal", principal(p)), ("role", text(format!("{:?}", role)))]),
+            ),
+            AuditEvent::RoleRevoked { by, principal: p, role } => (
+                "dms_role_revoked",
+                map([("by", principal(by)), ("principal", principal(p)), ("role", text(format!("{:?}", role)))]),
+            ),
+            AuditEvent::Paused { by, scope, reason } => (
+                "dms_paused",
+                map([
+                    ("by", principal(by)),
+                    ("scope", text(format!("{:?}", scope))),
+                    ("reason", text(reason.clone())),
+                ]),
+            ),
+            AuditEvent::Resumed { by, scope } => (
+                "dms_resumed",
+                map([("by", principal(by)), ("scope", text(format!("{:?}", scope)))]),
+            ),
+            AuditEvent::PayoutLegResolved { by, owner, leg, resolution } => {
+                let mut tx = map([("by", principal(by)), ("owner", principal(owner)), ("leg", nat(*leg as u64))]);
+                match resolution {
+                    LegResolution::Retry => {
+                        tx.insert("resolution".to_string(), text("retry"));
+                    }
+                    LegResolution::MarkPaid { block_index } => {
+                        tx.insert("resolution".to_string(), text("paid"));
+                        tx.insert("ledger_block".to_string(), nat(*block_index));
+                    }
+                }
+                ("dms_payout_leg_resolved", tx)
+            }
+        }
+    }
+}
+
+/// Block type and transaction fields for an account event, or None if it is not audited
+fn encode_account_event(event: &AccountEvent) -> Option<(&'static str, ICRC3Map)> {
+    let encoded = match event {
+        // Too frequent to be worth a block, and only of interest to the owner
+        AccountEvent::Heartbeat { .. } => return None,
+        // Only written by migrations, never as a new event
+        AccountEvent::Legacy { .. } => return None,
+        AccountEvent::Registered { timeout, beneficiary } => (
+            "dms_registered",
+            map([("timeout", nat(timeout.as_secs())), ("beneficiary", account(beneficiary))]),
+        ),
+        AccountEvent::BalanceSynced { previous, current } => (
+            "dms_balance_synced",
+            map([("previous", nat(*previous)), ("current", nat(*current))]),
+        ),
+        AccountEvent::DepositReceived { amount, from, block_index, credited } => {
+            let mut tx = map([
+                ("amount", nat(*amount)),
+                ("ledger_block", nat(*block_index)),
+                ("credited", nat(*credited)),
+            ]);
+            if let Some(from) = from {
+                tx.insert("from".to_string(), account(from));
+            }
+            ("dms_deposit", tx)
+        }
+        AccountEvent::Withdrawal { amount, fee, to, block_index } => (
+            "dms_withdrawal",
+            map([
+                ("amount", nat(*amount)),
+                ("fee", nat(*fee)),
+                ("to", account(to)),
+                ("ledger_block", nat(*block_index)),
+            ]),
+        ),
+        AccountEvent::TimeoutDetected { contestation_period } => (
+            "dms_timeout_detected",
+            map([("contestation_period", nat(contestation_period.as_secs()))]),
+        ),
+        AccountEvent::TimeoutCancelled { by } => ("dms_timeout_cancelled", map([("by", principal(by))])),
+        AccountEvent::PayoutStarted { amount, transfers } => (
+            "dms_payout_started",
+            map([("amount", nat(*amount)), ("transfers", nat(*transfers as u64))]),
+        ),
+        AccountEvent::PayoutTransfer { amount, fee, to, block_index } => (
+            "dms_payout_transfer",
+            map([
+                ("amount", nat(*amount)),
+                ("fee", nat(*fee)),
+                ("to", account(to)),
+                ("ledger_block", nat(*block_index)),
+            ]),
+        ),
+        AccountEvent::PayoutTransferFailed { amount, to, attempt, error } => (
+            "dms_payout_transfer_failed",
+            map([
+                ("amount", nat(*amount)),
+                ("to", account(to)),
+                ("attempt", nat(*attempt as u64)),
+                ("error", text(error.clone())),
+            ]),
+        ),
+        AccountEvent::TimeoutChanged { old, new } => (
+            "dms_timeout_changed",
+            map([("old", nat(old.as_secs())), ("new", nat(new.as_secs()))]),
+        ),
+        AccountEvent::ContestationPeriodChanged { old, new } => (
+            "dms_contestation_period_changed",
+            map([("old", nat(old.as_secs())), ("new", nat(new.as_secs()))]),
+        ),
+        AccountEvent::BeneficiariesChanged { old, new } => (
+            "dms_beneficiaries_changed",
+            map([("old", beneficiaries(old)), ("new", beneficiaries(new))]),
+        ),
+        AccountEvent::TrustedPartyAdded { principal: p } => {
+            ("dms_trusted_party_added", map([("principal", principal(p))]))
+        }
+        AccountEvent::TrustedPartyRemoved { principal: p } => {
+            ("dms_trusted_party_removed", map([("principal", principal(p))]))
+        }
+        AccountEvent::CustodyModeChanged { old, new } => (
+            "dms_custody_mode_changed",
+            map([("old", text(format!("{:?}", old))), ("new", text(format!("{:?}", new)))]),
+        ),
+        AccountEvent::LegacyBalanceMoved { legacy, credited, block_index } => {
+            let mut tx = map([("legacy", nat(*legacy)), ("credited", nat(*credited))]);
+            if let Some(block_index) = block_index {
+                tx.insert("ledger_block".to_string(), nat(*block_index));
+            }
+            ("dms_legacy_balance_moved", tx)
+        }
+        AccountEvent::BalanceDriftDetected { tracked, ledger } => (
+            "dms_balance_drift_detected",
+            map([("tracked", nat(*tracked)), ("ledger", nat(*ledger))]),
+        ),
+        AccountEvent::BalanceDriftResolved { tracked, ledger } => (
+            "dms_balance_drift_resolved",
+            map([("tracked", nat(*tracked)), ("ledger", nat(*ledger))]),
+        ),
+    };
+    Some(encoded)
+}
+
+/// Index and hash of the newest block
+fn tip(blocks: &StableBTreeMap<u64, StoredBlock, Memory>) -> Option<(u64, Hash)> {
+    blocks.last_key_value().map(|(index, block)| (index, block.0.hash()))
+}
+
+/// Append a block for `event` and certify the new tip
+pub fn append(timestamp: Timestamp, event: AuditEvent) {
+    let (btype, tx) = event.encode();
+    append_block(timestamp, btype, tx);
+}
+
+fn append_block(timestamp: Timestamp, btype: &str, tx: ICRC3Map) {
+    BLOCKS.with(|blocks| {
+        let mut blocks = blocks.borrow_mut();
+        let parent = tip(&blocks);
+
+        let mut block = map([("btype", text(btype)), ("ts", nat(timestamp.as_nanos())), ("tx", ICRC3Value::Map(tx))]);
+        if let Some((_, parent_hash)) = parent {
+            block.insert("phash".to_string(), ICRC3Value::Blob(ByteBuf::from(parent_hash.to_vec())));
+        }
+
+        let index = parent.map_or(0, |(index, _)| index + 1);
+        blocks.insert(index, StoredBlock(ICRC3Value::Map(block)));
+    });
+    certification::certify();
+}
+
+/// Record an account event if it belongs in the audit log
+pub fn record_account_event(owner: Principal, timestamp: Timestamp, event: &AccountEvent) {
+    if let Some((btype, mut tx)) = encode_account_event(event) {
+        tx.insert("owner".to_string(), principal(&owner));
+        append_block(timestamp, btype, tx);
+    }
+}
+
+fn leb128(mut n: u64) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    loop {
+        let byte = (n & 0x7f) as u8;
+        n >>= 7;
+        if n == 0 {
+            bytes.push(byte);
+            return bytes;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
+
+/// The tip as ICRC-3 lays it out in the certified tree (see `certification`)
+pub fn tip_tree() -> HashTree {
+    match BLOCKS.with(|blocks| tip(&blocks.borrow())) {
+        Some((index, hash)) => fork(
+            labeled("last_block_hash", leaf(hash.to_vec())),
+            labeled("last_block_index", leaf(leb128(index))),
+        ),
+        None => ic_certification::empty(),
+    }
+}
+
+/// Blocks of the audit log, up to 100 per call. Anyone may read them; the log is never archived.
+#[query]
+fn icrc3_get_blocks(requests: Vec<GetBlocksRequest>) -> GetBlocksResult {
+    BLOCKS.with(|blocks| {
+        let blocks = blocks.borrow();
+        let mut budget = MAX_BLOCKS_PER_RESPONSE;
+        let mut found = Vec::new();
+        for request in requests {
+            let Ok((start, length)) = request.as_start_and_length() else {
+                continue;
+            };
+            let length = length.min(budget);
+            for (index, block) in blocks.range(start..start.saturating_add(length)) {
+                found.push(BlockWithId {
+                    id: Nat::from(index),
+                    block: block.0,
+                });
+            }
+            budget -= length;
+        }
+
+        GetBlocksResult {
+            log_length: Nat::from(blocks.len()),
+            blocks: found,
+            archived_blocks: Vec::new(),
+        }
+    })
+}
+
+/// Certificate for the newest block's index and hash. Only available in query calls,
+/// and only once the log has a block.
+#[query]
+fn icrc3_get_tip_certificate() -> Option<ICRC3DataCertificate> {
+    if BLOCKS.with(|blocks| blocks.borrow().is_empty()) {
+        return None;
+    }
+    let certificate = ic_cdk::api::data_certificate()?;
+    let hash_tree = certification::encode_witness(&certification::witness(Reveal::AuditTip))?;
+    Some(ICRC3DataCertificate {
+        certificate: ByteBuf::from(certificate),
+        hash_tree,
+    })
+}
@@ -0,0 +1,261 @@
+//! The canister's certified data.
+//!
+//! Query responses come from a single replica, so anything a client acts on, e.g. a
+//! beneficiary waiting for a payout, is also kept in a hash tree whose root is set as
+//! the certified data. The subnet signs that root, and `get_certified_status` returns
+//! the signature together with a witness for one account. The tree is laid out as
+//!
+//! - `accounts/<sha256(owner principal)>`: Candid-encoded `CertifiedAccount`, the stored
+//!   fields `get_timeout_status` and `get_account_info` derive their answers from. Accounts
+//!   that do not exist (never registered, or closed after their payout) are absent. Keys
+//!   are hashed so that proving one account absent, which reveals its neighbours' keys,
+//!   names no other registrant.
+//!
+//! `get_timeout_status` and `get_account_info` stay uncertified and are deprecated in
+//! favour of `get_certified_status`.
+//! - `last_block_hash`, `last_block_index`: tip of the audit log, as ICRC-3 specifies.
+//! - `pauses`: Candid-encoded `PauseState`, which pushes deadlines back.
+//! - `reserves`: Candid-encoded `Option<ReservesSnapshot>`, the latest proof-of-reserves
+//!   root and totals (see `reserves`).
+//!
+//! Every update that changes an account calls `refresh` before it returns. The tree
+//! lives on the heap and is rebuilt from the state after an upgrade.
+
+use crate::admin::{self, Role};
+use crate::{
+    audit, reserves, AllowanceStatus, BalanceDrift, Beneficiary, CustodyMode, DeadManError, DurationSecs, PauseState, PayoutPlan,
+    Timestamp, UserAccount, STATE,
+};
+use candid::{CandidType, Deserialize, Principal};
+use ic_certification::{fork, labeled, labeled_hash, leaf, leaf_hash, pruned, AsHashTree, HashTree, RbTree};
+use ic_cdk::api::msg_caller;
+use ic_cdk::query;
+use serde::Serialize;
+use serde_bytes::ByteBuf;
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+
+thread_local! {
+    static ACCOUNTS: RefCell<RbTree<Vec<u8>, Vec<u8>>> = const { RefCell::new(RbTree::new()) };
+    static PAUSES: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+}
+
+/// What the certified tree commits to for one account
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct CertifiedAccount {
+    pub last_heartbeat: Timestamp,
+    pub timeout_duration: DurationSecs,
+    pub contestation_period: DurationSecs,
+    pub timeout_detected_at: Option<Timestamp>,
+    pub balance: u64,
+    pub custody_mode: CustodyMode,
+    pub allowance: Option<AllowanceStatus>,
+    pub beneficiaries: Vec<Beneficiary>,
+    pub payout: Option<PayoutPlan>,
+    pub balance_drift: Option<BalanceDrift>,
+}
+
+impl From<&UserAccount> for CertifiedAccount {
+    fn from(account: &UserAccount) -> Self {
+        Self {
+            last_heartbeat: account.last_heartbeat,
+            timeout_duration: account.timeout_duration_seconds,
+            contestation_period: account.contestation_period_seconds,
+            timeout_detected_at: account.timeout_detected_at,
+            balance: account.balance,
+            custody_mode: account.custody_mode,
+            allowance: account.allowance.clone(),
+            beneficiaries: account.beneficiaries.clone(),
+            payout: account.payout.clone(),
+            balance_drift: account.balance_drift.clone(),
+        }
+    }
+}
+
+fn encode<T: CandidType>(value: &T) -> Vec<u8> {
+    candid::encode_one(value).unwrap_or_else(|e| ic_cdk::trap(format!("Failed to encode certified data: {:?}", e)))
+}
+
+/// Label of `owner`'s account under `accounts`
+pub fn account_key(owner: &Principal) -> Vec<u8> {
+    Sha256::digest(owner.as_slice()).to_vec()
+}
+
+/// Put `owner`'s current account, or its absence, into the tree
+fn update_account(accounts: &mut RbTree<Vec<u8>, Vec<u8>>, owner: Principal, account: Option<&UserAccount>) {
+    let key = account_key(&owner);
+    match account {
+        Some(account) => {
+            let value = encode(&CertifiedAccount::from(account));
+            if accounts.get(&key) != Some(&value) {
+                accounts.insert(key, value);
+            }
+        }
+        None => accounts.delete(&key),
+    }
+}
+
+/// Re-certify the given accounts and the pauses after an update changed them
+pub fn refresh(owners: &[Principal]) {
+    STATE.with(|state| {
+        let s = state.borrow();
+        ACCOUNTS.with(|accounts| {
+            let mut accounts = accounts.borrow_mut();
+            for owner in owners {
+                update_account(&mut accounts, *owner, s.users.get(owner));
+            }
+        });
+        PAUSES.with(|pauses| *pauses.borrow_mut() = encode(&s.pauses));
+    });
+    certify();
+}
+
+/// Build the tree from scratch, e.g. after an upgrade
+pub fn rebuild() {
+    STATE.with(|state| {
+        let s = state.borrow();
+        ACCOUNTS.with(|accounts| {
+            let mut accounts = accounts.borrow_mut();
+            *accounts = RbTree::new();
+            for (owner, account) in s.users.iter() {
+                update_account(&mut accounts, *owner, Some(account));
+            }
+        });
+        PAUSES.with(|pauses| *pauses.borrow_mut() = encode(&s.pauses));
+    });
+    certify();
+}
+
+/// Part of the tree a witness reveals; everything else is pruned
+pub enum Reveal<'a> {
+    Nothing,
+    Account(&'a Principal),
+    AuditTip,
+    Reserves,
+}
+
+/// The certified tree, pruned down to what `reveal` asks for. Labels are in
+/// ascending order: `accounts` < `last_block_*` < `pauses` < `reserves`.
+pub fn witness(reveal: Reveal) -> HashTree {
+    let accounts = ACCOUNTS.with(|accounts| {
+        let accounts = accounts.borrow();
+        match reveal {
+            Reveal::Account(owner) => labeled("accounts", accounts.witness(&account_key(owner))),
+            _ => pruned(labeled_hash(b"accounts", &accounts.root_hash())),
+        }
+    });
+    let tip = audit::tip_tree();
+    let tip = match reveal {
+        Reveal::AuditTip => tip,
+        _ => pruned(tip.digest()),
+    };
+    let pauses = PAUSES.with(|pauses| {
+        let pauses = pauses.borrow();
+        match reveal {
+            Reveal::Account(_) => labeled("pauses", leaf(pauses.clone())),
+            _ => pruned(labeled_hash(b"pauses", &leaf_hash(&pauses))),
+        }
+    });
+    let snapshot = encode(&reserves::latest());
+    let reserves = match reveal {
+        Reveal::Reserves => labeled("reserves", leaf(snapshot)),
+        _ => pruned(labeled_hash(b"reserves", &leaf_hash(&snapshot))),
+    };
+    fork(fork(fork(accounts, tip), pauses), reserves)
+}
+
+/// Set the certified data to the root of the tree
+pub fn certify() {
+    let root = witness(Reveal::Nothing).digest();
+    // Unit tests run outside a canister, where there is no certified data
+    if cfg!(test) {
+        return;
+    }
+    ic_cdk::api::certified_data_set(root);
+}
+
+/// CBOR encoding of a witness, as clients and `ic-certification` expect it
+pub fn encode_witness(tree: &HashTree) -> Option<ByteBuf> {
+    let mut serializer = serde_cbor::ser::Serializer::new(Vec::new());
+    serializer.self_describe().ok()?;
+    tree.serialize(&mut serializer).ok()?;
+    Some(ByteBuf::from(serializer.into_inner()))
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct CertifiedStatus {
+    pub account: Option<CertifiedAccount>, // None if the owner has no account (anymore)
+    pub pauses: PauseState,
+    pub certificate: ByteBuf, // Signed by the subnet; its certified data is the witness' root hash
+    pub witness: ByteBuf, // CBOR hash tree revealing `accounts/<sha256(owner)>` and `pauses`
+}
+
+#[allow(non_camel_case_types, clippy::large_enum_variant)]
+#[derive(CandidType, Deserialize, Debug)]
+pub enum CertifiedStatusResult {
+    ok(CertifiedStatus),
+    err(DeadManError),
+}
+
+/// Certified status of `owner`'s account (the caller's if not given). Readable by the
+/// owner, their beneficiaries and trusted parties, and auditors. That an account does not
+/// exist can be proven to anyone. Only works in query calls, which carry a certificate.
+#[query]
+fn get_certified_status(owner: Option<Principal>) -> CertifiedStatusResult {
+    let caller = msg_caller();
+    let owner = owner.unwrap_or(caller);
+
+    let (account, pauses) = STATE.with(|state| {
+        let s = state.borrow();
+        (s.users.get(&owner).cloned(), s.pauses.clone())
+    });
+    if let Some(account) = &account {
+        let related = caller == owner
+            || account.beneficiaries.iter().any(|b| b.principal == caller)
+            || account.trusted_parties.contains(&caller);
+        if !related && !admin::has_role(&caller, Role::Auditor) {
+            return CertifiedStatusResult::err(DeadManError::Unauthorized {
+                reason: "only the owner, their beneficiaries and trusted parties can read this account".to_string(),
+            });
+        }
+    }
+
+    let Some(certificate) = ic_cdk::api::data_certificate() else {
+        return CertifiedStatusResult::err(DeadManError::internal("No certificate available, call as a query"));
+    };
+    let Some(witness) = encode_witness(&witness(Reveal::Account(&owner))) else {
+        return CertifiedStatusResult::err(DeadManError::internal("Failed to encode the witness"));
+    };
+    CertifiedStatusResult::ok(CertifiedStatus {
+        account: account.as_ref().map(CertifiedAccount::from),
+        pauses,
+        certificate: ByteBuf::from(certificate),
+        witness,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+        haystack.windows(needle.len()).any(|window| window == needle)
+    }
+
+    #[test]
+    fn proving_an_account_absent_names_no_other_registrant() {
+        let registrants: Vec<Principal> = (1..=5u8).map(|n| Principal::from_slice(&[n; 29])).collect();
+        let mut accounts: RbTree<Vec<u8>, Vec<u8>> = RbTree::new();
+        for owner in &registrants {
+            accounts.insert(account_key(owner), b"account".to_vec());
+        }
+
+        let absent = Principal::from_slice(&[9; 29]);
+        let witness = accounts.witness(&account_key(&absent));
+        assert_eq!(witness.digest(), accounts.root_hash());
+        let encoded = encode_witness(&witness).unwrap();
+        for owner in &registrants {
+            assert!(!contains(&encoded, owner.as_slice()));
+        }
+    }
+}
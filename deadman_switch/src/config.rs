@@ -0,0 +1,121 @@
+//! Deployment configuration.
+//!
+//! Everything that differs between a local replica, testnet and mainnet is passed
+//! as the install argument instead of being compiled in. `init` builds the config
+//! from `InitArgs` on top of the defaults below; `post_upgrade` applies the fields
+//! that are set on top of the stored config, so an upgrade without arguments keeps
+//! the current configuration.
+
+use crate::{DeadManError, DurationSecs, DEFAULT_CONTESTATION_PERIOD};
+use candid::{CandidType, Deserialize, Principal};
+use serde::Serialize;
+
+// Testnet ckBTC ledger, used when no ledger is given at install time
+const DEFAULT_CKBTC_LEDGER_CANISTER_ID: &str = "mxzaz-hqaaa-aaaar-qaada-cai";
+
+// How soon an account that is still due after a checker round is looked at again
+const DEFAULT_CHECK_INTERVAL: DurationSecs = DurationSecs::from_minutes(1);
+
+/// Optional behaviour that can be switched per deployment
+#[derive(CandidType, Deserialize, Clone, Debug, Serialize, PartialEq, Eq)]
+pub struct FeatureFlags {
+    /// Users may switch to ICRC-2 allowance custody
+    pub allowance_mode: bool,
+    /// Trusted parties may cancel a timeout during the contestation period
+    pub trusted_party_cancellation: bool,
+    /// `set_mock_balance` is callable (only exists in builds with the `demo` feature)
+    pub mock_balance: bool,
+}
+
+impl Default for FeatureFlags {
+    fn default() -> Self {
+        Self {
+            allowance_mode: true,
+            trusted_party_cancellation: true,
+            mock_balance: false,
+        }
+    }
+}
+
+/// Configuration stored in the canister state
+#[derive(CandidType, Deserialize, Clone, Debug, Serialize)]
+pub struct CanisterConfig {
+    pub ckbtc_ledger: Principal,
+    pub ckbtc_minter: Option<Principal>,
+    pub ckbtc_index: Option<Principal>,
+    pub check_interval: DurationSecs,
+    pub default_contestation_period: DurationSecs, // Applied to newly registered accounts
+    pub features: FeatureFlags,
+    pub history_retention: Option<DurationSecs>, // History entries older than this are dropped; None keeps them
+    pub history_max_entries: Option<u64>,        // Most recent entries kept per account; None keeps all
+}
+
+impl CanisterConfig {
+    /// Defaults for a fresh install without arguments
+    pub fn with_ledger(ckbtc_ledger: Principal) -> Self {
+        Self {
+            ckbtc_ledger,
+            ckbtc_minter: None,
+            ckbtc_index: None,
+            check_interval: DEFAULT_CHECK_INTERVAL,
+            default_contestation_period: DEFAULT_CONTESTATION_PERIOD,
+            features: FeatureFlags::default(),
+            history_retention: None,
+            history_max_entries: None,
+        }
+    }
+
+    /// Override every field that is set in `args`
+    pub fn apply(&mut self, args: InitArgs) -> Result<(), DeadManError> {
+        if let Some(ledger) = args.ckbtc_ledger_canister_id {
+            self.ckbtc_ledger = ledger;
+        }
+        if let Some(minter) = args.ckbtc_minter_canister_id {
+            self.ckbtc_minter = Some(minter);
+        }
+        if let Some(index) = args.ckbtc_index_canister_id {
+            self.ckbtc_index = Some(index);
+        }
+        if let Some(interval) = args.check_interval_seconds {
+            if interval == DurationSecs::ZERO {
+                return Err(DeadManError::invalid_argument("check_interval_seconds", "must be greater than 0"));
+            }
+            self.check_interval = interval;
+        }
+        if let Some(period) = args.default_contestation_period_seconds {
+            self.default_contestation_period = period;
+        }
+        if let Some(features) = args.features {
+            self.features = features;
+        }
+        if let Some(retention) = args.history_retention_seconds {
+            self.history_retention = (retention != DurationSecs::ZERO).then_some(retention);
+        }
+        if let Some(max_entries) = args.history_max_entries {
+            self.history_max_entries = (max_entries != 0).then_some(max_entries);
+        }
+        Ok(())
+    }
+}
+
+impl Default for CanisterConfig {
+    fn default() -> Self {
+        Self::with_ledger(
+            Principal::from_text(DEFAULT_CKBTC_LEDGER_CANISTER_ID).unwrap_or_else(|_| Principal::anonymous()),
+        )
+    }
+}
+
+/// Install and upgrade argument. Unset fields keep their default (install) or
+/// their current value (upgrade).
+#[derive(CandidType, Deserialize, Debug, Default)]
+pub struct InitArgs {
+    pub ckbtc_ledger_canister_id: Option<Principal>,
+    pub ckbtc_minter_canister_id: Option<Principal>,
+    pub ckbtc_index_canister_id: Option<Principal>,
+    pub check_interval_seconds: Option<DurationSecs>,
+    pub default_contestation_period_seconds: Option<DurationSecs>,
+    pub features: Option<FeatureFlags>,
+    pub history_retention_seconds: Option<DurationSecs>, // 0 keeps history forever
+    pub history_max_entries: Option<u64>,                // 0 keeps every entry
+}
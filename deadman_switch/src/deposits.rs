@@ -0,0 +1,426 @@
+//! Automatic deposit detection.
+//!
+//! When the deployment configures an ICRC index canister (`CanisterConfig::ckbtc_index`),
+//! the canister asks it every few minutes for new transactions on each custodial
+//! account's deposit subaccount. Incoming transfers and mints are credited to the
+//! account and recorded as `DepositReceived` with their ledger block, so users no
+//! longer have to call `sync_balance` after sending ckBTC.
+//!
+//! Each account remembers the newest block it has looked at
+//! (`UserAccount::last_deposit_block`). An account with more new transactions than one
+//! poll reads keeps where it stopped (`UserAccount::deposit_scan`) and the next poll
+//! continues from there, so older deposits are not skipped. Credits never take the tracked balance above
+//! the balance the index reports, so a deposit that `sync_balance` already counted is
+//! recorded but not credited twice. Accounts are locked while they are polled, like
+//! during a balance sync; busy ones are picked up by the next poll.
+
+use crate::{
+    certification, deposit_account, nat_to_u64, AccountEvent, AccountGuard, CustodyMode, DeadManError, Timestamp,
+    STATE,
+};
+use candid::{CandidType, Deserialize, Nat, Principal};
+use futures::future::join_all;
+use serde::Serialize;
+use ic_cdk::call::Call;
+use ic_cdk_timers::set_timer_interval;
+use icrc_ledger_types::icrc1::account::Account;
+use icrc_ledger_types::icrc3::transactions::Transaction;
+use std::cell::Cell;
+use std::time::Duration;
+
+// How often the index is polled
+const DEPOSIT_POLL_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+// Index queries in flight at once; their accounts stay locked until the batch is done
+const INDEX_BATCH_SIZE: usize = 50;
+
+// Transactions per `get_account_transactions` call, and calls per account and poll
+const INDEX_PAGE_SIZE: u64 = 100;
+const MAX_PAGES_PER_ACCOUNT: usize = 10;
+
+#[derive(CandidType, Deserialize)]
+struct GetAccountTransactionsArgs {
+    account: Account,
+    start: Option<Nat>, // Newest first, starting below this id; the newest if None
+    max_results: Nat,
+}
+
+#[derive(CandidType, Deserialize)]
+struct TransactionWithId {
+    id: Nat,
+    transaction: Transaction,
+}
+
+#[derive(CandidType, Deserialize)]
+struct GetTransactions {
+    balance: Nat,
+    transactions: Vec<TransactionWithId>,
+    oldest_tx_id: Option<Nat>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct GetTransactionsErr {
+    message: String,
+}
+
+/// An incoming transaction on a deposit subaccount
+struct Deposit {
+    block_index: u64,
+    amount: u64,
+    from: Option<Account>, // None for mints, e.g. BTC converted by the ckBTC minter
+}
+
+/// What the index knows about a deposit subaccount beyond the last poll
+struct IndexUpdate {
+    deposits: Vec<Deposit>, // Oldest first
+    newest: Option<u64>,    // Newest block touching the account, incoming or not
+    balance: u64,
+    resume: Option<u64>, // Oldest block read, if the page limit stopped the scan before `after`
+}
+
+/// A scan of the index that hit the page limit. Blocks from `below` down to
+/// `last_deposit_block` are still to be read; `newest` becomes `last_deposit_block`
+/// once they are.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, Serialize, PartialEq, Eq)]
+pub struct DepositScan {
+    pub newest: u64,
+    pub below: u64,
+}
+
+/// Outcome of one poll over every custodial account
+#[derive(Debug, Default)]
+pub struct PollSummary {
+    pub accounts: u64,
+    pub deposits: u64,
+    pub credited: u64,
+    pub skipped: u64, // Busy
+    pub failed: u64,  // The index could not be read
+}
+
+thread_local! {
+    static RUNNING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Clears RUNNING when dropped, including when a trap drops the poll's future
+struct PollGuard;
+
+impl Drop for PollGuard {
+    fn drop(&mut self) {
+        RUNNING.with(|running| running.set(false));
+    }
+}
+
+/// Poll the index periodically, if one is configured
+pub fn start() {
+    set_timer_interval(DEPOSIT_POLL_INTERVAL, || async {
+        let configured = STATE.with(|state| state.borrow().config.ckbtc_index.is_some());
+        if configured {
+            let _ = poll().await;
+        }
+    });
+}
+
+async fn get_account_transactions(
+    index: Principal,
+    account: Account,
+    start: Option<u64>,
+) -> Result<GetTransactions, String> {
+    let args = GetAccountTransactionsArgs {
+        account,
+        start: start.map(Nat::from),
+        max_results: Nat::from(INDEX_PAGE_SIZE),
+    };
+    let response = Call::unbounded_wait(index, "get_account_transactions")
+        .with_arg((args,))
+        .await
+        .map_err(|e| format!("index call failed: {}", e))?;
+    let (result,): (Result<GetTransactions, GetTransactionsErr>,) =
+        response.candid().map_err(|e| format!("undecodable index reply: {}", e))?;
+    result.map_err(|e| e.message)
+}
+
+/// Amount and sender if `transaction` moved funds into `account`
+fn incoming(account: &Account, transaction: &Transaction) -> Option<(u64, Option<Account>)> {
+    if let Some(mint) = transaction.mint.as_ref().filter(|mint| mint.to == *account) {
+        return Some((nat_to_u64(&mint.amount), None));
+    }
+    transaction
+        .transfer
+        .as_ref()
+        .filter(|transfer| transfer.to == *account && transfer.from != *account)
+        .map(|transfer| (nat_to_u64(&transfer.amount), Some(transfer.from)))
+}
+
+/// How much of each deposit to credit. Together they never take `tracked` above the
+/// balance the index reports; whatever is already tracked, e.g. by `sync_balance`, is
+/// not credited again.
+fn capped_credits(tracked: u64, index_balance: u64, amounts: impl IntoIterator<Item = u64>) -> Vec<u64> {
+    let mut room = index_balance.saturating_sub(tracked);
+    amounts
+        .into_iter()
+        .map(|amount| {
+            let credited = amount.min(room);
+            room -= credited;
+            credited
+        })
+        .collect()
+}
+
+/// Page back through the index until `after`, from the newest transaction or from
+/// below `resume_below` when continuing an interrupted scan
+async fn read_index(
+    index: Principal,
+    account: Account,
+    after: Option<u64>,
+    resume_below: Option<u64>,
+) -> Result<IndexUpdate, String> {
+    let mut update = IndexUpdate {
+        deposits: Vec::new(),
+        newest: None,
+        balance: 0,
+        resume: None,
+    };
+    let mut start = resume_below;
+    for page_number in 0..MAX_PAGES_PER_ACCOUNT {
+        let page = get_account_transactions(index, account, start).await?;
+        if page_number == 0 {
+            update.balance = nat_to_u64(&page.balance);
+        }
+
+        let mut done = page.transactions.is_empty();
+        for tx in &page.transactions {
+            let id = nat_to_u64(&tx.id);
+            // Some index versions include `start` itself
+            if start.is_some_and(|start| id >= start) {
+                continue;
+            }
+            if after.is_some_and(|after| id <= after) {
+                done = true;
+                break;
+            }
+            update.newest.get_or_insert(id);
+            if let Some((amount, from)) = incoming(&account, &tx.transaction) {
+                update.deposits.push(Deposit {
+                    block_index: id,
+                    amount,
+                    from,
+                });
+            }
+            start = Some(id);
+        }
+        let oldest = page.oldest_tx_id.as_ref().map(nat_to_u64);
+        if done || oldest.is_none() || oldest == start {
+            update.deposits.reverse();
+            return Ok(update);
+        }
+    }
+
+    ic_cdk::println!(
+        "Deposit poll for {} stopped after {} transactions, the next poll continues below block {:?}",
+        account,
+        MAX_PAGES_PER_ACCOUNT as u64 * INDEX_PAGE_SIZE,
+        start
+    );
+    update.resume = start;
+    update.deposits.reverse();
+    Ok(update)
+}
+
+/// Credit new deposits to every custodial account
+pub async fn poll() -> Result<PollSummary, DeadManError> {
+    let Some(index) = STATE.with(|state| state.borrow().config.ckbtc_index) else {
+        return Err(DeadManError::FeatureDisabled {
+            feature: "Deposit detection (no index canister configured)".to_string(),
+        });
+    };
+    if RUNNING.with(|running| running.replace(true)) {
+        return Err(DeadManError::Busy);
+    }
+    let _guard = PollGuard;
+
+    let owners: Vec<(Principal, Option<u64>, Option<DepositScan>)> = STATE.with(|state| {
+        state
+            .borrow()
+            .users
+            .iter()
+            .filter(|(_, account)| account.custody_mode == CustodyMode::Custodial)
+            .map(|(owner, account)| (*owner, account.last_deposit_block, account.deposit_scan))
+            .collect()
+    });
+
+    let mut summary = PollSummary::default();
+    let mut changed = Vec::new();
+    for batch in owners.chunks(INDEX_BATCH_SIZE) {
+        let locked: Vec<(Principal, Option<u64>, Option<DepositScan>, AccountGuard)> = batch
+            .iter()
+            .filter_map(|(owner, after, scan)| match AccountGuard::acquire(*owner) {
+                Ok(guard) => Some((*owner, *after, *scan, guard)),
+                Err(_) => {
+                    summary.skipped += 1;
+                    None
+                }
+            })
+            .collect();
+        let updates = join_all(
+            locked.iter().map(|(owner, after, scan, _)| {
+                read_index(index, deposit_account(owner), *after, scan.map(|scan| scan.below))
+            }),
+        )
+        .await;
+
+        let now = Timestamp::now();
+        for ((owner, _, scan, _guard), update) in locked.iter().zip(updates) {
+            let update = match update {
+                Ok(update) => update,
+                Err(e) => {
+                    ic_cdk::println!("Deposit poll failed for {}: {}", owner, e);
+                    summary.failed += 1;
+                    continue;
+                }
+            };
+            summary.accounts += 1;
+            if update.newest.is_none() && scan.is_none() {
+                continue;
+            }
+
+            STATE.with(|state| {
+                let mut s = state.borrow_mut();
+                // Closed or switched to allowance mode while the index was queried
+                let Some(account) = s
+                    .users
+                    .get_mut(owner)
+                    .filter(|account| account.custody_mode == CustodyMode::Custodial)
+                else {
+                    return;
+                };
+                let credits = capped_credits(
+                    account.balance,
+                    update.balance,
+                    update.deposits.iter().map(|deposit| deposit.amount),
+                );
+                for (deposit, credited) in update.deposits.into_iter().zip(credits) {
+                    account.balance += credited;
+                    account.record(
+                        now,
+                        AccountEvent::DepositReceived {
+                            amount: deposit.amount,
+                            from: deposit.from,
+                            block_index: deposit.block_index,
+                            credited,
+                        },
+                    );
+                    ic_cdk::println!(
+                        "Deposit of {} ckBTC to {} in block {}, {} credited",
+                        deposit.amount, owner, deposit.block_index, credited
+                    );
+                    summary.deposits += 1;
+                    summary.credited += credited;
+                }
+                // A continued scan ends at the block the first part started from
+                let newest = scan.map(|scan| scan.newest).or(update.newest);
+                match (update.resume, newest) {
+                    (Some(below), Some(newest)) => account.deposit_scan = Some(DepositScan { newest, below }),
+                    _ => {
+                        account.deposit_scan = None;
+                        account.last_deposit_block = newest.or(account.last_deposit_block);
+                    }
+                }
+            });
+            changed.push(*owner);
+        }
+    }
+
+    certification::refresh(&changed);
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use icrc_ledger_types::icrc3::transactions::{Burn, Mint, Transfer};
+
+    fn account(id: u8) -> Account {
+        Account {
+            owner: Principal::from_slice(&[id]),
+            subaccount: Some([id; 32]),
+        }
+    }
+
+    fn transfer(from: Account, to: Account, amount: u64) -> Transaction {
+        Transaction::transfer(
+            Transfer {
+                amount: Nat::from(amount),
+                from,
+                to,
+                spender: None,
+                memo: None,
+                fee: None,
+                created_at_time: None,
+            },
+            0,
+        )
+    }
+
+    #[test]
+    fn transfers_into_the_account_are_incoming() {
+        let deposit = account(1);
+        let sender = account(2);
+        assert_eq!(incoming(&deposit, &transfer(sender, deposit, 500)), Some((500, Some(sender))));
+    }
+
+    #[test]
+    fn mints_are_incoming_without_a_sender() {
+        let deposit = account(1);
+        let mint = Transaction::mint(
+            Mint {
+                amount: Nat::from(700u64),
+                to: deposit,
+                memo: None,
+                created_at_time: None,
+                fee: None,
+            },
+            0,
+        );
+        assert_eq!(incoming(&deposit, &mint), Some((700, None)));
+        assert_eq!(incoming(&account(2), &mint), None);
+    }
+
+    #[test]
+    fn outgoing_and_self_transfers_and_burns_are_not_incoming() {
+        let deposit = account(1);
+        let other = account(2);
+        assert_eq!(incoming(&deposit, &transfer(deposit, other, 500)), None);
+        assert_eq!(incoming(&deposit, &transfer(deposit, deposit, 500)), None);
+        assert_eq!(incoming(&deposit, &transfer(other, account(3), 500)), None);
+        let burn = Transaction::burn(
+            Burn {
+                amount: Nat::from(500u64),
+                from: deposit,
+                spender: None,
+                memo: None,
+                created_at_time: None,
+                fee: None,
+            },
+            0,
+        );
+        assert_eq!(incoming(&deposit, &burn), None);
+    }
+
+    #[test]
+    fn deposits_are_credited_in_full_while_the_index_balance_covers_them() {
+        assert_eq!(capped_credits(1_000, 1_800, [300, 500]), vec![300, 500]);
+    }
+
+    #[test]
+    fn deposits_already_tracked_are_not_credited_again() {
+        // sync_balance counted the first deposit before the poll saw it
+        assert_eq!(capped_credits(1_300, 1_800, [300, 500]), vec![300, 200]);
+        assert_eq!(capped_credits(1_800, 1_800, [300, 500]), vec![0, 0]);
+    }
+
+    #[test]
+    fn tracked_above_the_index_balance_credits_nothing() {
+        assert_eq!(capped_credits(2_000, 1_800, [300]), vec![0]);
+        assert_eq!(capped_credits(0, 0, []), Vec::<u64>::new());
+    }
+}
@@ -0,0 +1,97 @@
+//! Errors returned by the canister's endpoints.
+//!
+//! Every `*Result` type carries a `DeadManError` in its `err` case, so clients can
+//! match on the variant instead of the message. `Display` renders the message the
+//! endpoints used to return as plain text.
+
+use crate::{CustodyMode, LedgerError, PauseScope};
+use candid::{CandidType, Deserialize};
+use serde::Serialize;
+
+#[derive(CandidType, Deserialize, Clone, Debug, Serialize, PartialEq, Eq)]
+pub enum DeadManError {
+    /// The caller has no account
+    NotRegistered,
+    AlreadyRegistered,
+    /// The caller lacks the role or relationship the method requires
+    Unauthorized { reason: String },
+    InvalidArgument { field: String, reason: String },
+    InsufficientBalance { available: u64, required: u64 },
+    /// The operation was paused by the operators
+    Paused { scope: PauseScope, reason: String },
+    /// A payout has started; the account can no longer be changed
+    PayoutInProgress,
+    /// Another operation on the same account, or a checker round, is running; retry shortly
+    Busy,
+    /// The account's custody mode does not support the operation
+    WrongCustodyMode { mode: CustodyMode },
+    /// Switched off on this deployment
+    FeatureDisabled { feature: String },
+    /// The beneficiary, trusted party, role or timeout to act on does not exist
+    NotFound { what: String },
+    /// The request conflicts with the current state, e.g. it would change nothing
+    Conflict { reason: String },
+    /// A ledger call failed; no funds moved
+    Ledger(LedgerError),
+    /// A ledger transfer may or may not have executed; retrying the same withdrawal or `sync_balance` settles it
+    LedgerOutcomeUnknown(LedgerError),
+    /// Something that should not happen; the message is meant for the developers
+    Internal { message: String },
+}
+
+impl DeadManError {
+    pub fn invalid_argument(field: &str, reason: impl Into<String>) -> Self {
+        DeadManError::InvalidArgument {
+            field: field.to_string(),
+            reason: reason.into(),
+        }
+    }
+
+    pub fn not_found(what: impl Into<String>) -> Self {
+        DeadManError::NotFound { what: what.into() }
+    }
+
+    pub fn conflict(reason: impl Into<String>) -> Self {
+        DeadManError::Conflict { reason: reason.into() }
+    }
+
+    pub fn internal(message: impl Into<String>) -> Self {
+        DeadManError::Internal { message: message.into() }
+    }
+}
+
+impl std::fmt::Display for DeadManError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DeadManError::NotRegistered => write!(f, "User not registered"),
+            DeadManError::AlreadyRegistered => write!(f, "User already registered"),
+            DeadManError::Unauthorized { reason } => write!(f, "Not authorized: {}", reason),
+            DeadManError::InvalidArgument { field, reason } => write!(f, "Invalid {}: {}", field, reason),
+            DeadManError::InsufficientBalance { available, required } => {
+                write!(f, "Insufficient balance: {} ckBTC available, {} ckBTC required", available, required)
+            }
+            DeadManError::Paused { scope, reason } => write!(f, "{:?} are paused: {}", scope, reason),
+            DeadManError::PayoutInProgress => write!(f, "Payout already in progress"),
+            DeadManError::Busy => write!(f, "Another operation on this account is in progress, try again shortly"),
+            DeadManError::WrongCustodyMode { mode } => write!(f, "Not supported in {:?} custody mode", mode),
+            DeadManError::FeatureDisabled { feature } => write!(f, "{} is disabled on this deployment", feature),
+            DeadManError::NotFound { what } => write!(f, "{} not found", what),
+            DeadManError::Conflict { reason } => write!(f, "{}", reason),
+            DeadManError::Ledger(e) => write!(f, "Ledger error: {}", e),
+            DeadManError::LedgerOutcomeUnknown(e) => {
+                write!(f, "Transfer outcome unknown ({}), retry it unchanged or call sync_balance to settle", e)
+            }
+            DeadManError::Internal { message } => write!(f, "Internal error: {}", message),
+        }
+    }
+}
+
+impl From<LedgerError> for DeadManError {
+    fn from(e: LedgerError) -> Self {
+        if e.outcome_unknown() {
+            DeadManError::LedgerOutcomeUnknown(e)
+        } else {
+            DeadManError::Ledger(e)
+        }
+    }
+}
@@ -0,0 +1,295 @@
+//! Per-account history, kept in stable memory.
+//!
+//! Entries live in a `StableBTreeMap` keyed by `(owner, seq)`, where `seq` counts up
+//! from 0 for every owner. Appending never touches older entries, so the log grows
+//! without bound unless the deployment configures a retention policy
+//! (`CanisterConfig::history_retention` / `history_max_entries`). Retention only ever
+//! removes an owner's oldest entries, which keeps every owner's sequence numbers
+//! contiguous: the oldest and newest keys are enough to know how many entries exist.
+//!
+//! History outlives the account: after a payout the former owner can still read it.
+
+use crate::{
+    AccountEvent, DeadManError, Memory, Timestamp, TransactionLog, MEMORY_MANAGER, STATE,
+};
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::msg_caller;
+use ic_cdk::query;
+use ic_cdk_timers::{set_timer, set_timer_interval};
+use ic_stable_structures::memory_manager::MemoryId;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{StableBTreeMap, Storable};
+use serde::Serialize;
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+use std::ops::Bound as RangeBound;
+use std::time::Duration;
+
+const HISTORY_MEMORY_ID: MemoryId = MemoryId::new(1);
+
+// Largest page `get_transaction_history` returns
+const MAX_PAGE_SIZE: u32 = 100;
+
+// Entries a single history query looks at before handing back a cursor, so filters
+// that match rarely cannot exhaust the query's instruction limit
+const MAX_SCANNED_PER_PAGE: usize = 5_000;
+
+// How often the retention sweep runs, and how many instructions one sweep message may use
+const RETENTION_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const RETENTION_INSTRUCTION_BUDGET: u64 = 20_000_000_000;
+
+impl Storable for TransactionLog {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(candid::encode_one(self).expect("failed to encode history entry"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        candid::decode_one(&bytes).expect("failed to decode history entry")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    static LOG: RefCell<StableBTreeMap<(Principal, u64), TransactionLog, Memory>> = RefCell::new(
+        StableBTreeMap::init(MEMORY_MANAGER.with(|m| m.borrow().get(HISTORY_MEMORY_ID))),
+    );
+
+    // Owner the interrupted retention sweep continues from
+    static SWEEP_CURSOR: Cell<Option<Principal>> = const { Cell::new(None) };
+}
+
+/// Keys of every entry of `owner`, oldest first
+fn owner_range(owner: Principal) -> std::ops::RangeInclusive<(Principal, u64)> {
+    (owner, 0)..=(owner, u64::MAX)
+}
+
+/// Oldest and newest sequence number of `owner`'s entries
+fn bounds(log: &StableBTreeMap<(Principal, u64), TransactionLog, Memory>, owner: Principal) -> Option<(u64, u64)> {
+    let mut range = log.range(owner_range(owner));
+    let ((_, first), _) = range.next()?;
+    let last = range.next_back().map_or(first, |((_, seq), _)| seq);
+    Some((first, last))
+}
+
+/// Add an entry to the end of `owner`'s history
+pub fn append(owner: Principal, timestamp: Timestamp, event: AccountEvent) {
+    LOG.with(|log| {
+        let mut log = log.borrow_mut();
+        let seq = bounds(&log, owner).map_or(0, |(_, last)| last + 1);
+        log.insert((owner, seq), TransactionLog { timestamp, event });
+    });
+}
+
+/// Category of an `AccountEvent`, for filtering
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, Serialize, PartialEq, Eq)]
+pub enum EventKind {
+    Registered,
+    Heartbeat,
+    BalanceSynced,
+    Deposit,
+    Withdrawal,
+    TimeoutDetected,
+    TimeoutCancelled,
+    PayoutStarted,
+    PayoutTransfer,
+    PayoutTransferFailed,
+    SettingsChanged, // Timeout, contestation period, beneficiaries, trusted parties or custody mode
+    BalanceDrift,    // Detected or resolved by reconciliation
+    Legacy,
+}
+
+impl AccountEvent {
+    pub fn kind(&self) -> EventKind {
+        match self {
+            AccountEvent::Registered { .. } => EventKind::Registered,
+            AccountEvent::Heartbeat { .. } => EventKind::Heartbeat,
+            AccountEvent::BalanceSynced { .. } => EventKind::BalanceSynced,
+            AccountEvent::DepositReceived { .. } | AccountEvent::LegacyBalanceMoved { .. } => EventKind::Deposit,
+            AccountEvent::Withdrawal { .. } => EventKind::Withdrawal,
+            AccountEvent::TimeoutDetected { .. } => EventKind::TimeoutDetected,
+            AccountEvent::TimeoutCancelled { .. } => EventKind::TimeoutCancelled,
+            AccountEvent::PayoutStarted { .. } => EventKind::PayoutStarted,
+            AccountEvent::PayoutTransfer { .. } => EventKind::PayoutTransfer,
+            AccountEvent::PayoutTransferFailed { .. } => EventKind::PayoutTransferFailed,
+            AccountEvent::TimeoutChanged { .. }
+            | AccountEvent::ContestationPeriodChanged { .. }
+            | AccountEvent::BeneficiariesChanged { .. }
+            | AccountEvent::TrustedPartyAdded { .. }
+            | AccountEvent::TrustedPartyRemoved { .. }
+            | AccountEvent::CustodyModeChanged { .. } => EventKind::SettingsChanged,
+            AccountEvent::BalanceDriftDetected { .. } | AccountEvent::BalanceDriftResolved { .. } => {
+                EventKind::BalanceDrift
+            }
+            AccountEvent::Legacy { .. } => EventKind::Legacy,
+        }
+    }
+}
+
+/// Which part of the history to return. Entries come newest first.
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct HistoryQuery {
+    pub before: Option<u64>, // Cursor: only entries older than this sequence number
+    pub limit: Option<u32>,  // Defaults to and is capped at 100
+    pub from: Option<Timestamp>, // Inclusive
+    pub to: Option<Timestamp>,   // Exclusive
+    pub kinds: Option<Vec<EventKind>>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct HistoryEntry {
+    pub seq: u64,
+    pub timestamp: Timestamp,
+    pub event: AccountEvent,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct HistoryPage {
+    pub entries: Vec<HistoryEntry>,
+    pub next: Option<u64>, // Pass as `before` to continue; None once the history is exhausted
+    pub total: u64,        // Entries retained for this account, regardless of filters
+}
+
+#[allow(non_camel_case_types)]
+#[derive(CandidType, Deserialize, Debug)]
+pub enum TransactionHistoryResult {
+    ok(HistoryPage),
+    err(DeadManError),
+}
+
+/// One page of `owner`'s history matching `query`
+pub fn page(owner: Principal, query: &HistoryQuery) -> HistoryPage {
+    let limit = query.limit.unwrap_or(MAX_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE) as usize;
+
+    LOG.with(|log| {
+        let log = log.borrow();
+        let total = bounds(&log, owner).map_or(0, |(first, last)| last - first + 1);
+        let end = match query.before {
+            Some(before) => RangeBound::Excluded((owner, before)),
+            None => RangeBound::Included((owner, u64::MAX)),
+        };
+
+        let mut entries = Vec::new();
+        let mut next = None;
+        let range = log.range((RangeBound::Included((owner, 0)), end)).rev();
+        for (scanned, ((_, seq), entry)) in range.enumerate() {
+            // Entries are appended in time order, so nothing older can match
+            if query.from.is_some_and(|from| entry.timestamp < from) {
+                break;
+            }
+            if entries.len() == limit || scanned == MAX_SCANNED_PER_PAGE {
+                next = Some(seq + 1);
+                break;
+            }
+
+            let in_range = query.to.is_none_or(|to| entry.timestamp < to);
+            let wanted = query.kinds.as_ref().is_none_or(|kinds| kinds.contains(&entry.event.kind()));
+            if in_range && wanted {
+                entries.push(HistoryEntry {
+                    seq,
+                    timestamp: entry.timestamp,
+                    event: entry.event,
+                });
+            }
+        }
+
+        HistoryPage { entries, next, total }
+    })
+}
+
+/// Page through the caller's history, newest first
+#[query]
+fn get_transaction_history(query: HistoryQuery) -> TransactionHistoryResult {
+    let caller = msg_caller();
+    let registered = STATE.with(|state| state.borrow().users.contains_key(&caller));
+
+    let page = page(caller, &query);
+    if !registered && page.total == 0 {
+        return TransactionHistoryResult::err(DeadManError::NotRegistered);
+    }
+    TransactionHistoryResult::ok(page)
+}
+
+/// Start the periodic retention sweep
+pub fn start() {
+    set_timer_interval(RETENTION_INTERVAL, || async { sweep() });
+}
+
+/// Drop entries the retention policy no longer keeps, one owner at a time.
+/// Continues in a new message if it runs low on instructions.
+pub fn sweep() {
+    let (retention, max_entries) = STATE.with(|state| {
+        let config = &state.borrow().config;
+        (config.history_retention, config.history_max_entries)
+    });
+    if retention.is_none() && max_entries.is_none() {
+        SWEEP_CURSOR.with(|cursor| cursor.set(None));
+        return;
+    }
+    let cutoff = retention.map(|retention| {
+        Timestamp::from_nanos(Timestamp::now().as_nanos().saturating_sub(retention.as_nanos()))
+    });
+
+    let mut removed = 0u64;
+    let mut owner = SWEEP_CURSOR.with(|cursor| cursor.take());
+    LOG.with(|log| {
+        let mut log = log.borrow_mut();
+        if owner.is_none() {
+            owner = log.first_key_value().map(|((owner, _), _)| owner);
+        }
+
+        while let Some(current) = owner {
+            if ic_cdk::api::instruction_counter() > RETENTION_INSTRUCTION_BUDGET {
+                SWEEP_CURSOR.with(|cursor| cursor.set(Some(current)));
+                set_timer(Duration::ZERO, async { sweep() });
+                break;
+            }
+            removed += prune_owner(&mut log, current, cutoff, max_entries);
+            owner = log
+                .range((RangeBound::Excluded((current, u64::MAX)), RangeBound::Unbounded))
+                .next()
+                .map(|((owner, _), _)| owner);
+        }
+    });
+
+    if removed > 0 {
+        ic_cdk::println!("History retention removed {} entr(ies)", removed);
+    }
+}
+
+/// Remove `owner`'s entries beyond `max_entries` and those older than `cutoff`
+fn prune_owner(
+    log: &mut StableBTreeMap<(Principal, u64), TransactionLog, Memory>,
+    owner: Principal,
+    cutoff: Option<Timestamp>,
+    max_entries: Option<u64>,
+) -> u64 {
+    let Some((mut first, last)) = bounds(log, owner) else {
+        return 0;
+    };
+    let mut removed = 0;
+
+    if let Some(max_entries) = max_entries {
+        let count = last - first + 1;
+        while count - removed > max_entries {
+            log.remove(&(owner, first));
+            first += 1;
+            removed += 1;
+        }
+    }
+
+    if let Some(cutoff) = cutoff {
+        while first <= last {
+            match log.get(&(owner, first)) {
+                Some(entry) if entry.timestamp < cutoff => {
+                    log.remove(&(owner, first));
+                    first += 1;
+                    removed += 1;
+                }
+                _ => break,
+            }
+        }
+    }
+
+    removed
+}
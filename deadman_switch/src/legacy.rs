@@ -0,0 +1,306 @@
+//! Balances from before deposit subaccounts.
+//!
+//! The first builds kept every user's ckBTC in the canister's default account. When a
+//! state saved by them is migrated (see `migrations`), each tracked balance moves to
+//! `UserAccount::legacy_balance` and the account starts at zero. Right after the upgrade,
+//! and every ten minutes while any are left, the canister transfers each of them from its
+//! default account to the owner's deposit subaccount and credits what arrived, the
+//! share minus the ledger fee, recording `LegacyBalanceMoved`.
+//!
+//! Those balances are claims, not proof of funds: old builds credited the whole default
+//! account to whoever synced and let anyone set a mock balance. Before the first move each
+//! claim gets a share of what the default account actually holds, pro-rated when the claims
+//! add up to more, so moving them in any order cannot hand one owner another's funds.
+//!
+//! The created_at_time and fee of a transfer are stored before it is sent. A transfer
+//! that may have executed is resent unchanged, so the ledger deduplicates it and the
+//! funds cannot be moved twice; only a first attempt the ledger refused is retried as a
+//! new transaction.
+
+use crate::{
+    certification, deposit_account, ledger_balance_of, ledger_fee, scheduler, transfer_ckbtc, AccountEvent,
+    AccountGuard, DeadManError, Timestamp, MEMO_TAG, STATE,
+};
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::api::canister_self;
+use icrc_ledger_types::icrc1::account::Account;
+use ic_cdk_timers::{set_timer, set_timer_interval};
+use serde::Serialize;
+use std::cell::Cell;
+use std::time::Duration;
+
+// How often balances that could not be moved yet are retried
+const MOVE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// A balance still held in the canister's default account
+#[derive(CandidType, Deserialize, Clone, Debug, Serialize, PartialEq, Eq)]
+pub struct LegacyBalance {
+    pub amount: u64,         // Balance the old version tracked
+    pub share: Option<u64>,  // What the default account covers of it, fee included; set before the first move
+    pub unsettled: Option<UnsettledTransfer>, // Sent to the deposit subaccount, outcome not known yet
+}
+
+/// A transfer that may have executed, resent unchanged until the ledger settles it
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, Serialize, PartialEq, Eq)]
+pub struct UnsettledTransfer {
+    pub created_at_time: Timestamp,
+    pub fee: u64,
+}
+
+thread_local! {
+    static RUNNING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Clears RUNNING when dropped, including when a trap drops the run's future
+struct RunGuard;
+
+impl Drop for RunGuard {
+    fn drop(&mut self) {
+        RUNNING.with(|running| running.set(false));
+    }
+}
+
+fn pending_owners() -> Vec<Principal> {
+    STATE.with(|state| {
+        state
+            .borrow()
+            .users
+            .iter()
+            .filter(|(_, account)| account.legacy_balance.is_some())
+            .map(|(owner, _)| *owner)
+            .collect()
+    })
+}
+
+/// Move the legacy balances now and retry periodically, if there are any
+pub fn start() {
+    if pending_owners().is_empty() {
+        return;
+    }
+    set_timer(Duration::ZERO, async {
+        let _ = move_all().await;
+    });
+    set_timer_interval(MOVE_INTERVAL, || async {
+        if !pending_owners().is_empty() {
+            let _ = move_all().await;
+        }
+    });
+}
+
+/// Split what the default account holds across the claims on it: each claim in full if
+/// they fit, otherwise in proportion, rounded down so the shares never add up to more
+fn pro_rated(available: u64, claims: &[u64]) -> Vec<u64> {
+    let total: u128 = claims.iter().map(|&claim| u128::from(claim)).sum();
+    if total <= u128::from(available) {
+        return claims.to_vec();
+    }
+    claims
+        .iter()
+        .map(|&claim| (u128::from(claim) * u128::from(available) / total) as u64)
+        .collect()
+}
+
+/// Give every legacy balance without a share its part of the default account. Shares
+/// already given and not yet moved are still in the account and are set aside first.
+async fn assign_shares(ledger: Principal) -> Result<(), DeadManError> {
+    let unassigned = STATE.with(|state| {
+        state.borrow().users.values().any(|account| {
+            account.legacy_balance.as_ref().is_some_and(|legacy| legacy.share.is_none())
+        })
+    });
+    if !unassigned {
+        return Ok(());
+    }
+    let default_account = Account {
+        owner: canister_self(),
+        subaccount: None,
+    };
+    let held = ledger_balance_of(ledger, default_account).await?;
+
+    STATE.with(|state| {
+        let mut s = state.borrow_mut();
+        let assigned: u64 = s
+            .users
+            .values()
+            .filter_map(|account| account.legacy_balance.as_ref()?.share)
+            .sum();
+        let claims: Vec<(Principal, u64)> = s
+            .users
+            .iter()
+            .filter_map(|(owner, account)| {
+                let legacy = account.legacy_balance.as_ref()?;
+                legacy.share.is_none().then_some((*owner, legacy.amount))
+            })
+            .collect();
+        let amounts: Vec<u64> = claims.iter().map(|(_, amount)| *amount).collect();
+        let shares = pro_rated(held.saturating_sub(assigned), &amounts);
+        for ((owner, claim), share) in claims.into_iter().zip(shares) {
+            if let Some(legacy) = s.users.get_mut(&owner).and_then(|a| a.legacy_balance.as_mut()) {
+                legacy.share = Some(share);
+            }
+            if share < claim {
+                ic_cdk::println!(
+                    "Legacy balance of {} claims {} ckBTC, the default account covers {}",
+                    owner, claim, share
+                );
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Memo of a move: tag | 'L'. The destination differs per account, so it needs no more.
+fn move_memo() -> Vec<u8> {
+    let mut memo = MEMO_TAG.to_vec();
+    memo.push(b'L');
+    memo
+}
+
+/// Move every legacy balance that is not locked into its deposit subaccount.
+/// Returns the number of balances moved.
+pub async fn move_all() -> Result<u64, DeadManError> {
+    if RUNNING.with(|running| running.replace(true)) {
+        return Err(DeadManError::Busy);
+    }
+    let _guard = RunGuard;
+
+    let owners = pending_owners();
+    if owners.is_empty() {
+        return Ok(0);
+    }
+    let ledger = STATE.with(|state| state.borrow().config.ckbtc_ledger);
+    let current_fee = ledger_fee(ledger).await?;
+    assign_shares(ledger).await?;
+
+    let mut moved = 0;
+    let mut changed = Vec::new();
+    for owner in owners {
+        let Ok(_account_guard) = AccountGuard::acquire(owner) else {
+            continue;
+        };
+
+        // Persist the attempt before sending it; a resend reuses it unchanged
+        let attempt = STATE.with(|state| {
+            let mut s = state.borrow_mut();
+            let legacy = s.users.get_mut(&owner)?.legacy_balance.as_mut()?;
+            let share = legacy.share?;
+            let resend = legacy.unsettled.is_some();
+            let transfer = *legacy.unsettled.get_or_insert(UnsettledTransfer {
+                created_at_time: Timestamp::now(),
+                fee: current_fee,
+            });
+            Some((legacy.amount, share, transfer, resend))
+        });
+        let Some((amount, share, transfer, resend)) = attempt else {
+            continue;
+        };
+
+        if share <= transfer.fee {
+            // The fee would take all of it
+            STATE.with(|state| {
+                if let Some(account) = state.borrow_mut().users.get_mut(&owner) {
+                    account.legacy_balance = None;
+                    account.record(
+                        Timestamp::now(),
+                        AccountEvent::LegacyBalanceMoved {
+                            legacy: amount,
+                            credited: 0,
+                            block_index: None,
+                        },
+                    );
+                }
+            });
+            changed.push(owner);
+            continue;
+        }
+
+        let credited = share - transfer.fee;
+        let result = transfer_ckbtc(
+            ledger,
+            None,
+            deposit_account(&owner),
+            credited,
+            transfer.fee,
+            transfer.created_at_time,
+            move_memo(),
+        )
+        .await;
+
+        STATE.with(|state| {
+            let mut s = state.borrow_mut();
+            let Some(account) = s.users.get_mut(&owner) else {
+                return;
+            };
+            match result {
+                Ok(block_index) => {
+                    account.legacy_balance = None;
+                    account.balance += credited;
+                    account.record(
+                        Timestamp::now(),
+                        AccountEvent::LegacyBalanceMoved {
+                            legacy: amount,
+                            credited,
+                            block_index: Some(block_index),
+                        },
+                    );
+                    ic_cdk::println!(
+                        "Moved {} ckBTC of {}'s legacy balance to the deposit subaccount, block {}",
+                        credited, owner, block_index
+                    );
+                    moved += 1;
+                }
+                Err(e) if e.outcome_unknown() => {
+                    ic_cdk::println!("Legacy balance move for {} has an unknown outcome: {}", owner, e);
+                }
+                Err(e) => {
+                    let may_have_executed = resend && !e.rules_out_duplicate();
+                    if may_have_executed {
+                        ic_cdk::println!(
+                            "Legacy balance move for {} refused after an unknown outcome, resent unchanged: {}",
+                            owner, e
+                        );
+                    } else {
+                        if let Some(legacy) = account.legacy_balance.as_mut() {
+                            legacy.unsettled = None;
+                        }
+                        ic_cdk::println!("Legacy balance move for {} refused, retried later: {}", owner, e);
+                    }
+                }
+            }
+        });
+        changed.push(owner);
+    }
+
+    for owner in &changed {
+        scheduler::reschedule(*owner);
+    }
+    certification::refresh(&changed);
+    Ok(moved)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn claims_the_default_account_covers_are_kept_whole() {
+        assert_eq!(pro_rated(1_000, &[300, 700]), vec![300, 700]);
+        assert_eq!(pro_rated(5_000, &[300, 700]), vec![300, 700]);
+    }
+
+    #[test]
+    fn claims_above_the_default_account_are_scaled_down_alike() {
+        // Two owners each claim the whole account, as the old sync_balance let them
+        assert_eq!(pro_rated(1_000, &[1_000, 1_000]), vec![500, 500]);
+        assert_eq!(pro_rated(900, &[100, 200, 600, 900]), vec![50, 100, 300, 450]);
+    }
+
+    #[test]
+    fn scaled_shares_never_add_up_to_more_than_is_held() {
+        let claims = [333, 333, 334, 1];
+        let shares = pro_rated(10, &claims);
+        assert!(shares.iter().sum::<u64>() <= 10);
+        assert_eq!(pro_rated(0, &claims), vec![0; 4]);
+        assert_eq!(pro_rated(u64::MAX, &[u64::MAX, u64::MAX]), vec![u64::MAX / 2; 2]);
+    }
+}
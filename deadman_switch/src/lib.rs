@@ -1,29 +1,174 @@
 use candid::{CandidType, Deserialize, Principal};
-use ic_cdk::{api::time, api::caller, api::id, call::Call, init, post_upgrade, pre_upgrade, query, update};
-use ic_cdk_timers::set_timer_interval;
+use ic_cdk::{
+    api::canister_self,
+    api::msg_caller,
+    call::{Call, CallErrorExt, CallFailed},
+    init, post_upgrade, pre_upgrade, query, update,
+};
+use ic_stable_structures::{
+    memory_manager::{MemoryId, MemoryManager, VirtualMemory},
+    reader::Reader,
+    writer::Writer,
+    DefaultMemoryImpl, Memory as _,
+};
+use candid::Nat;
+use futures::future::join_all;
 use icrc_ledger_types::{
-    icrc1::account::Account,
-    icrc1::transfer::{Memo, TransferArg, TransferError},
+    icrc1::account::{Account, Subaccount},
+    icrc1::transfer::{Memo, TransferError},
+    icrc2::allowance::{Allowance, AllowanceArgs},
+    icrc2::transfer_from::{TransferFromArgs, TransferFromError},
 };
 use serde::Serialize;
-use std::cell::RefCell;
-use std::collections::HashMap;
-use std::time::Duration;
-
-// Testnet ckBTC ledger canister ID
-// Update this with the actual testnet ckBTC ledger canister ID
-const CKBTC_LEDGER_CANISTER_ID: &str = "mxzaz-hqaaa-aaaar-qaada-cai"; // Testnet ckBTC ledger
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, BTreeSet};
+
+mod admin;
+mod audit;
+mod certification;
+mod config;
+mod deposits;
+mod error;
+mod history;
+mod legacy;
+mod migrations;
+mod pause;
+mod reconciliation;
+mod reserves;
+mod scheduler;
+mod timestamp;
+
+pub use admin::Role;
+pub use config::{CanisterConfig, FeatureFlags, InitArgs};
+pub use deposits::DepositScan;
+pub use error::DeadManError;
+pub use legacy::LegacyBalance;
+pub use pause::{PauseScope, PauseState};
+pub use reconciliation::BalanceDrift;
+pub use timestamp::{DurationSecs, Timestamp};
+
+// Unit tests run outside a canister and talk to a stand-in ledger instead
+#[cfg(test)]
+use tests::ledger::{ledger_fee, transfer_ckbtc};
+
+// Stable memory region holding the serialized state across upgrades
+// (memory 1 holds the account history, see `history`)
+const UPGRADES_MEMORY_ID: MemoryId = MemoryId::new(0);
+
+// Upper bound on beneficiaries per account (each one is a separate ledger transfer)
+const MAX_BENEFICIARIES: usize = 10;
+
+// Payout legs that fail are retried after 1 min, 2 min, 4 min, ... up to once a day
+const PAYOUT_RETRY_BASE: DurationSecs = DurationSecs::from_minutes(1);
+const PAYOUT_RETRY_MAX: DurationSecs = DurationSecs::from_days(1);
+
+// Legs whose outcome is unknown must be resent within the ledger's 24h deduplication
+// window, so their retries are spaced at most an hour apart
+const PAYOUT_UNKNOWN_RETRY_MAX: DurationSecs = DurationSecs::from_hours(1);
+
+// An in-flight leg that has not completed after this long is resent (idempotently)
+const PAYOUT_IN_FLIGHT_STALE: DurationSecs = DurationSecs::from_minutes(10);
+
+// Default grace window between timeout detection and the transfer, unless configured otherwise
+const DEFAULT_CONTESTATION_PERIOD: DurationSecs = DurationSecs::from_days(7);
+
+type Memory = VirtualMemory<DefaultMemoryImpl>;
 
 thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+
     static STATE: RefCell<DeadManSwitchState> = RefCell::default();
+
+    // Makes withdrawal memos unique; combined with created_at_time it does not need to survive upgrades
+    static TRANSFER_NONCE: Cell<u64> = const { Cell::new(0) };
+
+    // Accounts with a money-moving call (withdraw, balance sync, payout) in progress
+    static ACCOUNT_LOCKS: RefCell<BTreeSet<Principal>> = RefCell::default();
+}
+
+/// Funds deducted for a withdrawal while its transfer is in flight. Neither `Clone` nor
+/// `Copy`: releasing it consumes it, so a refused transfer is given back exactly once.
+#[must_use]
+struct WithdrawalReservation {
+    total: u64,
+}
+
+/// Exclusive hold on an account across the awaits of a money-moving call.
+/// Released when dropped, including when the call's future is dropped after a trap.
+struct AccountGuard {
+    principal: Principal,
+}
+
+impl AccountGuard {
+    fn acquire(principal: Principal) -> Result<Self, DeadManError> {
+        ACCOUNT_LOCKS.with(|locks| {
+            if try_lock(&mut locks.borrow_mut(), principal) {
+                Ok(AccountGuard { principal })
+            } else {
+                Err(DeadManError::Busy)
+            }
+        })
+    }
+}
+
+impl Drop for AccountGuard {
+    fn drop(&mut self) {
+        ACCOUNT_LOCKS.with(|locks| unlock(&mut locks.borrow_mut(), &self.principal));
+    }
+}
+
+/// Mark `principal` as locked in `locks`; false if it already is
+fn try_lock(locks: &mut BTreeSet<Principal>, principal: Principal) -> bool {
+    locks.insert(principal)
+}
+
+fn unlock(locks: &mut BTreeSet<Principal>, principal: &Principal) {
+    locks.remove(principal);
+}
+
+fn upgrades_memory() -> Memory {
+    MEMORY_MANAGER.with(|m| m.borrow().get(UPGRADES_MEMORY_ID))
 }
 
 #[derive(CandidType, Deserialize, Clone, Debug, Serialize)]
 pub struct TransactionLog {
-    pub timestamp: u64,
-    pub transaction_type: String, // "heartbeat", "deposit", "withdrawal", "transfer", "update"
-    pub amount: Option<u64>,
-    pub details: String,
+    pub timestamp: Timestamp,
+    pub event: AccountEvent,
+}
+
+/// Something that happened to an account, as recorded in its history
+#[derive(CandidType, Deserialize, Clone, Debug, Serialize)]
+pub enum AccountEvent {
+    Registered { timeout: DurationSecs, beneficiary: Account },
+    Heartbeat { next_due: Timestamp },
+    /// The tracked balance was set to the ledger balance of the deposit subaccount
+    BalanceSynced { previous: u64, current: u64 },
+    /// The index reported a transfer or mint into the deposit subaccount. `credited` is the
+    /// part added to the tracked balance, less than `amount` if a sync already counted it.
+    DepositReceived { amount: u64, from: Option<Account>, block_index: u64, credited: u64 },
+    Withdrawal { amount: u64, fee: u64, to: Account, block_index: u64 },
+    TimeoutDetected { contestation_period: DurationSecs },
+    TimeoutCancelled { by: Principal }, // The owner or one of their trusted parties
+    PayoutStarted { amount: u64, transfers: u32 },
+    PayoutTransfer { amount: u64, fee: u64, to: Account, block_index: u64 },
+    PayoutTransferFailed { amount: u64, to: Account, attempt: u32, error: String },
+    TimeoutChanged { old: DurationSecs, new: DurationSecs },
+    ContestationPeriodChanged { old: DurationSecs, new: DurationSecs },
+    BeneficiariesChanged { old: Vec<Beneficiary>, new: Vec<Beneficiary> },
+    TrustedPartyAdded { principal: Principal },
+    TrustedPartyRemoved { principal: Principal },
+    CustodyModeChanged { old: CustodyMode, new: CustodyMode },
+    /// Reconciliation found less on the deposit subaccount than the tracked balance
+    BalanceDriftDetected { tracked: u64, ledger: u64 },
+    /// The deposit subaccount covers the tracked balance again
+    BalanceDriftResolved { tracked: u64, ledger: u64 },
+    /// The balance tracked before deposit subaccounts existed was moved from the canister's
+    /// default account into the deposit subaccount. `credited` is what arrived after the fee;
+    /// no block if the fee would have taken all of it.
+    LegacyBalanceMoved { legacy: u64, credited: u64, block_index: Option<u64> },
+    /// Entry written before events were typed
+    Legacy { transaction_type: String, amount: Option<u64>, details: String },
 }
 
 #[derive(CandidType, Deserialize, Clone, Debug, Serialize)]
@@ -31,55 +176,315 @@ pub struct Beneficiary {
     #[serde(rename = "beneficiary_principal")]
     pub principal: Principal,
     pub percentage: u8, // 0-100, for multiple beneficiaries
-    pub subaccount: Option<Vec<u8>>, // Optional subaccount for ICRC-1 account (typically 32 bytes)
+    pub subaccount: Option<Vec<u8>>, // Optional subaccount for ICRC-1 account (32 bytes, validated on entry)
+}
+
+impl Beneficiary {
+    /// The ICRC-1 account payouts for this beneficiary are sent to
+    pub fn account(&self) -> Account {
+        Account {
+            owner: self.principal,
+            subaccount: self
+                .subaccount
+                .as_ref()
+                .and_then(|bytes| Subaccount::try_from(bytes.as_slice()).ok()),
+        }
+    }
+}
+
+/// Check a full beneficiary list: 1..=MAX_BENEFICIARIES entries, each with a non-zero share,
+/// shares summing to exactly 100, no principal listed twice and the owner not among them
+fn validate_beneficiaries(owner: &Principal, beneficiaries: &[Beneficiary]) -> Result<(), DeadManError> {
+    if beneficiaries.is_empty() {
+        return Err(DeadManError::invalid_argument("beneficiaries", "at least one beneficiary is required"));
+    }
+    if beneficiaries.len() > MAX_BENEFICIARIES {
+        return Err(DeadManError::invalid_argument(
+            "beneficiaries",
+            format!("at most {} beneficiaries are allowed", MAX_BENEFICIARIES),
+        ));
+    }
+
+    for (i, beneficiary) in beneficiaries.iter().enumerate() {
+        if beneficiary.principal == *owner {
+            return Err(DeadManError::invalid_argument("beneficiaries", "you cannot be your own beneficiary"));
+        }
+        if beneficiary.principal == Principal::anonymous() {
+            return Err(DeadManError::invalid_argument(
+                "beneficiaries",
+                "anonymous principal cannot be a beneficiary",
+            ));
+        }
+        if beneficiary.percentage == 0 {
+            return Err(DeadManError::invalid_argument(
+                "beneficiaries",
+                format!("{} has a 0% share", beneficiary.principal),
+            ));
+        }
+        validate_subaccount(&beneficiary.subaccount)?;
+        if beneficiaries[..i].iter().any(|b| b.principal == beneficiary.principal) {
+            return Err(DeadManError::invalid_argument(
+                "beneficiaries",
+                format!("{} is listed more than once", beneficiary.principal),
+            ));
+        }
+    }
+
+    let total: u32 = beneficiaries.iter().map(|b| b.percentage as u32).sum();
+    if total != 100 {
+        return Err(DeadManError::invalid_argument(
+            "beneficiaries",
+            format!("percentages must sum to 100, got {}", total),
+        ));
+    }
+
+    Ok(())
+}
+
+/// Check that an optional subaccount is a valid 32-byte ICRC-1 subaccount
+fn validate_subaccount(subaccount: &Option<Vec<u8>>) -> Result<(), DeadManError> {
+    match subaccount {
+        Some(bytes) if bytes.len() != 32 => Err(DeadManError::invalid_argument(
+            "subaccount",
+            format!("must be exactly 32 bytes, got {}", bytes.len()),
+        )),
+        _ => Ok(()),
+    }
 }
 
 #[derive(CandidType, Deserialize, Clone, Debug, Serialize)]
 pub struct UserAccount {
     #[serde(rename = "user_principal")]
     pub principal: Principal,
-    pub last_heartbeat: u64,
-    pub timeout_duration_seconds: u64,
+    pub last_heartbeat: Timestamp,
+    pub timeout_duration_seconds: DurationSecs,
     pub beneficiary: Principal, // Primary beneficiary (for backward compatibility)
     pub beneficiaries: Vec<Beneficiary>, // Multiple beneficiaries support
     pub balance: u64,
-    pub transaction_history: Vec<TransactionLog>,
-    pub contestation_period_seconds: u64, // Grace window before transfer executes
-    pub timeout_detected_at: Option<u64>, // When timeout was first detected
+    pub contestation_period_seconds: DurationSecs, // Grace window before transfer executes
+    pub timeout_detected_at: Option<Timestamp>, // When timeout was first detected
     pub trusted_parties: Vec<Principal>, // Trusted parties who can override during grace period
+    pub custody_mode: CustodyMode, // Where the funds are held until payout
+    pub allowance: Option<AllowanceStatus>, // Last observed ICRC-2 allowance (allowance mode only)
+    pub payout: Option<PayoutPlan>, // Set once the grace period is over and the payout has been planned
+    pub balance_drift: Option<BalanceDrift>, // Set by reconciliation while the ledger holds less than `balance`; holds payouts
+    pub last_deposit_block: Option<u64>, // Newest ledger block of the deposit subaccount seen by deposit detection
+    pub legacy_balance: Option<LegacyBalance>, // Balance from before deposit subaccounts, until it is moved into one
+    pub deposit_scan: Option<DepositScan>, // Deposit detection scan that hit the page limit, continued by the next poll
+    pub pending_withdrawal: Option<PendingWithdrawal>, // Withdrawal whose transfer may have executed, until it is settled
+}
+
+/// A withdrawal sent to the ledger without a known outcome. Its amount and fee stay
+/// deducted, and a retry resends it with the same created_at_time and memo so the
+/// ledger deduplicates it instead of paying it twice.
+#[derive(CandidType, Deserialize, Clone, Debug, Serialize, PartialEq, Eq)]
+pub struct PendingWithdrawal {
+    pub amount: u64,
+    pub fee: u64,
+    pub to: Account,
+    pub created_at_time: Timestamp,
+    pub memo: Vec<u8>,
+}
+
+impl UserAccount {
+    /// Append to the account's history in stable memory
+    /// and, unless it is private to the owner, to the audit log
+    pub fn record(&self, timestamp: Timestamp, event: AccountEvent) {
+        audit::record_account_event(self.principal, timestamp, &event);
+        history::append(self.principal, timestamp, event);
+    }
+
+    /// Deduct a withdrawal's amount and fee from the tracked balance before its transfer is sent
+    fn reserve_withdrawal(&mut self, total: u64) -> Result<WithdrawalReservation, DeadManError> {
+        if self.payout.is_some() {
+            return Err(DeadManError::PayoutInProgress);
+        }
+        if self.pending_withdrawal.is_some() {
+            return Err(DeadManError::conflict("An earlier withdrawal has not been settled yet"));
+        }
+        if self.balance < total {
+            return Err(DeadManError::InsufficientBalance {
+                available: self.balance,
+                required: total,
+            });
+        }
+        self.balance -= total;
+        Ok(WithdrawalReservation { total })
+    }
+
+    /// Give back the funds of a withdrawal the ledger refused
+    fn release_withdrawal(&mut self, reservation: WithdrawalReservation) {
+        self.balance = self.balance.saturating_add(reservation.total);
+    }
+
+    /// Drop the pending withdrawal once the ledger showed it never executed, giving back its funds
+    fn cancel_pending_withdrawal(&mut self) -> Option<PendingWithdrawal> {
+        let pending = self.pending_withdrawal.take()?;
+        self.release_withdrawal(WithdrawalReservation {
+            total: pending.amount.saturating_add(pending.fee),
+        });
+        Some(pending)
+    }
+
+    /// When the account times out unless another heartbeat arrives.
+    /// Pushed back by any time heartbeats were paused since the last one.
+    pub fn timeout_at(&self, pauses: &PauseState) -> Timestamp {
+        pauses.push_back(self.last_heartbeat, self.timeout_duration_seconds, Timestamp::now())
+    }
+
+    /// When the contestation period ends and the payout may execute.
+    /// Counted from detection if the timer has seen the timeout, otherwise from the timeout itself,
+    /// and pushed back by any time heartbeats were paused during the period.
+    pub fn grace_period_end(&self, pauses: &PauseState) -> Timestamp {
+        let start = self.timeout_detected_at.unwrap_or_else(|| self.timeout_at(pauses));
+        pauses.push_back(start, self.contestation_period_seconds, Timestamp::now())
+    }
+}
+
+/// Progress of one payout transfer
+#[derive(CandidType, Deserialize, Clone, Debug, Serialize, PartialEq, Eq)]
+pub enum PayoutLegStatus {
+    Pending,
+    InFlight { started_at: Timestamp },
+    Paid { block_index: u64 },
+    /// The ledger did not execute the transfer; retried as a new transaction. Held for an
+    /// operator (`admin_resolve_payout_leg`) if `next_retry_at` is `Timestamp::MAX`.
+    Failed { error: String, next_retry_at: Timestamp },
+    /// The transfer may have executed; resent with the same created_at_time and memo
+    OutcomeUnknown { error: String, next_retry_at: Timestamp },
+}
+
+/// One beneficiary transfer of a payout
+#[derive(CandidType, Deserialize, Clone, Debug, Serialize)]
+pub struct PayoutLeg {
+    pub to: Account,
+    pub amount: u64,
+    pub fee: u64,
+    pub status: PayoutLegStatus,
+    pub attempts: u32,
+    pub created_at_time: Option<Timestamp>, // Sent to the ledger for deduplication, assigned on first attempt
+    pub memo: Option<Vec<u8>>,
+}
+
+/// Durable record of an account's payout. The account is only removed once every leg is paid.
+#[derive(CandidType, Deserialize, Clone, Debug, Serialize)]
+pub struct PayoutPlan {
+    pub created_at: Timestamp,
+    pub custody_mode: CustodyMode,
+    pub legs: Vec<PayoutLeg>,
+}
+
+impl PayoutPlan {
+    /// Whether any funds may already have moved, i.e. the payout can no longer be called off
+    pub fn has_started(&self) -> bool {
+        self.legs.iter().any(|leg| {
+            matches!(
+                leg.status,
+                PayoutLegStatus::InFlight { .. }
+                    | PayoutLegStatus::Paid { .. }
+                    | PayoutLegStatus::OutcomeUnknown { .. }
+            )
+        })
+    }
+}
+
+/// Where a user's ckBTC sits until the switch fires
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, Serialize, PartialEq, Eq)]
+pub enum CustodyMode {
+    /// Funds are deposited into the user's subaccount of this canister
+    Custodial,
+    /// Funds stay in the owner's account; the owner grants this canister an ICRC-2
+    /// allowance and the canister pulls the funds to the beneficiaries on trigger
+    Allowance,
+}
+
+/// Snapshot of the ICRC-2 allowance an owner granted this canister
+#[derive(CandidType, Deserialize, Clone, Debug, Serialize)]
+pub struct AllowanceStatus {
+    pub allowance: u64,
+    pub expires_at: Option<Timestamp>,
+    pub owner_balance: u64, // Ledger balance of the owner's default account
+    pub checked_at: Timestamp,
+}
+
+/// Layout version of DeadManSwitchState as saved in stable memory.
+/// See `migrations` for how older layouts are brought up to date.
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, Serialize, PartialEq, Eq)]
+pub enum StateVersion {
+    V1,
+    V2,
+    V3,
+    V4,
+    V5,
+    V6,
+    V7,
+    V8,
+}
+
+impl StateVersion {
+    pub const CURRENT: StateVersion = StateVersion::V8;
+
+    pub fn as_u32(self) -> u32 {
+        match self {
+            StateVersion::V1 => 1,
+            StateVersion::V2 => 2,
+            StateVersion::V3 => 3,
+            StateVersion::V4 => 4,
+            StateVersion::V5 => 5,
+            StateVersion::V6 => 6,
+            StateVersion::V7 => 7,
+            StateVersion::V8 => 8,
+        }
+    }
+
+    pub fn from_u32(version: u32) -> Option<Self> {
+        match version {
+            1 => Some(StateVersion::V1),
+            2 => Some(StateVersion::V2),
+            3 => Some(StateVersion::V3),
+            4 => Some(StateVersion::V4),
+            5 => Some(StateVersion::V5),
+            6 => Some(StateVersion::V6),
+            7 => Some(StateVersion::V7),
+            8 => Some(StateVersion::V8),
+            _ => None,
+        }
+    }
 }
 
 #[derive(CandidType, Deserialize, Clone, Debug, Serialize)]
 pub struct DeadManSwitchState {
-    pub users: HashMap<Principal, UserAccount>,
-    pub ckbtc_ledger: Principal,
+    pub version: StateVersion,
+    pub users: BTreeMap<Principal, UserAccount>, // Ordered so admin listings can page by principal
+    pub config: CanisterConfig,
+    pub roles: BTreeMap<Principal, Role>, // Operators and auditors; controllers are implied
+    pub pauses: PauseState, // Emergency circuit breakers set by operators
 }
 
 impl Default for DeadManSwitchState {
     fn default() -> Self {
         Self {
-            users: HashMap::new(),
-            ckbtc_ledger: Principal::anonymous(),
+            version: StateVersion::CURRENT,
+            users: BTreeMap::new(),
+            config: CanisterConfig::default(),
+            roles: BTreeMap::new(),
+            pauses: PauseState::default(),
         }
     }
 }
 
-#[derive(CandidType, Deserialize, Debug)]
-pub struct InitArgs {
-    pub ckbtc_ledger_canister_id: Option<Principal>,
-}
-
 #[derive(CandidType, Deserialize, Debug)]
 pub struct RegisterArgs {
-    pub timeout_duration_seconds: u64,
+    pub timeout_duration_seconds: DurationSecs,
     pub beneficiary: Principal,
+    pub beneficiary_subaccount: Option<Vec<u8>>, // Pay out to this subaccount of the beneficiary
 }
 
 #[derive(CandidType, Deserialize, Debug)]
 pub struct HeartbeatResponse {
     pub success: bool,
     pub message: String,
-    pub next_heartbeat_due: u64,
+    pub next_heartbeat_due: Timestamp,
 }
 
 #[derive(CandidType, Deserialize, Debug)]
@@ -87,6 +492,7 @@ pub struct TransferResult {
     pub success: bool,
     pub message: String,
     pub block_index: Option<u64>,
+    pub transfers_attempted: u32, // Ledger transfers sent by this call
 }
 
 // Candid variant types for proper serialization
@@ -95,85 +501,201 @@ pub struct TransferResult {
 #[derive(CandidType, Deserialize, Debug)]
 pub enum Result_ {
     ok(String),
-    err(String),
+    err(DeadManError),
 }
 
 #[allow(non_camel_case_types)]
 #[derive(CandidType, Deserialize, Debug)]
 pub enum HeartbeatResult {
     ok(HeartbeatResponse),
-    err(String),
+    err(DeadManError),
 }
 
-#[allow(non_camel_case_types)]
+#[allow(non_camel_case_types, clippy::large_enum_variant)]
 #[derive(CandidType, Deserialize, Debug)]
 pub enum AccountInfoResult {
     ok(UserAccount),
-    err(String),
+    err(DeadManError),
 }
 
 #[allow(non_camel_case_types)]
 #[derive(CandidType, Deserialize, Debug)]
 pub enum BalanceResult {
     ok(u64),
-    err(String),
+    err(DeadManError),
 }
 
 #[allow(non_camel_case_types)]
 #[derive(CandidType, Deserialize, Debug)]
-pub enum TimeoutStatusResult {
-    ok(TimeoutStatus),
-    err(String),
+pub enum DepositAccountResult {
+    ok(Account),
+    err(DeadManError),
+}
+
+#[allow(non_camel_case_types)]
+#[derive(CandidType, Deserialize, Debug)]
+pub enum AllowanceResult {
+    ok(AllowanceStatus),
+    err(DeadManError),
 }
 
 #[allow(non_camel_case_types)]
 #[derive(CandidType, Deserialize, Debug)]
-pub enum TransactionHistoryResult {
-    ok(Vec<TransactionLog>),
-    err(String),
+pub enum TimeoutStatusResult {
+    ok(TimeoutStatus),
+    err(DeadManError),
 }
 
 #[init]
-fn init() {
+fn init(args: Option<InitArgs>) {
     ic_cdk::println!("Dead Man Switch Canister initialized");
     
     STATE.with(|state| {
         let mut s = state.borrow_mut();
-        // Default to testnet ckBTC ledger if not provided
-        s.ckbtc_ledger = Principal::from_text(CKBTC_LEDGER_CANISTER_ID)
-            .unwrap_or_else(|_| Principal::anonymous());
-        ic_cdk::println!("ckBTC Ledger Canister ID: {}", s.ckbtc_ledger);
+        // Anything not given falls back to the defaults (testnet ckBTC ledger)
+        if let Err(e) = s.config.apply(args.unwrap_or_default()) {
+            ic_cdk::trap(format!("Invalid init arguments: {}", e));
+        }
+        ic_cdk::println!("ckBTC Ledger Canister ID: {}", s.config.ckbtc_ledger);
     });
 
     // Start the timer to check for timeouts
-    start_timeout_checker();
+    scheduler::start();
+    history::start();
+    reserves::start();
+    reconciliation::start();
+    deposits::start();
+    certification::rebuild();
 }
 
 #[post_upgrade]
-fn post_upgrade() {
+fn post_upgrade(args: Option<InitArgs>) {
     ic_cdk::println!("Dead Man Switch Canister upgraded");
-    start_timeout_checker();
+
+    match load_state_from_stable_memory() {
+        Some(state) => {
+            ic_cdk::println!("Restored {} user account(s) from stable memory", state.users.len());
+            STATE.with(|s| *s.borrow_mut() = state);
+        }
+        None => {
+            // Upgrading from a build that did not persist its state
+            ic_cdk::println!("No saved state found in stable memory, starting fresh");
+        }
+    }
+
+    STATE.with(|state| {
+        let mut s = state.borrow_mut();
+        if let Some(args) = args {
+            if let Err(e) = admin::check_ledger_switch(&s, &args).and_then(|_| s.config.apply(args)) {
+                ic_cdk::trap(format!("Invalid upgrade arguments: {}", e));
+            }
+        }
+        ic_cdk::println!("ckBTC Ledger Canister ID: {}", s.config.ckbtc_ledger);
+    });
+
+    scheduler::start();
+    history::start();
+    reserves::start();
+    reconciliation::start();
+    deposits::start();
+    legacy::start();
+    certification::rebuild();
 }
 
 #[pre_upgrade]
 fn pre_upgrade() {
     ic_cdk::println!("Dead Man Switch Canister pre-upgrade");
+    save_state_to_stable_memory();
+}
+
+/// Serialize the whole state into the upgrades memory.
+/// Layout: schema version (u32 LE) | payload length (u64 LE) | Candid-encoded DeadManSwitchState
+fn save_state_to_stable_memory() {
+    let (version, bytes) = STATE.with(|state| {
+        let s = state.borrow();
+        (s.version.as_u32(), candid::encode_one(&*s))
+    });
+    let bytes = bytes.unwrap_or_else(|e| ic_cdk::trap(format!("Failed to encode state: {:?}", e)));
+
+    let mut memory = upgrades_memory();
+    let mut writer = Writer::new(&mut memory, 0);
+    writer
+        .write(&version.to_le_bytes())
+        .and_then(|_| writer.write(&(bytes.len() as u64).to_le_bytes()))
+        .and_then(|_| writer.write(&bytes))
+        .unwrap_or_else(|e| ic_cdk::trap(format!("Failed to write state to stable memory: {:?}", e)));
+
+    ic_cdk::println!("Saved {} bytes of state (schema v{})", bytes.len(), version);
+}
+
+/// Read the state written by `save_state_to_stable_memory` and migrate it to the current layout.
+/// Returns None if nothing was saved yet. Traps on an unknown schema version or a corrupt
+/// payload so the upgrade is rolled back instead of silently dropping user accounts.
+fn load_state_from_stable_memory() -> Option<DeadManSwitchState> {
+    let memory = upgrades_memory();
+    if memory.size() == 0 {
+        return None;
+    }
+
+    let mut reader = Reader::new(&memory, 0);
+    let mut version_bytes = [0u8; 4];
+    let mut len_bytes = [0u8; 8];
+    reader
+        .read(&mut version_bytes)
+        .and_then(|_| reader.read(&mut len_bytes))
+        .unwrap_or_else(|e| ic_cdk::trap(format!("Failed to read state header: {:?}", e)));
+
+    let version = u32::from_le_bytes(version_bytes);
+    let len = u64::from_le_bytes(len_bytes) as usize;
+    if len == 0 {
+        return None;
+    }
+
+    let mut bytes = vec![0u8; len];
+    reader
+        .read(&mut bytes)
+        .unwrap_or_else(|e| ic_cdk::trap(format!("Failed to read state payload: {:?}", e)));
+
+    let state = migrations::decode_state(version, &bytes).unwrap_or_else(|e| ic_cdk::trap(e));
+    if version != state.version.as_u32() {
+        ic_cdk::println!(
+            "Migrated state from schema v{} to v{}",
+            version,
+            state.version.as_u32()
+        );
+    }
+    Some(state)
 }
 
 /// Register a new user account with dead man switch functionality
 #[update]
 async fn register(args: RegisterArgs) -> Result_ {
-    let caller = caller();
+    let caller = msg_caller();
     
     if caller == Principal::anonymous() {
-        return Result_::err("Anonymous principal not allowed".to_string());
+        return Result_::err(DeadManError::Unauthorized {
+            reason: "anonymous principal not allowed".to_string(),
+        });
+    }
+
+    if let Err(e) = STATE.with(|state| state.borrow().pauses.check(PauseScope::Registrations)) {
+        return Result_::err(e);
+    }
+
+    if args.timeout_duration_seconds == DurationSecs::ZERO {
+        return Result_::err(DeadManError::invalid_argument("timeout_duration_seconds", "must be greater than 0"));
     }
 
-    if args.timeout_duration_seconds == 0 {
-        return Result_::err("Timeout duration must be greater than 0".to_string());
+    let initial_beneficiary = Beneficiary {
+        principal: args.beneficiary,
+        percentage: 100,
+        subaccount: args.beneficiary_subaccount,
+    };
+    if let Err(e) = validate_beneficiaries(&caller, std::slice::from_ref(&initial_beneficiary)) {
+        return Result_::err(e);
     }
 
-    let current_time = time();
+    let current_time = Timestamp::now();
 
     let already_registered = STATE.with(|state| {
         let s = state.borrow();
@@ -181,70 +703,78 @@ async fn register(args: RegisterArgs) -> Result_ {
     });
 
     if already_registered {
-        return Result_::err("User already registered".to_string());
+        return Result_::err(DeadManError::AlreadyRegistered);
     }
 
     STATE.with(|state| {
         let mut s = state.borrow_mut();
+        let contestation_period = s.config.default_contestation_period;
+        let beneficiary_account = initial_beneficiary.account();
         let account = UserAccount {
             principal: caller,
             last_heartbeat: current_time,
             timeout_duration_seconds: args.timeout_duration_seconds,
-            beneficiary: args.beneficiary.clone(),
-            beneficiaries: vec![Beneficiary {
-                principal: args.beneficiary,
-                percentage: 100,
-                subaccount: None,
-            }],
+            beneficiary: args.beneficiary,
+            beneficiaries: vec![initial_beneficiary],
             balance: 0,
-            transaction_history: vec![TransactionLog {
-                timestamp: current_time,
-                transaction_type: "register".to_string(),
-                amount: None,
-                details: format!("Registered with timeout: {}s", args.timeout_duration_seconds),
-            }],
-            contestation_period_seconds: 7 * 24 * 60 * 60, // Default 7 days grace period
+            contestation_period_seconds: contestation_period,
             timeout_detected_at: None,
             trusted_parties: Vec::new(),
+            custody_mode: CustodyMode::Custodial,
+            allowance: None,
+            payout: None,
+            balance_drift: None,
+            last_deposit_block: None,
+            legacy_balance: None,
+            deposit_scan: None,
+            pending_withdrawal: None,
         };
 
+        account.record(
+            current_time,
+            AccountEvent::Registered {
+                timeout: args.timeout_duration_seconds,
+                beneficiary: beneficiary_account,
+            },
+        );
         s.users.insert(caller, account);
         ic_cdk::println!("User registered: {}, timeout: {}s, beneficiary: {}", 
-            caller, args.timeout_duration_seconds, args.beneficiary);
+            caller, args.timeout_duration_seconds.as_secs(), args.beneficiary);
     });
+    scheduler::reschedule(caller);
+    certification::refresh(&[caller]);
 
     Result_::ok(format!(
         "Registered successfully. You must send heartbeat every {} seconds",
-        args.timeout_duration_seconds
+        args.timeout_duration_seconds.as_secs()
     ))
 }
 
 /// Send heartbeat to indicate user is alive
 #[update]
 async fn heartbeat() -> HeartbeatResult {
-    let caller = caller();
-    let current_time = time();
+    let caller = msg_caller();
+    let current_time = Timestamp::now();
 
-    STATE.with(|state| {
+    let result = STATE.with(|state| {
         let mut s = state.borrow_mut();
-        
+        // Timeouts are pushed back for as long as heartbeats are paused
+        if let Err(e) = s.pauses.check(PauseScope::Heartbeats) {
+            return HeartbeatResult::err(e);
+        }
+
         match s.users.get_mut(&caller) {
             Some(account) => {
+                if account.payout.as_ref().is_some_and(PayoutPlan::has_started) {
+                    return HeartbeatResult::err(DeadManError::PayoutInProgress);
+                }
                 account.last_heartbeat = current_time;
                 // Reset timeout detection if user sends heartbeat during grace period
                 account.timeout_detected_at = None;
-                let next_due = current_time + account.timeout_duration_seconds;
-                account.transaction_history.push(TransactionLog {
-                    timestamp: current_time,
-                    transaction_type: "heartbeat".to_string(),
-                    amount: None,
-                    details: format!("Heartbeat sent, next due: {}", next_due),
-                });
-                // Keep only last 100 transactions
-                if account.transaction_history.len() > 100 {
-                    account.transaction_history.remove(0);
-                }
-                ic_cdk::println!("Heartbeat received from: {}, next due: {}", caller, next_due);
+                account.payout = None;
+                let next_due = current_time.saturating_add(account.timeout_duration_seconds);
+                account.record(current_time, AccountEvent::Heartbeat { next_due });
+                ic_cdk::println!("Heartbeat received from: {}, next due: {}", caller, next_due.as_nanos());
                 
                 HeartbeatResult::ok(HeartbeatResponse {
                     success: true,
@@ -252,735 +782,1670 @@ async fn heartbeat() -> HeartbeatResult {
                     next_heartbeat_due: next_due,
                 })
             }
-            None => HeartbeatResult::err("User not registered".to_string()),
+            None => HeartbeatResult::err(DeadManError::NotRegistered),
         }
-    })
+    });
+
+    scheduler::reschedule(caller);
+    certification::refresh(&[caller]);
+    result
 }
 
 /// Verify and sync ckBTC balance from ledger
 /// This function checks the actual ledger balance and syncs with tracked balance
 #[update]
 async fn sync_balance() -> Result_ {
-    let caller = caller();
+    let caller = msg_caller();
     
     // Check if user is registered
     if !STATE.with(|state| {
         let s = state.borrow();
         s.users.contains_key(&caller)
     }) {
-        return Result_::err("User not registered. Please register first.".to_string());
+        return Result_::err(DeadManError::NotRegistered);
+    }
+
+    if is_allowance_mode(&caller) {
+        return Result_::err(DeadManError::WrongCustodyMode {
+            mode: CustodyMode::Allowance,
+        });
     }
 
+    let _guard = match AccountGuard::acquire(caller) {
+        Ok(guard) => guard,
+        Err(e) => return Result_::err(e),
+    };
+
     let ledger = STATE.with(|state| {
         let s = state.borrow();
-        s.ckbtc_ledger
+        s.config.ckbtc_ledger
     });
 
-    // Get canister's account balance from ledger
-    let canister_id = id();
-    let account = Account {
-        owner: canister_id,
-        subaccount: None,
-    };
-
-    let response = match Call::unbounded_wait(ledger, "icrc1_balance_of")
-        .with_arg(&(account,))
-        .await
-    {
-        Ok(resp) => resp,
-        Err(e) => {
-            ic_cdk::println!("Balance sync failed: {:?}", e);
-            return Result_::err(format!("Failed to sync balance: {:?}", e));
-        }
-    };
-    let (ledger_balance,): (u64,) = match response.candid() {
-        Ok(val) => val,
-        Err(e) => {
-            ic_cdk::println!("Failed to decode balance response: {:?}", e);
-            return Result_::err(format!("Failed to decode balance: {:?}", e));
-        }
+    // Only the caller's own deposit subaccount counts towards their balance
+    let ledger_balance = match ledger_balance_of(ledger, deposit_account(&caller)).await {
+        Ok(balance) => balance,
+        Err(e) => return Result_::err(e.into()),
     };
 
-    let current_time = time();
-    STATE.with(|state| {
+    let current_time = Timestamp::now();
+    let result = STATE.with(|state| {
         let mut s = state.borrow_mut();
         if let Some(account) = s.users.get_mut(&caller) {
             let previous_balance = account.balance;
             account.balance = ledger_balance;
+            // The ledger balance shows whether a withdrawal with an unknown outcome went through
+            account.pending_withdrawal = None;
             
             if ledger_balance != previous_balance {
-                let difference = ledger_balance as i128 - previous_balance as i128;
-                let transaction_type = if difference > 0 {
-                    "deposit"
-                } else {
-                    "balance_sync"
-                };
-                
-                account.transaction_history.push(TransactionLog {
-                    timestamp: current_time,
-                    transaction_type: transaction_type.to_string(),
-                    amount: Some(ledger_balance),
-                    details: format!(
-                        "Balance synced: {} ckBTC (previous: {}, difference: {})",
-                        ledger_balance, previous_balance, difference
-                    ),
-                });
-                
-                if account.transaction_history.len() > 100 {
-                    account.transaction_history.remove(0);
-                }
+                account.record(
+                    current_time,
+                    AccountEvent::BalanceSynced {
+                        previous: previous_balance,
+                        current: ledger_balance,
+                    },
+                );
             }
+            reconciliation::resolve(account, ledger_balance, current_time);
             
             ic_cdk::println!("Balance synced for {}: {} ckBTC", caller, ledger_balance);
             Result_::ok(format!("Balance synced: {} ckBTC", ledger_balance))
         } else {
-            Result_::err("User account not found".to_string())
+            Result_::err(DeadManError::NotRegistered)
         }
-    })
+    });
+    // Releases a payout held by a balance drift
+    scheduler::reschedule(caller);
+    certification::refresh(&[caller]);
+    result
 }
 
-/// Deposit ckBTC to the dead man switch
-/// Note: Users should transfer ckBTC directly to this canister's account first
-/// Then call sync_balance() to verify and update the tracked balance
+/// Confirm a deposit of `amount` ckBTC that was transferred to the caller's deposit account
+/// (see get_deposit_account). Fails without changing anything until at least `amount` has
+/// arrived on top of the tracked balance; everything that arrived is then credited.
 #[update]
-async fn deposit(_amount: u64) -> Result_ {
-    let caller = caller();
+async fn deposit(amount: u64) -> Result_ {
+    let caller = msg_caller();
     
     // Check if user is registered
     if !STATE.with(|state| {
         let s = state.borrow();
         s.users.contains_key(&caller)
     }) {
-        return Result_::err("User not registered. Please register first.".to_string());
+        return Result_::err(DeadManError::NotRegistered);
+    }
+
+    if is_allowance_mode(&caller) {
+        return Result_::err(DeadManError::WrongCustodyMode {
+            mode: CustodyMode::Allowance,
+        });
     }
 
-    // Verify actual balance from ledger
+    let _guard = match AccountGuard::acquire(caller) {
+        Ok(guard) => guard,
+        Err(e) => return Result_::err(e),
+    };
+
+    // Verify actual balance of the caller's deposit subaccount from ledger
     let ledger = STATE.with(|state| {
         let s = state.borrow();
-        s.ckbtc_ledger
+        s.config.ckbtc_ledger
     });
 
-    let canister_id = id();
-    let account = Account {
-        owner: canister_id,
-        subaccount: None,
-    };
-
-    let response = match Call::unbounded_wait(ledger, "icrc1_balance_of")
-        .with_arg(&(account,))
-        .await
-    {
-        Ok(resp) => resp,
-        Err(e) => {
-            ic_cdk::println!("Balance sync failed: {:?}", e);
-            return Result_::err(format!("Failed to sync balance: {:?}", e));
-        }
-    };
-    let (ledger_balance,): (u64,) = match response.candid() {
-        Ok(val) => val,
-        Err(e) => {
-            ic_cdk::println!("Failed to decode balance response: {:?}", e);
-            return Result_::err(format!("Failed to decode balance: {:?}", e));
-        }
+    let ledger_balance = match ledger_balance_of(ledger, deposit_account(&caller)).await {
+        Ok(balance) => balance,
+        Err(e) => return Result_::err(e.into()),
     };
 
-    let current_time = time();
-    STATE.with(|state| {
+    let current_time = Timestamp::now();
+    let result = STATE.with(|state| {
         let mut s = state.borrow_mut();
         if let Some(account) = s.users.get_mut(&caller) {
             // Verify that ledger balance matches or exceeds expected balance
             if ledger_balance < account.balance {
-                return Result_::err(format!(
+                return Result_::err(DeadManError::conflict(format!(
                     "Ledger balance ({}) is less than tracked balance ({}). Please sync balance first.",
                     ledger_balance, account.balance
-                ));
+                )));
+            }
+
+            let arrived = ledger_balance - account.balance;
+            if arrived < amount {
+                return Result_::err(DeadManError::conflict(format!(
+                    "Only {} of the {} ckBTC deposit has reached your deposit account; retry once the transfer has settled",
+                    arrived, amount
+                )));
             }
             
             // Update balance to match ledger
             let previous_balance = account.balance;
             account.balance = ledger_balance;
+            // The ledger balance shows whether a withdrawal with an unknown outcome went through
+            account.pending_withdrawal = None;
             
             if ledger_balance > previous_balance {
-                account.transaction_history.push(TransactionLog {
-                    timestamp: current_time,
-                    transaction_type: "deposit".to_string(),
-                    amount: Some(ledger_balance - previous_balance),
-                    details: format!("Deposited {} ckBTC (verified from ledger)", ledger_balance - previous_balance),
-                });
-                
-                if account.transaction_history.len() > 100 {
-                    account.transaction_history.remove(0);
-                }
+                account.record(
+                    current_time,
+                    AccountEvent::BalanceSynced {
+                        previous: previous_balance,
+                        current: ledger_balance,
+                    },
+                );
             }
+            reconciliation::resolve(account, ledger_balance, current_time);
             
             ic_cdk::println!("Deposit verified: {} ckBTC from {} (ledger balance: {})", 
                 ledger_balance - previous_balance, caller, ledger_balance);
             Result_::ok(format!("Deposit verified: {} ckBTC", ledger_balance - previous_balance))
         } else {
-            Result_::err("User account not found".to_string())
+            Result_::err(DeadManError::NotRegistered)
         }
-    })
+    });
+    scheduler::reschedule(caller);
+    certification::refresh(&[caller]);
+    result
 }
 
-/// Transfer ckBTC using ICRC-1 standard
-async fn transfer_ckbtc(
-    ledger: Principal,
-    to: Principal,
-    amount: u64,
-) -> Result<u64, TransferError> {
-    let transfer_args = TransferArg {
-        from_subaccount: None,
-        to: Account {
-            owner: to,
-            subaccount: None,
-        },
-        fee: None,
-        created_at_time: None,
-        memo: Some(Memo::from(vec![0x44, 0x45, 0x41, 0x44, 0x4D, 0x41, 0x4E])), // "DEADMAN" in hex
-        amount: amount.into(),
-    };
+/// Derive the ICRC-1 subaccount holding a user's deposits.
+/// Layout: principal length byte followed by the principal bytes, zero padded to 32 bytes.
+fn deposit_subaccount(user: &Principal) -> Subaccount {
+    let bytes = user.as_slice();
+    let mut subaccount = [0u8; 32];
+    subaccount[0] = bytes.len() as u8;
+    subaccount[1..1 + bytes.len()].copy_from_slice(bytes);
+    subaccount
+}
 
-    ic_cdk::println!("Calling icrc1_transfer on ledger: {}", ledger);
-    
-    // Call the ICRC-1 transfer method on the ckBTC ledger
-    let response = match Call::unbounded_wait(ledger, "icrc1_transfer")
-        .with_arg(&(transfer_args,))
+/// The canister-owned ledger account a user deposits ckBTC into
+fn deposit_account(user: &Principal) -> Account {
+    Account {
+        owner: canister_self(),
+        subaccount: Some(deposit_subaccount(user)),
+    }
+}
+
+/// Read an account's balance from the ckBTC ledger
+async fn ledger_balance_of(ledger: Principal, account: Account) -> Result<u64, LedgerError> {
+    let response = match Call::unbounded_wait(ledger, "icrc1_balance_of")
+        .with_arg((account,))
         .await
     {
         Ok(resp) => resp,
         Err(e) => {
-            ic_cdk::println!("Transfer call failed: {:?}", e);
-            return Err(TransferError::TemporarilyUnavailable);
+            ic_cdk::println!("Balance sync failed: {:?}", e);
+            return Err(LedgerError::from_call_failed(e));
         }
     };
-    let (transfer_result,): (Result<u64, TransferError>,) = match response.candid() {
+    let (balance,): (Nat,) = match response.candid() {
         Ok(val) => val,
         Err(e) => {
-            ic_cdk::println!("Failed to decode transfer response: {:?}", e);
-            return Err(TransferError::TemporarilyUnavailable);
+            ic_cdk::println!("Failed to decode balance response: {:?}", e);
+            return Err(LedgerError::Decode(e.to_string()));
         }
     };
-    transfer_result
+    u64::try_from(balance.0).map_err(|_| LedgerError::Decode("Ledger balance does not fit in u64".to_string()))
 }
 
-/// Check and transfer funds if timeout occurred (after grace period)
-async fn check_and_transfer(user: &UserAccount) -> Result<TransferResult, String> {
-    let current_time = time();
-    
-    // Check if timeout has been reached
-    let timeout_reached = current_time >= user.last_heartbeat + user.timeout_duration_seconds;
-    
-    if !timeout_reached {
-        return Ok(TransferResult {
-            success: false,
-            message: "Timeout not reached".to_string(),
-            block_index: None,
-        });
+/// Whether the user keeps their funds in their own account (ICRC-2 allowance mode)
+fn is_allowance_mode(user: &Principal) -> bool {
+    STATE.with(|state| {
+        let s = state.borrow();
+        s.users
+            .get(user)
+            .map(|a| a.custody_mode == CustodyMode::Allowance)
+            .unwrap_or(false)
+    })
+}
+
+/// The account this canister spends allowances from
+fn spender_account() -> Account {
+    Account {
+        owner: canister_self(),
+        subaccount: None,
     }
-    
-    // Check if we're still in contestation period
-    let timeout_detected_at = user.timeout_detected_at.unwrap_or(user.last_heartbeat + user.timeout_duration_seconds);
-    let grace_period_end = timeout_detected_at + user.contestation_period_seconds;
-    
-    if current_time < grace_period_end {
-        return Ok(TransferResult {
-            success: false,
-            message: format!("Still in contestation period. Transfer will execute at {}", grace_period_end),
+}
+
+fn nat_to_u64(n: &Nat) -> u64 {
+    u64::try_from(n.0.clone()).unwrap_or(u64::MAX)
+}
+
+/// Read the ckBTC ledger's transfer fee
+#[cfg(not(test))]
+async fn ledger_fee(ledger: Principal) -> Result<u64, LedgerError> {
+    let response = match Call::unbounded_wait(ledger, "icrc1_fee").await {
+        Ok(resp) => resp,
+        Err(e) => {
+            ic_cdk::println!("Fee query failed: {:?}", e);
+            return Err(LedgerError::from_call_failed(e));
+        }
+    };
+    match response.candid::<(Nat,)>() {
+        Ok((fee,)) => Ok(nat_to_u64(&fee)),
+        Err(e) => {
+            ic_cdk::println!("Failed to decode fee response: {:?}", e);
+            Err(LedgerError::Decode(e.to_string()))
+        }
+    }
+}
+
+/// Read the ICRC-2 allowance `owner` granted this canister
+async fn ledger_allowance(ledger: Principal, owner: Account) -> Result<Allowance, LedgerError> {
+    let args = AllowanceArgs {
+        account: owner,
+        spender: spender_account(),
+    };
+    let response = match Call::unbounded_wait(ledger, "icrc2_allowance")
+        .with_arg((args,))
+        .await
+    {
+        Ok(resp) => resp,
+        Err(e) => {
+            ic_cdk::println!("Allowance query failed: {:?}", e);
+            return Err(LedgerError::from_call_failed(e));
+        }
+    };
+    match response.candid::<(Allowance,)>() {
+        Ok((allowance,)) => Ok(allowance),
+        Err(e) => {
+            ic_cdk::println!("Failed to decode allowance response: {:?}", e);
+            Err(LedgerError::Decode(e.to_string()))
+        }
+    }
+}
+
+/// Fetch the owner's current allowance and balance from the ledger and store the snapshot
+async fn refresh_allowance_status(ledger: Principal, owner: Principal) -> Result<AllowanceStatus, LedgerError> {
+    let owner_account = Account {
+        owner,
+        subaccount: None,
+    };
+    let allowance = ledger_allowance(ledger, owner_account).await?;
+    let owner_balance = ledger_balance_of(ledger, owner_account).await?;
+
+    let status = AllowanceStatus {
+        allowance: nat_to_u64(&allowance.allowance),
+        expires_at: allowance.expires_at.map(Timestamp::from_nanos),
+        owner_balance,
+        checked_at: Timestamp::now(),
+    };
+
+    STATE.with(|state| {
+        let mut s = state.borrow_mut();
+        if let Some(account) = s.users.get_mut(&owner) {
+            account.allowance = Some(status.clone());
+        }
+    });
+    certification::refresh(&[owner]);
+
+    Ok(status)
+}
+
+/// Why a call to the ckBTC ledger failed, or a transfer did not produce a block
+#[derive(CandidType, Deserialize, Clone, Debug, Serialize, PartialEq, Eq)]
+pub enum LedgerError {
+    /// The call could not be made at all (e.g. not enough cycles); the ledger never saw it
+    CallFailed(String),
+    /// The call was rejected. `clean` rejects guarantee the ledger did not execute it.
+    Rejected { code: u32, message: String, clean: bool },
+    /// The ledger replied but the reply could not be decoded, so the transfer may have executed
+    Decode(String),
+    /// The ledger refused icrc1_transfer
+    Transfer(TransferError),
+    /// The ledger refused icrc2_transfer_from
+    TransferFrom(TransferFromError),
+}
+
+impl LedgerError {
+    /// Whether the ledger may have executed the transfer despite the error.
+    /// Such transfers must only be retried with the same created_at_time and memo.
+    pub fn outcome_unknown(&self) -> bool {
+        match self {
+            LedgerError::Rejected { clean, .. } => !clean,
+            LedgerError::Decode(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Whether refusing a resent transfer shows that no earlier attempt executed. The
+    /// ledger looks for duplicates before it checks the balance, so only running short does.
+    pub fn rules_out_duplicate(&self) -> bool {
+        matches!(
+            self,
+            LedgerError::Transfer(TransferError::InsufficientFunds { .. })
+                | LedgerError::TransferFrom(TransferFromError::InsufficientFunds { .. })
+        )
+    }
+
+    fn from_call_failed(e: CallFailed) -> Self {
+        let clean = e.is_clean_reject();
+        match e {
+            CallFailed::CallRejected(rejected) => LedgerError::Rejected {
+                code: rejected.raw_reject_code(),
+                message: rejected.reject_message().to_string(),
+                clean,
+            },
+            other => LedgerError::CallFailed(other.to_string()),
+        }
+    }
+}
+
+impl std::fmt::Display for LedgerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LedgerError::CallFailed(message) => write!(f, "ledger call failed: {}", message),
+            LedgerError::Rejected { code, message, .. } => {
+                write!(f, "ledger call rejected (code {}): {}", code, message)
+            }
+            LedgerError::Decode(message) => write!(f, "undecodable ledger reply: {}", message),
+            LedgerError::Transfer(e) => write!(f, "{}", e),
+            LedgerError::TransferFrom(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// Memo tag prefixed to every transfer this canister makes: "DEADMAN"
+const MEMO_TAG: &[u8] = b"DEADMAN";
+
+/// Memo of a payout leg: tag | 'P' | leg index | plan creation time.
+/// Unique per leg and stable across retries, so the ledger can deduplicate them.
+fn payout_memo(plan_created_at: Timestamp, index: usize) -> Vec<u8> {
+    let mut memo = MEMO_TAG.to_vec();
+    memo.push(b'P');
+    memo.push(index as u8);
+    memo.extend_from_slice(&plan_created_at.as_nanos().to_be_bytes());
+    memo
+}
+
+/// Memo of a withdrawal: tag | 'W' | per-canister counter
+fn withdrawal_memo() -> Vec<u8> {
+    let nonce = TRANSFER_NONCE.with(|n| {
+        let nonce = n.get();
+        n.set(nonce.wrapping_add(1));
+        nonce
+    });
+    let mut memo = MEMO_TAG.to_vec();
+    memo.push(b'W');
+    memo.extend_from_slice(&nonce.to_be_bytes());
+    memo
+}
+
+/// Transfer ckBTC using ICRC-1 standard.
+/// `created_at_time` and `memo` make the call idempotent: resending the same transfer
+/// within the ledger's deduplication window returns the original block.
+#[cfg(not(test))]
+async fn transfer_ckbtc(
+    ledger: Principal,
+    from_subaccount: Option<Subaccount>,
+    to: Account,
+    amount: u64,
+    fee: u64,
+    created_at_time: Timestamp,
+    memo: Vec<u8>,
+) -> Result<u64, LedgerError> {
+    let transfer_args = icrc_ledger_types::icrc1::transfer::TransferArg {
+        from_subaccount,
+        to,
+        fee: Some(fee.into()),
+        created_at_time: Some(created_at_time.as_nanos()),
+        memo: Some(Memo::from(memo)),
+        amount: amount.into(),
+    };
+
+    ic_cdk::println!("Calling icrc1_transfer on ledger: {} (to account: {})", ledger, to);
+    
+    // Call the ICRC-1 transfer method on the ckBTC ledger
+    let response = match Call::unbounded_wait(ledger, "icrc1_transfer")
+        .with_arg(&(transfer_args,))
+        .await
+    {
+        Ok(resp) => resp,
+        Err(e) => {
+            ic_cdk::println!("Transfer call failed: {:?}", e);
+            return Err(LedgerError::from_call_failed(e));
+        }
+    };
+    let (transfer_result,): (Result<Nat, TransferError>,) = match response.candid() {
+        Ok(val) => val,
+        Err(e) => {
+            ic_cdk::println!("Failed to decode transfer response: {:?}", e);
+            return Err(LedgerError::Decode(e.to_string()));
+        }
+    };
+    match transfer_result {
+        Ok(block_index) => Ok(nat_to_u64(&block_index)),
+        // An earlier attempt of this exact transfer already went through
+        Err(TransferError::Duplicate { duplicate_of }) => {
+            ic_cdk::println!("Transfer already executed in block {}", duplicate_of);
+            Ok(nat_to_u64(&duplicate_of))
+        }
+        Err(e) => Err(LedgerError::Transfer(e)),
+    }
+}
+
+/// Pull ckBTC from `from` to `to` using an ICRC-2 allowance.
+/// Idempotent in the same way as `transfer_ckbtc`.
+async fn transfer_from_ckbtc(
+    ledger: Principal,
+    from: Principal,
+    to: Account,
+    amount: u64,
+    fee: u64,
+    created_at_time: Timestamp,
+    memo: Vec<u8>,
+) -> Result<u64, LedgerError> {
+    let transfer_args = TransferFromArgs {
+        spender_subaccount: None,
+        from: Account {
+            owner: from,
+            subaccount: None,
+        },
+        to,
+        amount: amount.into(),
+        fee: Some(fee.into()),
+        memo: Some(Memo::from(memo)),
+        created_at_time: Some(created_at_time.as_nanos()),
+    };
+
+    ic_cdk::println!("Calling icrc2_transfer_from on ledger: {} (to account: {})", ledger, to);
+
+    let response = match Call::unbounded_wait(ledger, "icrc2_transfer_from")
+        .with_arg((transfer_args,))
+        .await
+    {
+        Ok(resp) => resp,
+        Err(e) => {
+            ic_cdk::println!("Transfer from call failed: {:?}", e);
+            return Err(LedgerError::from_call_failed(e));
+        }
+    };
+    let (transfer_result,): (Result<Nat, TransferFromError>,) = match response.candid() {
+        Ok(val) => val,
+        Err(e) => {
+            ic_cdk::println!("Failed to decode transfer from response: {:?}", e);
+            return Err(LedgerError::Decode(e.to_string()));
+        }
+    };
+    match transfer_result {
+        Ok(block_index) => Ok(nat_to_u64(&block_index)),
+        Err(TransferFromError::Duplicate { duplicate_of }) => {
+            ic_cdk::println!("Transfer from already executed in block {}", duplicate_of);
+            Ok(nat_to_u64(&duplicate_of))
+        }
+        Err(e) => Err(LedgerError::TransferFrom(e)),
+    }
+}
+
+/// One ledger transfer of a payout
+#[derive(CandidType, Deserialize, Clone, Debug, Serialize)]
+pub struct PlannedTransfer {
+    pub to: Account,
+    pub amount: u64,
+    pub fee: u64,
+}
+
+/// How a balance is split across the beneficiaries.
+/// Invariant: the sum of `amount + fee` over all transfers equals `total`.
+#[derive(CandidType, Deserialize, Clone, Debug, Serialize)]
+pub struct DistributionPlan {
+    pub total: u64,
+    pub transfers: Vec<PlannedTransfer>,
+}
+
+/// The accounts to pay and their percentage, primary beneficiary first
+fn payout_targets(user: &UserAccount) -> Vec<(Account, u8)> {
+    if user.beneficiaries.is_empty() {
+        vec![(
+            Account {
+                owner: user.beneficiary,
+                subaccount: None,
+            },
+            100,
+        )]
+    } else {
+        user.beneficiaries.iter().map(|b| (b.account(), b.percentage)).collect()
+    }
+}
+
+/// Split `balance` across `targets` so that every transfer pays its own ledger fee and
+/// the whole balance is used up.
+///
+/// Each target gets `floor(distributable * percentage / total_percentage)` where
+/// `distributable = balance - fee * transfers`. The rounding remainder goes to the first
+/// target (the primary beneficiary). Targets whose share would not exceed the fee are
+/// dropped rather than paying as much in fees as they receive, and the split is
+/// recomputed so their percentage goes to the others. Percentages must sum to 100.
+fn plan_distribution(balance: u64, fee: u64, targets: &[(Account, u8)]) -> Result<DistributionPlan, String> {
+    let percentages: u32 = targets.iter().map(|(_, p)| *p as u32).sum();
+    if !targets.is_empty() && percentages != 100 {
+        return Err(format!("Beneficiary percentages sum to {} instead of 100", percentages));
+    }
+    let mut legs: Vec<(Account, u8)> = targets.to_vec();
+
+    loop {
+        if legs.is_empty() {
+            return Err("No beneficiaries to pay".to_string());
+        }
+
+        let total_fees = fee as u128 * legs.len() as u128;
+        if (balance as u128) <= total_fees {
+            if legs.len() > 1 {
+                // Not enough to pay everyone's fee: fall back to fewer transfers
+                legs.truncate(1);
+                continue;
+            }
+            return Err(format!(
+                "Balance ({} ckBTC) does not cover the transfer fee ({} ckBTC)",
+                balance, fee
+            ));
+        }
+        let distributable = balance as u128 - total_fees;
+        let total_percentage: u128 = legs.iter().map(|(_, p)| *p as u128).sum();
+
+        let shares: Vec<u128> = legs
+            .iter()
+            .map(|(_, percentage)| distributable * *percentage as u128 / total_percentage)
+            .collect();
+
+        // Drop non-primary legs that would receive no more than their fee and try again
+        if shares.iter().skip(1).any(|share| *share <= fee as u128) {
+            let mut i = 0;
+            legs.retain(|_| {
+                let keep = i == 0 || shares[i] > fee as u128;
+                i += 1;
+                keep
+            });
+            continue;
+        }
+
+        let remainder = distributable - shares.iter().sum::<u128>();
+        let transfers: Vec<PlannedTransfer> = legs
+            .iter()
+            .zip(shares)
+            .enumerate()
+            .map(|(i, ((to, _), share))| PlannedTransfer {
+                to: *to,
+                amount: (if i == 0 { share + remainder } else { share }) as u64,
+                fee,
+            })
+            .collect();
+
+        let planned: u128 = transfers.iter().map(|t| t.amount as u128 + t.fee as u128).sum();
+        if planned != balance as u128 {
+            return Err(format!("Distribution plan sums to {} instead of {}", planned, balance));
+        }
+
+        return Ok(DistributionPlan {
+            total: balance,
+            transfers,
+        });
+    }
+}
+
+/// Snapshot how much can be pulled from the owner's account right now (allowance mode).
+/// Bounded by both the allowance and the owner's balance.
+async fn available_allowance(ledger: Principal, user: &UserAccount) -> Result<u64, String> {
+    let status = refresh_allowance_status(ledger, user.principal)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    if status.expires_at.is_some_and(|expires_at| expires_at <= Timestamp::now()) {
+        return Err("Allowance expired before the transfer could execute".to_string());
+    }
+
+    Ok(status.allowance.min(status.owner_balance))
+}
+
+/// Work out the payout for an expired account. Returns None if there is nothing to pay.
+async fn create_payout_plan(ledger: Principal, user: &UserAccount) -> Result<Option<PayoutPlan>, String> {
+    // In allowance mode every icrc2_transfer_from consumes its fee from both the
+    // allowance and the owner's balance, just like icrc1_transfer does for deposits
+    let available = match user.custody_mode {
+        CustodyMode::Custodial => user.balance,
+        CustodyMode::Allowance => available_allowance(ledger, user).await?,
+    };
+    if available == 0 {
+        return Ok(None);
+    }
+
+    let fee = ledger_fee(ledger).await.map_err(|e| e.to_string())?;
+    let distribution = plan_distribution(available, fee, &payout_targets(user))?;
+
+    Ok(Some(PayoutPlan {
+        created_at: Timestamp::now(),
+        custody_mode: user.custody_mode,
+        legs: distribution
+            .transfers
+            .into_iter()
+            .map(|transfer| PayoutLeg {
+                to: transfer.to,
+                amount: transfer.amount,
+                fee: transfer.fee,
+                status: PayoutLegStatus::Pending,
+                attempts: 0,
+                created_at_time: None,
+                memo: None,
+            })
+            .collect(),
+    }))
+}
+
+/// Delay before retrying a failed leg: doubles with every attempt, capped
+fn payout_retry_delay(attempts: u32) -> DurationSecs {
+    let exponent = attempts.saturating_sub(1).min(31);
+    PAYOUT_RETRY_BASE
+        .saturating_mul(1u64 << exponent)
+        .min(PAYOUT_RETRY_MAX)
+}
+
+/// Execute one payout leg and record the outcome on the account
+async fn execute_payout_leg(ledger: Principal, user: Principal, index: usize) {
+    let started_at = Timestamp::now();
+    let leg = STATE.with(|state| {
+        let mut s = state.borrow_mut();
+        let account = s.users.get_mut(&user)?;
+        let plan = account.payout.as_mut()?;
+        let plan_created_at = plan.created_at;
+        let custody_mode = plan.custody_mode;
+        let leg = plan.legs.get_mut(index)?;
+
+        // A transfer the ledger definitely did not execute is resent as a new transaction.
+        // Anything that may have executed keeps its created_at_time so the ledger dedupes it.
+        let may_have_executed = matches!(
+            leg.status,
+            PayoutLegStatus::InFlight { .. } | PayoutLegStatus::OutcomeUnknown { .. }
+        );
+        if leg.created_at_time.is_none() || !may_have_executed {
+            leg.created_at_time = Some(started_at);
+        }
+        if leg.memo.is_none() {
+            leg.memo = Some(payout_memo(plan_created_at, index));
+        }
+        leg.status = PayoutLegStatus::InFlight { started_at };
+        leg.attempts += 1;
+        Some((leg.clone(), custody_mode, may_have_executed))
+    });
+    let Some((leg, custody_mode, may_have_executed)) = leg else {
+        return;
+    };
+    let created_at_time = leg.created_at_time.unwrap_or(started_at);
+    let memo = leg.memo.clone().unwrap_or_default();
+
+    ic_cdk::println!(
+        "Payout leg {} for {}: {} ckBTC (fee {}) to account {}, attempt {}",
+        index, user, leg.amount, leg.fee, leg.to, leg.attempts
+    );
+
+    let result = match custody_mode {
+        CustodyMode::Custodial => {
+            transfer_ckbtc(
+                ledger,
+                Some(deposit_subaccount(&user)),
+                leg.to,
+                leg.amount,
+                leg.fee,
+                created_at_time,
+                memo,
+            )
+            .await
+        }
+        CustodyMode::Allowance => {
+            transfer_from_ckbtc(ledger, user, leg.to, leg.amount, leg.fee, created_at_time, memo).await
+        }
+    };
+
+    let current_time = Timestamp::now();
+    STATE.with(|state| {
+        let mut s = state.borrow_mut();
+        let Some(account) = s.users.get_mut(&user) else {
+            return;
+        };
+        let Some(leg) = account.payout.as_mut().and_then(|plan| plan.legs.get_mut(index)) else {
+            return;
+        };
+        // A resent leg may complete twice (once as a Duplicate); only account for it once
+        if matches!(leg.status, PayoutLegStatus::Paid { .. }) {
+            return;
+        }
+
+        let event = match result {
+            Ok(block_index) => {
+                leg.status = PayoutLegStatus::Paid { block_index };
+                ic_cdk::println!("Paid {} ckBTC to account {}, block: {}", leg.amount, leg.to, block_index);
+                AccountEvent::PayoutTransfer {
+                    amount: leg.amount,
+                    fee: leg.fee,
+                    to: leg.to,
+                    block_index,
+                }
+            }
+            Err(e) => {
+                let attempted_amount = leg.amount;
+                let too_old = matches!(
+                    e,
+                    LedgerError::Transfer(TransferError::TooOld)
+                        | LedgerError::TransferFrom(TransferFromError::TooOld)
+                );
+                // Retrying cannot help: the leg asks for more than the source holds,
+                // or for an amount the ledger refuses to move
+                let unpayable = matches!(
+                    e,
+                    LedgerError::Transfer(TransferError::InsufficientFunds { .. } | TransferError::BadBurn { .. })
+                        | LedgerError::TransferFrom(
+                            TransferFromError::InsufficientFunds { .. } | TransferFromError::BadBurn { .. }
+                        )
+                );
+                let expected_fee = match &e {
+                    LedgerError::Transfer(TransferError::BadFee { expected_fee })
+                    | LedgerError::TransferFrom(TransferFromError::BadFee { expected_fee }) => {
+                        Some(nat_to_u64(expected_fee))
+                    }
+                    _ => None,
+                };
+                let delay = payout_retry_delay(leg.attempts);
+                leg.status = if too_old && may_have_executed {
+                    // The ledger can no longer tell us whether the earlier attempt went through
+                    PayoutLegStatus::Failed {
+                        error: format!(
+                            "Outcome of an earlier attempt is unknown and outside the deduplication window, needs manual review: {}",
+                            e
+                        ),
+                        next_retry_at: Timestamp::MAX,
+                    }
+                } else if unpayable {
+                    PayoutLegStatus::Failed {
+                        error: format!("Needs manual review: {}", e),
+                        next_retry_at: Timestamp::MAX,
+                    }
+                } else if let Some(expected_fee) = expected_fee {
+                    // The fee changed since the plan was made. The leg keeps its share of the
+                    // balance, so it pays the new fee out of its amount.
+                    let share = leg.amount + leg.fee;
+                    if may_have_executed {
+                        // A resend with a different fee would not be deduplicated
+                        PayoutLegStatus::Failed {
+                            error: format!(
+                                "Fee changed while the outcome of an earlier attempt is unknown, needs manual review: {}",
+                                e
+                            ),
+                            next_retry_at: Timestamp::MAX,
+                        }
+                    } else if share <= expected_fee {
+                        PayoutLegStatus::Failed {
+                            error: format!(
+                                "Share of {} ckBTC does not cover the new fee, needs manual review: {}",
+                                share, e
+                            ),
+                            next_retry_at: Timestamp::MAX,
+                        }
+                    } else {
+                        leg.amount = share - expected_fee;
+                        leg.fee = expected_fee;
+                        PayoutLegStatus::Pending
+                    }
+                } else if e.outcome_unknown() {
+                    PayoutLegStatus::OutcomeUnknown {
+                        error: e.to_string(),
+                        next_retry_at: current_time.saturating_add(delay.min(PAYOUT_UNKNOWN_RETRY_MAX)),
+                    }
+                } else {
+                    PayoutLegStatus::Failed {
+                        error: e.to_string(),
+                        next_retry_at: current_time.saturating_add(delay),
+                    }
+                };
+                ic_cdk::println!(
+                    "Transfer error to {}: {} (attempt {}, status: {:?})",
+                    leg.to, e, leg.attempts, leg.status
+                );
+                AccountEvent::PayoutTransferFailed {
+                    amount: attempted_amount,
+                    to: leg.to,
+                    attempt: leg.attempts,
+                    error: e.to_string(),
+                }
+            }
+        };
+
+        // Keep the tracked balance in line with the deposit subaccount
+        if custody_mode == CustodyMode::Custodial && matches!(leg.status, PayoutLegStatus::Paid { .. }) {
+            account.balance = account.balance.saturating_sub(leg.amount + leg.fee);
+        }
+
+        account.record(current_time, event);
+    });
+}
+
+/// Check and transfer funds if timeout occurred (after grace period).
+/// The payout plan is persisted on the account before any transfer is made; every call
+/// retries the legs that are due, and `success` is only reported once every leg is paid.
+async fn check_and_transfer(user: &UserAccount, max_transfers: usize) -> Result<TransferResult, String> {
+    let current_time = Timestamp::now();
+    let pauses = STATE.with(|state| state.borrow().pauses.clone());
+    
+    // Check if timeout has been reached
+    let timeout_reached = current_time >= user.timeout_at(&pauses);
+    
+    if !timeout_reached {
+        return Ok(TransferResult {
+            success: false,
+            message: "Timeout not reached".to_string(),
             block_index: None,
+            transfers_attempted: 0,
+        });
+    }
+    
+    // Check if we're still in contestation period
+    let grace_period_end = user.grace_period_end(&pauses);
+    
+    if current_time < grace_period_end {
+        return Ok(TransferResult {
+            success: false,
+            message: format!(
+                "Still in contestation period. Transfer will execute at {}",
+                grace_period_end.as_nanos()
+            ),
+            block_index: None,
+            transfers_attempted: 0,
+        });
+    }
+
+    if let Err(e) = pauses.check(PauseScope::Payouts) {
+        return Ok(TransferResult {
+            success: false,
+            message: e.to_string(),
+            block_index: None,
+            transfers_attempted: 0,
+        });
+    }
+
+    // A withdrawal or sync still in flight would race the payout for the same funds
+    let _guard = match AccountGuard::acquire(user.principal) {
+        Ok(guard) => guard,
+        Err(_) => {
+            return Ok(TransferResult {
+                success: false,
+                message: "Account busy, payout deferred to the next check".to_string(),
+                block_index: None,
+                transfers_attempted: 0,
+            });
+        }
+    };
+
+    // The caller's copy predates the lock; plan from the account as it is now
+    let user = match STATE.with(|state| state.borrow().users.get(&user.principal).cloned()) {
+        Some(account) => account,
+        None => return Err("User account not found".to_string()),
+    };
+    let user = &user;
+
+    // The deposit subaccount holds less than the plan would send
+    if let Some(drift) = &user.balance_drift {
+        return Ok(TransferResult {
+            success: false,
+            message: format!(
+                "Payout held: the deposit subaccount holds {} ckBTC but {} ckBTC are tracked",
+                drift.ledger, drift.tracked
+            ),
+            block_index: None,
+            transfers_attempted: 0,
         });
     }
 
-    if user.balance == 0 {
+    // Paying out now would leave the balance from the default account behind
+    if let Some(legacy) = &user.legacy_balance {
         return Ok(TransferResult {
             success: false,
-            message: "No balance to transfer".to_string(),
+            message: format!(
+                "Payout held: {} ckBTC are still being moved into the deposit subaccount",
+                legacy.amount
+            ),
             block_index: None,
+            transfers_attempted: 0,
         });
     }
 
     // Get ledger canister ID
     let ledger = STATE.with(|state| {
         let s = state.borrow();
-        s.ckbtc_ledger
+        s.config.ckbtc_ledger
     });
 
-    // Handle multiple beneficiaries or single beneficiary
-    if user.beneficiaries.len() > 1 {
-        // Multiple beneficiaries - split according to percentages
-        let mut total_transferred = 0u64;
-        let mut transfer_results = Vec::new();
-        
-        for beneficiary in &user.beneficiaries {
-            let amount = (user.balance as u128 * beneficiary.percentage as u128 / 100) as u64;
-            if amount > 0 {
-                let account_type = if beneficiary.subaccount.is_some() {
-                    "principal-associated account"
-                } else {
-                    "wallet address"
-                };
-                ic_cdk::println!(
-                    "Transferring {} ckBTC to {} ({})",
-                    amount, beneficiary.principal, account_type
-                );
-                match transfer_ckbtc(ledger, beneficiary.principal, amount).await {
-                    Ok(block_index) => {
-                        total_transferred += amount;
-                        let account_desc = if beneficiary.subaccount.is_some() {
-                            format!("account with subaccount")
-                        } else {
-                            format!("wallet")
-                        };
-                        transfer_results.push(format!("{} ckBTC to {} {} (block: {})", amount, beneficiary.principal, account_desc, block_index));
-                    }
-                    Err(e) => {
-                        ic_cdk::println!("Transfer error to {}: {:?}", beneficiary.principal, e);
-                    }
-                }
+    if user.payout.is_none() {
+        let plan = match create_payout_plan(ledger, user).await? {
+            Some(plan) => plan,
+            None => {
+                return Ok(TransferResult {
+                    success: false,
+                    message: "No balance to transfer".to_string(),
+                    block_index: None,
+                    transfers_attempted: 0,
+                });
             }
-        }
-        
-        if total_transferred > 0 {
-            ic_cdk::println!("Timeout transfer successful: {} ckBTC split among {} beneficiaries", total_transferred, user.beneficiaries.len());
-            Ok(TransferResult {
-                success: true,
-                message: format!("Transferred {} ckBTC: {}", total_transferred, transfer_results.join(", ")),
-                block_index: None, // Multiple transfers, no single block index
-            })
-        } else {
-            Err("All transfers failed".to_string())
-        }
-    } else {
-        // Single beneficiary (backward compatible)
-        let beneficiary = user.beneficiaries.first().map(|b| b.principal).unwrap_or(user.beneficiary);
-        
+        };
+
         ic_cdk::println!(
-            "Timeout detected for user: {}. Transferring {} ckBTC to beneficiary: {}",
-            user.principal, user.balance, beneficiary
+            "Timeout detected for user: {}. Distributing {} ckBTC across {} transfer(s)",
+            user.principal,
+            plan.legs.iter().map(|l| l.amount + l.fee).sum::<u64>(),
+            plan.legs.len()
         );
 
-        match transfer_ckbtc(ledger, beneficiary, user.balance).await {
-            Ok(block_index) => {
-                ic_cdk::println!("Transfer successful, block index: {}", block_index);
-                Ok(TransferResult {
-                    success: true,
-                    message: format!("Transferred {} ckBTC to beneficiary", user.balance),
-                    block_index: Some(block_index),
-                })
-            }
-            Err(e) => {
-                ic_cdk::println!("Transfer error: {:?}", e);
-                Err(format!("Transfer failed: {:?}", e))
+        let created = STATE.with(|state| {
+            let mut s = state.borrow_mut();
+            let Some(account) = s.users.get_mut(&user.principal) else {
+                return false;
+            };
+            // The owner may have come back while the plan was being computed
+            if account.payout.is_some() || account.timeout_detected_at.is_none() {
+                return false;
             }
+            account.record(
+                current_time,
+                AccountEvent::PayoutStarted {
+                    amount: plan.legs.iter().map(|l| l.amount).sum(),
+                    transfers: plan.legs.len() as u32,
+                },
+            );
+            account.payout = Some(plan);
+            true
+        });
+        if !created {
+            return Ok(TransferResult {
+                success: false,
+                message: "Account changed while planning the payout".to_string(),
+                block_index: None,
+                transfers_attempted: 0,
+            });
         }
     }
-}
 
-/// Start the periodic timeout checker
-fn start_timeout_checker() {
-    set_timer_interval(Duration::from_secs(60), || {
-        check_timeouts()
+    // Retry every leg that is pending or whose backoff has elapsed.
+    // In-flight legs are left alone until they are stale: resending them is safe
+    // because the ledger deduplicates on created_at_time and memo.
+    let due_legs: Vec<usize> = STATE.with(|state| {
+        let s = state.borrow();
+        s.users
+            .get(&user.principal)
+            .and_then(|a| a.payout.as_ref())
+            .map(|plan| {
+                plan.legs
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, leg)| match &leg.status {
+                        PayoutLegStatus::Pending => true,
+                        PayoutLegStatus::Failed { next_retry_at, .. }
+                        | PayoutLegStatus::OutcomeUnknown { next_retry_at, .. } => *next_retry_at <= current_time,
+                        PayoutLegStatus::InFlight { started_at } => {
+                            started_at.saturating_add(PAYOUT_IN_FLIGHT_STALE) <= current_time
+                        }
+                        PayoutLegStatus::Paid { .. } => false,
+                    })
+                    .map(|(i, _)| i)
+                    .collect()
+            })
+            .unwrap_or_default()
     });
-    ic_cdk::println!("Timeout checker started - checking every 60 seconds");
-}
 
-/// Check all users for timeout and transfer funds if needed
-async fn check_timeouts() {
-    let current_time = time();
-    let mut users_to_check = Vec::new();
-    let mut users_to_mark_timeout = Vec::new();
+    // Each leg pays a different account, so they can all be in flight at once
+    let due_legs: Vec<usize> = due_legs.into_iter().take(max_transfers).collect();
+    let transfers_attempted = due_legs.len() as u32;
+    join_all(due_legs.into_iter().map(|index| execute_payout_leg(ledger, user.principal, index))).await;
 
-    STATE.with(|state| {
+    let plan = STATE.with(|state| {
         let s = state.borrow();
-        for (principal, account) in s.users.iter() {
-            let time_since_heartbeat = current_time.saturating_sub(account.last_heartbeat);
-            let timeout_reached = time_since_heartbeat >= account.timeout_duration_seconds;
-            
-            if timeout_reached {
-                // Mark timeout detection if not already marked
-                if account.timeout_detected_at.is_none() {
-                    users_to_mark_timeout.push(*principal);
-                    ic_cdk::println!(
-                        "User {} timeout detected: {}s since last heartbeat (threshold: {}s). Grace period started.",
-                        principal, time_since_heartbeat, account.timeout_duration_seconds
-                    );
-                }
-                
-                // Check if grace period has passed
-                let timeout_detected_at = account.timeout_detected_at.unwrap_or(account.last_heartbeat + account.timeout_duration_seconds);
-                let grace_period_end = timeout_detected_at + account.contestation_period_seconds;
-                
-                if current_time >= grace_period_end {
-                    ic_cdk::println!(
-                        "User {} grace period expired. Transfer will be executed.",
-                        principal
-                    );
-                    users_to_check.push(account.clone());
-                }
-            }
-        }
+        s.users.get(&user.principal).and_then(|a| a.payout.clone())
     });
-    
-    // Mark timeout detection timestamps
-    for principal in users_to_mark_timeout {
+    let Some(plan) = plan else {
+        return Err("Payout plan disappeared".to_string());
+    };
+
+    let paid: Vec<(u64, u64)> = plan
+        .legs
+        .iter()
+        .filter_map(|leg| match leg.status {
+            PayoutLegStatus::Paid { block_index } => Some((leg.amount, block_index)),
+            _ => None,
+        })
+        .collect();
+    let total_transferred: u64 = paid.iter().map(|(amount, _)| amount).sum();
+
+    if paid.len() == plan.legs.len() {
+        ic_cdk::println!(
+            "Timeout transfer successful: {} ckBTC split among {} beneficiaries",
+            total_transferred, plan.legs.len()
+        );
+        Ok(TransferResult {
+            success: true,
+            message: format!("Transferred {} ckBTC in {} transfer(s)", total_transferred, plan.legs.len()),
+            // Multiple transfers have no single block index
+            block_index: if paid.len() == 1 { Some(paid[0].1) } else { None },
+            transfers_attempted,
+        })
+    } else {
+        Ok(TransferResult {
+            success: false,
+            message: format!(
+                "Payout in progress: {} of {} transfer(s) settled ({} ckBTC)",
+                paid.len(),
+                plan.legs.len(),
+                total_transferred
+            ),
+            block_index: None,
+            transfers_attempted,
+        })
+    }
+}
+
+/// Handle an account whose deadline has passed (see `advance_deadline`) and certify
+/// where it ended up. Returns the number of ledger transfers sent.
+async fn process_deadline(principal: Principal, max_transfers: usize) -> usize {
+    let attempted = advance_deadline(principal, max_transfers).await;
+    certification::refresh(&[principal]);
+    attempted
+}
+
+/// Start the contestation period when the timeout is first seen, and run (or resume)
+/// the payout once it has ended.
+/// Sends at most `max_transfers` ledger transfers and returns how many it sent.
+async fn advance_deadline(principal: Principal, max_transfers: usize) -> usize {
+    let current_time = Timestamp::now();
+    let Some((account, pauses)) = STATE.with(|state| {
+        let s = state.borrow();
+        s.users.get(&principal).map(|account| (account.clone(), s.pauses.clone()))
+    }) else {
+        return 0;
+    };
+
+    if account.payout.is_none() && current_time < account.timeout_at(&pauses) {
+        // A heartbeat arrived after the deadline was indexed
+        return 0;
+    }
+
+    if account.timeout_detected_at.is_none() {
+        ic_cdk::println!(
+            "User {} timeout detected: {}s since last heartbeat (threshold: {}s). Grace period started.",
+            principal,
+            current_time.saturating_duration_since(account.last_heartbeat).as_secs(),
+            account.timeout_duration_seconds.as_secs()
+        );
         STATE.with(|state| {
             let mut s = state.borrow_mut();
             if let Some(account) = s.users.get_mut(&principal) {
                 account.timeout_detected_at = Some(current_time);
-                account.transaction_history.push(TransactionLog {
-                    timestamp: current_time,
-                    transaction_type: "timeout_detected".to_string(),
-                    amount: None,
-                    details: format!("Timeout detected. Grace period: {}s", account.contestation_period_seconds),
-                });
-                if account.transaction_history.len() > 100 {
-                    account.transaction_history.remove(0);
-                }
+                let contestation_period = account.contestation_period_seconds;
+                account.record(current_time, AccountEvent::TimeoutDetected { contestation_period });
             }
         });
-    }
 
-    for user in users_to_check {
-        ic_cdk::println!("Processing timeout for user: {}", user.principal);
-        match check_and_transfer(&user).await {
-            Ok(result) => {
-                if result.success {
-                    ic_cdk::println!("Transfer successful: {}", result.message);
-                    // Remove user account after successful transfer
-                    STATE.with(|state| {
-                        let mut s = state.borrow_mut();
-                        s.users.remove(&user.principal);
-                    });
-                } else {
-                    ic_cdk::println!("Transfer not needed: {}", result.message);
-                }
+        // Snapshot the allowance when the grace period starts so owners and trusted
+        // parties can see whether it will cover the transfer
+        if account.custody_mode == CustodyMode::Allowance {
+            let ledger = STATE.with(|state| state.borrow().config.ckbtc_ledger);
+            if let Err(e) = refresh_allowance_status(ledger, principal).await {
+                ic_cdk::println!("Allowance refresh failed for {}: {}", principal, e);
             }
-            Err(e) => {
-                ic_cdk::println!("Error processing timeout for {}: {}", user.principal, e);
+        }
+        return 0;
+    }
+
+    if account.payout.is_none() && current_time < account.grace_period_end(&pauses) {
+        return 0;
+    }
+
+    ic_cdk::println!("Processing timeout for user: {}", principal);
+    match check_and_transfer(&account, max_transfers).await {
+        Ok(result) => {
+            if result.success {
+                ic_cdk::println!("Transfer successful: {}", result.message);
+                finish_payout(&principal);
+            } else {
+                ic_cdk::println!("Transfer not completed: {}", result.message);
             }
+            result.transfers_attempted as usize
+        }
+        Err(e) => {
+            ic_cdk::println!("Error processing timeout for {}: {}", principal, e);
+            0
         }
     }
 }
 
-/// Query user account information
+/// Remove an account once every leg of its payout is paid.
+/// Deposits synced while the payout was running stay tracked and get a new payout plan.
+fn finish_payout(user: &Principal) {
+    STATE.with(|state| {
+        let mut s = state.borrow_mut();
+        let Some(account) = s.users.get_mut(user) else {
+            return;
+        };
+        if account.custody_mode == CustodyMode::Custodial && account.balance > 0 {
+            ic_cdk::println!(
+                "Payout for {} complete, {} ckBTC arrived meanwhile and will be paid out next",
+                user, account.balance
+            );
+            account.payout = None;
+        } else {
+            s.users.remove(user);
+            audit::append(Timestamp::now(), audit::AuditEvent::AccountClosed { owner: *user });
+        }
+    });
+}
+
+/// Query user account information.
+/// Deprecated: the response is not certified; use `get_certified_status` where it matters.
 #[query]
 fn get_account_info() -> AccountInfoResult {
-    let caller = caller();
+    let caller = msg_caller();
     
     STATE.with(|state| {
-        let mut s = state.borrow_mut();
-        match s.users.get_mut(&caller) {
-            Some(account) => {
-                // Ensure backward compatibility - initialize new fields if missing
-                if account.beneficiaries.is_empty() {
-                    account.beneficiaries = vec![Beneficiary {
-                        principal: account.beneficiary,
-                        percentage: 100,
-                        subaccount: None,
-                    }];
-                }
-                // Initialize new fields for backward compatibility
-                if account.contestation_period_seconds == 0 {
-                    account.contestation_period_seconds = 7 * 24 * 60 * 60; // Default 7 days
-                }
-                AccountInfoResult::ok(account.clone())
-            }
-            None => AccountInfoResult::err("User not registered".to_string()),
+        let s = state.borrow();
+        match s.users.get(&caller) {
+            Some(account) => AccountInfoResult::ok(account.clone()),
+            None => AccountInfoResult::err(DeadManError::NotRegistered),
         }
     })
 }
 
-/// Query all registered users (admin function)
+/// Get the ledger account the caller should send ckBTC deposits to
 #[query]
-fn list_users() -> Vec<(Principal, UserAccount)> {
+fn get_deposit_account() -> DepositAccountResult {
+    let caller = msg_caller();
+
     STATE.with(|state| {
         let s = state.borrow();
-        s.users.iter().map(|(k, v)| (*k, v.clone())).collect()
+        if s.users.contains_key(&caller) {
+            DepositAccountResult::ok(deposit_account(&caller))
+        } else {
+            DepositAccountResult::err(DeadManError::NotRegistered)
+        }
     })
 }
 
 /// Get ckBTC balance for a specific user
 #[query]
 fn get_user_balance() -> BalanceResult {
-    let caller = caller();
+    let caller = msg_caller();
     
     STATE.with(|state| {
         let s = state.borrow();
         match s.users.get(&caller) {
             Some(u) => BalanceResult::ok(u.balance),
-            None => BalanceResult::err("User not registered".to_string()),
+            None => BalanceResult::err(DeadManError::NotRegistered),
         }
     })
 }
 
-/// Get ckBTC balance for the canister from the ledger
+/// Get ckBTC balance of the canister's default account from the ledger.
+/// Deposits sit in per-user subaccounts; see `admin_get_reconciliation_report` for those.
 #[update]
 async fn get_ckbtc_balance() -> BalanceResult {
     let ledger = STATE.with(|state| {
         let s = state.borrow();
-        s.ckbtc_ledger
+        s.config.ckbtc_ledger
     });
 
-    // Call icrc1_balance_of on the ledger
-    let canister_id = id();
     let account = Account {
-        owner: canister_id,
+        owner: canister_self(),
         subaccount: None,
     };
+    match ledger_balance_of(ledger, account).await {
+        Ok(balance) => BalanceResult::ok(balance),
+        Err(e) => BalanceResult::err(e.into()),
+    }
+}
 
-    match Call::unbounded_wait(ledger, "icrc1_balance_of")
-        .with_arg(&(account,))
-        .await
-    {
-        Ok(response) => {
-            match response.candid::<(u64,)>() {
-                Ok((balance,)) => BalanceResult::ok(balance),
-                Err(e) => {
-                    ic_cdk::println!("Failed to decode balance response: {:?}", e);
-                    // Fallback to sum of user balances
-                    BalanceResult::ok(STATE.with(|state| {
-                        let s = state.borrow();
-                        s.users.values().map(|u| u.balance).sum()
-                    }))
+/// Update user settings (timeout duration and/or beneficiary)
+#[update]
+async fn update_settings(
+    timeout_duration_seconds: Option<u64>,
+    beneficiary: Option<Principal>,
+    beneficiary_subaccount: Option<Vec<u8>>,
+) -> Result_ {
+    let caller = msg_caller();
+    let current_time = Timestamp::now();
+
+    let result = STATE.with(|state| {
+        let mut s = state.borrow_mut();
+        
+        match s.users.get_mut(&caller) {
+            Some(account) => {
+                // The payout plan was made from the current settings
+                if account.payout.is_some() {
+                    return Result_::err(DeadManError::PayoutInProgress);
+                }
+                let mut changes = Vec::new();
+                let mut events = Vec::new();
+
+                // Validate everything before changing anything
+                if timeout_duration_seconds == Some(0) {
+                    return Result_::err(DeadManError::invalid_argument(
+                        "timeout_duration_seconds",
+                        "must be greater than 0",
+                    ));
+                }
+                let new_beneficiary = match beneficiary {
+                    Some(ben) => {
+                        let new_beneficiary = Beneficiary {
+                            principal: ben,
+                            percentage: 100,
+                            subaccount: beneficiary_subaccount,
+                        };
+                        if let Err(e) = validate_beneficiaries(&caller, std::slice::from_ref(&new_beneficiary)) {
+                            return Result_::err(e);
+                        }
+                        Some(new_beneficiary)
+                    }
+                    None if beneficiary_subaccount.is_some() => {
+                        return Result_::err(DeadManError::invalid_argument(
+                            "beneficiary_subaccount",
+                            "a beneficiary subaccount requires a beneficiary",
+                        ));
+                    }
+                    None => None,
+                };
+
+                if let Some(timeout) = timeout_duration_seconds {
+                    let new = DurationSecs::from_secs(timeout);
+                    events.push(AccountEvent::TimeoutChanged {
+                        old: account.timeout_duration_seconds,
+                        new,
+                    });
+                    account.timeout_duration_seconds = new;
+                    changes.push(format!("timeout: {}s", timeout));
                 }
+
+                if let Some(new_beneficiary) = new_beneficiary {
+                    account.beneficiary = new_beneficiary.principal;
+                    changes.push(format!("beneficiary: {}", new_beneficiary.account()));
+                    let old = std::mem::replace(&mut account.beneficiaries, vec![new_beneficiary]);
+                    events.push(AccountEvent::BeneficiariesChanged {
+                        old,
+                        new: account.beneficiaries.clone(),
+                    });
+                }
+                
+                if changes.is_empty() {
+                    return Result_::err(DeadManError::invalid_argument("settings", "no changes provided"));
+                }
+
+                for event in events {
+                    account.record(current_time, event);
+                }
+                
+                ic_cdk::println!("Settings updated for user: {}, changes: {:?}", caller, changes);
+                Result_::ok(format!("Settings updated: {}", changes.join(", ")))
             }
+            None => Result_::err(DeadManError::NotRegistered),
         }
-        Err(e) => {
-            ic_cdk::println!("Balance query failed: {:?}", e);
-            // Fallback to sum of user balances
-            BalanceResult::ok(STATE.with(|state| {
-                let s = state.borrow();
-                s.users.values().map(|u| u.balance).sum()
-            }))
+    });
+
+    scheduler::reschedule(caller);
+    certification::refresh(&[caller]);
+    result
+}
+
+/// Replace the beneficiary list. The first entry becomes the primary beneficiary.
+/// Percentages must sum to exactly 100.
+#[update]
+async fn set_beneficiaries(beneficiaries: Vec<Beneficiary>) -> Result_ {
+    let caller = msg_caller();
+    let current_time = Timestamp::now();
+
+    if let Err(e) = validate_beneficiaries(&caller, &beneficiaries) {
+        return Result_::err(e);
+    }
+
+    let result = STATE.with(|state| {
+        let mut s = state.borrow_mut();
+
+        match s.users.get_mut(&caller) {
+            Some(account) => {
+                if account.payout.is_some() {
+                    return Result_::err(DeadManError::PayoutInProgress);
+                }
+                let summary = beneficiaries
+                    .iter()
+                    .map(|b| format!("{} {}%", b.account(), b.percentage))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                account.beneficiary = beneficiaries[0].principal;
+                let old = std::mem::replace(&mut account.beneficiaries, beneficiaries.clone());
+                account.record(
+                    current_time,
+                    AccountEvent::BeneficiariesChanged {
+                        old,
+                        new: beneficiaries,
+                    },
+                );
+                ic_cdk::println!("Beneficiaries set for user {}: {}", caller, summary);
+                Result_::ok(format!("Beneficiaries set: {}", summary))
+            }
+            None => Result_::err(DeadManError::NotRegistered),
+        }
+    });
+    certification::refresh(&[caller]);
+    result
+}
+
+/// Add a beneficiary. Their share is taken from the primary beneficiary's share.
+#[update]
+async fn add_beneficiary(beneficiary: Beneficiary) -> Result_ {
+    let caller = msg_caller();
+    let current_time = Timestamp::now();
+
+    let result = STATE.with(|state| {
+        let mut s = state.borrow_mut();
+
+        match s.users.get_mut(&caller) {
+            Some(account) => {
+                if account.payout.is_some() {
+                    return Result_::err(DeadManError::PayoutInProgress);
+                }
+                let mut updated = account.beneficiaries.clone();
+                match updated.first_mut() {
+                    Some(primary) if primary.percentage > beneficiary.percentage => {
+                        primary.percentage -= beneficiary.percentage;
+                    }
+                    Some(primary) => {
+                        return Result_::err(DeadManError::invalid_argument(
+                            "percentage",
+                            format!(
+                                "the primary beneficiary only holds {}%, cannot give away {}%",
+                                primary.percentage, beneficiary.percentage
+                            ),
+                        ));
+                    }
+                    None => {
+                        return Result_::err(DeadManError::internal("No primary beneficiary to take the share from"));
+                    }
+                }
+                updated.push(beneficiary.clone());
+                if let Err(e) = validate_beneficiaries(&caller, &updated) {
+                    return Result_::err(e);
+                }
+
+                let old = std::mem::replace(&mut account.beneficiaries, updated);
+                let new = account.beneficiaries.clone();
+                account.record(current_time, AccountEvent::BeneficiariesChanged { old, new });
+                ic_cdk::println!(
+                    "Beneficiary added: {} ({}%) for user {}",
+                    beneficiary.principal, beneficiary.percentage, caller
+                );
+                Result_::ok(format!("Beneficiary {} added with {}%", beneficiary.principal, beneficiary.percentage))
+            }
+            None => Result_::err(DeadManError::NotRegistered),
         }
-    }
+    });
+    certification::refresh(&[caller]);
+    result
 }
 
-/// Update user settings (timeout duration and/or beneficiary)
+/// Remove a beneficiary. Their share goes back to the primary beneficiary.
+/// The primary beneficiary itself can only be replaced via set_beneficiaries.
 #[update]
-async fn update_settings(
-    timeout_duration_seconds: Option<u64>,
-    beneficiary: Option<Principal>,
-) -> Result_ {
-    let caller = caller();
-    let current_time = time();
+async fn remove_beneficiary(beneficiary: Principal) -> Result_ {
+    let caller = msg_caller();
+    let current_time = Timestamp::now();
 
-    STATE.with(|state| {
+    let result = STATE.with(|state| {
         let mut s = state.borrow_mut();
-        
+
         match s.users.get_mut(&caller) {
             Some(account) => {
-                let mut changes = Vec::new();
-                
-                if let Some(timeout) = timeout_duration_seconds {
-                    if timeout == 0 {
-                        return Result_::err("Timeout duration must be greater than 0".to_string());
-                    }
-                    account.timeout_duration_seconds = timeout;
-                    changes.push(format!("timeout: {}s", timeout));
-                }
-                
-                if let Some(ben) = beneficiary {
-                    account.beneficiary = ben;
-                    account.beneficiaries = vec![Beneficiary {
-                        principal: ben,
-                        percentage: 100,
-                        subaccount: None,
-                    }];
-                    changes.push(format!("beneficiary: {}", ben));
-                }
-                
-                if changes.is_empty() {
-                    return Result_::err("No changes provided".to_string());
+                if account.payout.is_some() {
+                    return Result_::err(DeadManError::PayoutInProgress);
                 }
-                
-                account.transaction_history.push(TransactionLog {
-                    timestamp: current_time,
-                    transaction_type: "update".to_string(),
-                    amount: None,
-                    details: format!("Settings updated: {}", changes.join(", ")),
-                });
-                
-                if account.transaction_history.len() > 100 {
-                    account.transaction_history.remove(0);
+                let pos = match account.beneficiaries.iter().position(|b| b.principal == beneficiary) {
+                    Some(pos) => pos,
+                    None => return Result_::err(DeadManError::not_found("Beneficiary")),
+                };
+                if pos == 0 {
+                    return Result_::err(DeadManError::invalid_argument(
+                        "beneficiary",
+                        "cannot remove the primary beneficiary, use set_beneficiaries instead",
+                    ));
                 }
-                
-                ic_cdk::println!("Settings updated for user: {}, changes: {:?}", caller, changes);
-                Result_::ok(format!("Settings updated: {}", changes.join(", ")))
+
+                let old = account.beneficiaries.clone();
+                let removed = account.beneficiaries.remove(pos);
+                account.beneficiaries[0].percentage += removed.percentage;
+                let new = account.beneficiaries.clone();
+                account.record(current_time, AccountEvent::BeneficiariesChanged { old, new });
+                ic_cdk::println!("Beneficiary removed: {} for user {}", beneficiary, caller);
+                Result_::ok(format!("Beneficiary {} removed", beneficiary))
             }
-            None => Result_::err("User not registered".to_string()),
+            None => Result_::err(DeadManError::NotRegistered),
         }
-    })
+    });
+    certification::refresh(&[caller]);
+    result
 }
 
 /// Withdraw ckBTC from the dead man switch (before timeout)
 #[update]
-async fn withdraw(amount: u64, to: Principal) -> Result_ {
-    let caller = caller();
-    let current_time = time();
+async fn withdraw(amount: u64, to: Account) -> Result_ {
+    withdraw_from(msg_caller(), amount, to).await
+}
+
+/// `withdraw` on behalf of `caller`
+async fn withdraw_from(caller: Principal, amount: u64, to: Account) -> Result_ {
+    let current_time = Timestamp::now();
     
     // Check if user is registered
     if !STATE.with(|state| {
         let s = state.borrow();
         s.users.contains_key(&caller)
     }) {
-        return Result_::err("User not registered. Please register first.".to_string());
+        return Result_::err(DeadManError::NotRegistered);
     }
 
-    // Check balance and get ledger
-    let account_balance = STATE.with(|state| {
-        let s = state.borrow();
-        s.users.get(&caller).map(|a| a.balance)
-    });
-    
-    match account_balance {
-        Some(balance) => {
-            if balance < amount {
-                return Result_::err("Insufficient balance".to_string());
-            }
-        }
-        None => {
-            return Result_::err("User account not found".to_string());
-        }
+    if let Err(e) = STATE.with(|state| state.borrow().pauses.check(PauseScope::Withdrawals)) {
+        return Result_::err(e);
     }
-    
-    let ledger = STATE.with(|state| {
-        let s = state.borrow();
-        s.ckbtc_ledger
-    });
+
+    // Held until the withdrawal has settled: no other withdrawal, sync or payout
+    // can touch this account in the meantime
+    let _guard = match AccountGuard::acquire(caller) {
+        Ok(guard) => guard,
+        Err(e) => return Result_::err(e),
+    };
 
     let ledger = STATE.with(|state| {
         let s = state.borrow();
-        s.ckbtc_ledger
+        s.config.ckbtc_ledger
     });
 
-    // Transfer ckBTC to withdrawal address (default to wallet address, no subaccount)
-    match transfer_ckbtc(ledger, to, amount).await {
+    // A withdrawal with an unknown outcome is resent unchanged, so the ledger deduplicates it
+    let pending = STATE.with(|state| {
+        state
+            .borrow()
+            .users
+            .get(&caller)
+            .and_then(|account| account.pending_withdrawal.clone())
+    });
+    let (transfer, reservation) = match pending {
+        Some(pending) if pending.amount == amount && pending.to == to => (pending, None),
+        Some(pending) => {
+            return Result_::err(DeadManError::conflict(format!(
+                "The withdrawal of {} ckBTC to {} has an unknown outcome; retry it with the same amount and account, or call sync_balance",
+                pending.amount, pending.to
+            )));
+        }
+        None => {
+            // The ledger fee is charged to the deposit subaccount on top of the amount
+            let fee = match ledger_fee(ledger).await {
+                Ok(fee) => fee,
+                Err(e) => return Result_::err(e.into()),
+            };
+            let pending = PendingWithdrawal {
+                amount,
+                fee,
+                to,
+                created_at_time: current_time,
+                memo: withdrawal_memo(),
+            };
+
+            // Check and deduct the balance before the transfer; rolled back if the ledger refuses it.
+            // The transfer is kept with it in case its outcome turns out to be unknown.
+            let reserved = STATE.with(|state| match state.borrow_mut().users.get_mut(&caller) {
+                Some(account) => account.reserve_withdrawal(amount.saturating_add(fee)).inspect(|_| {
+                    account.pending_withdrawal = Some(pending.clone());
+                }),
+                None => Err(DeadManError::NotRegistered),
+            });
+            match reserved {
+                Ok(reservation) => (pending, Some(reservation)),
+                Err(e) => return Result_::err(e),
+            }
+        }
+    };
+    let resend = reservation.is_none();
+
+    // Transfer ckBTC from the caller's deposit subaccount to the withdrawal account
+    let result = match transfer_ckbtc(
+        ledger,
+        Some(deposit_subaccount(&caller)),
+        to,
+        amount,
+        transfer.fee,
+        transfer.created_at_time,
+        transfer.memo.clone(),
+    )
+    .await
+    {
         Ok(block_index) => {
             STATE.with(|state| {
                 let mut s = state.borrow_mut();
                 if let Some(account) = s.users.get_mut(&caller) {
-                    account.balance -= amount;
-                    account.transaction_history.push(TransactionLog {
-                        timestamp: current_time,
-                        transaction_type: "withdrawal".to_string(),
-                        amount: Some(amount),
-                        details: format!("Withdrew {} ckBTC to {}", amount, to),
-                    });
-                    if account.transaction_history.len() > 100 {
-                        account.transaction_history.remove(0);
-                    }
+                    account.pending_withdrawal = None;
+                    account.record(
+                        current_time,
+                        AccountEvent::Withdrawal {
+                            amount,
+                            fee: transfer.fee,
+                            to,
+                            block_index,
+                        },
+                    );
                 }
             });
             ic_cdk::println!("Withdrawal successful: {} ckBTC to {}, block: {}", amount, to, block_index);
             Result_::ok(format!("Withdrew {} ckBTC to {}", amount, to))
         }
-        Err(e) => {
-            ic_cdk::println!("Withdrawal error: {:?}", e);
-            Result_::err(format!("Withdrawal failed: {:?}", e))
+        Err(e) if e.outcome_unknown() || (resend && !e.rules_out_duplicate()) => {
+            // The funds may have left; keep them deducted until a retry or sync_balance settles it
+            ic_cdk::println!("Withdrawal outcome unknown: {}", e);
+            Result_::err(DeadManError::LedgerOutcomeUnknown(e))
         }
-    }
-}
-
-/// Get transaction history for the current user
-#[query]
-fn get_transaction_history() -> TransactionHistoryResult {
-    let caller = caller();
-    
-    STATE.with(|state| {
-        let s = state.borrow();
-        match s.users.get(&caller) {
-            Some(u) => TransactionHistoryResult::ok(u.transaction_history.clone()),
-            None => TransactionHistoryResult::err("User not registered".to_string()),
+        Err(e) => {
+            STATE.with(|state| {
+                if let Some(account) = state.borrow_mut().users.get_mut(&caller) {
+                    match reservation {
+                        Some(reservation) => {
+                            account.pending_withdrawal = None;
+                            account.release_withdrawal(reservation);
+                        }
+                        None => {
+                            account.cancel_pending_withdrawal();
+                        }
+                    }
+                }
+            });
+            ic_cdk::println!("Withdrawal error: {}", e);
+            Result_::err(DeadManError::Ledger(e))
         }
-    })
+    };
+    certification::refresh(&[caller]);
+    result
 }
 
 /// Cancel timeout transfer during grace period (user or trusted party)
 #[update]
 async fn cancel_timeout_transfer() -> Result_ {
-    let caller = caller();
-    let current_time = time();
+    let caller = msg_caller();
+    let current_time = Timestamp::now();
     
-    STATE.with(|state| {
+    let mut rescheduled = vec![caller];
+    let result = STATE.with(|state| {
         let mut s = state.borrow_mut();
         
         match s.users.get_mut(&caller) {
             Some(account) => {
+                if account.payout.as_ref().is_some_and(PayoutPlan::has_started) {
+                    return Result_::err(DeadManError::PayoutInProgress);
+                }
                 // User can cancel their own timeout
                 if account.timeout_detected_at.is_some() {
                     account.timeout_detected_at = None;
-                    account.transaction_history.push(TransactionLog {
-                        timestamp: current_time,
-                        transaction_type: "timeout_cancelled".to_string(),
-                        amount: None,
-                        details: "Timeout transfer cancelled by user".to_string(),
-                    });
-                    if account.transaction_history.len() > 100 {
-                        account.transaction_history.remove(0);
-                    }
+                    account.payout = None;
+                    account.record(current_time, AccountEvent::TimeoutCancelled { by: caller });
                     ic_cdk::println!("Timeout transfer cancelled by user: {}", caller);
                     Result_::ok("Timeout transfer cancelled".to_string())
                 } else {
-                    Result_::err("No active timeout to cancel".to_string())
+                    Result_::err(DeadManError::not_found("Active timeout"))
                 }
             }
             None => {
+                if !s.config.features.trusted_party_cancellation {
+                    return Result_::err(DeadManError::FeatureDisabled {
+                        feature: "Trusted party cancellation".to_string(),
+                    });
+                }
                 // Check if caller is a trusted party for any user
                 let mut cancelled = false;
                 for (principal, account) in s.users.iter_mut() {
-                    if account.trusted_parties.contains(&caller) && account.timeout_detected_at.is_some() {
+                    if account.trusted_parties.contains(&caller)
+                        && account.timeout_detected_at.is_some()
+                        && !account.payout.as_ref().is_some_and(PayoutPlan::has_started)
+                    {
                         account.timeout_detected_at = None;
-                        account.transaction_history.push(TransactionLog {
-                            timestamp: current_time,
-                            transaction_type: "timeout_cancelled".to_string(),
-                            amount: None,
-                            details: format!("Timeout transfer cancelled by trusted party: {}", caller),
-                        });
-                        if account.transaction_history.len() > 100 {
-                            account.transaction_history.remove(0);
-                        }
+                        account.payout = None;
+                        account.record(current_time, AccountEvent::TimeoutCancelled { by: caller });
                         cancelled = true;
+                        rescheduled.push(*principal);
                         ic_cdk::println!("Timeout transfer cancelled by trusted party {} for user {}", caller, principal);
                     }
                 }
                 if cancelled {
                     Result_::ok("Timeout transfer cancelled by trusted party".to_string())
                 } else {
-                    Result_::err("Not authorized to cancel timeout transfer".to_string())
+                    Result_::err(DeadManError::Unauthorized {
+                        reason: "only the owner or a trusted party can cancel the timeout transfer".to_string(),
+                    })
                 }
             }
         }
-    })
+    });
+
+    for principal in &rescheduled {
+        scheduler::reschedule(*principal);
+    }
+    certification::refresh(&rescheduled);
+    result
 }
 
 /// Add trusted party who can override during grace period
 #[update]
 async fn add_trusted_party(trusted_party: Principal) -> Result_ {
-    let caller = caller();
-    let current_time = time();
+    let caller = msg_caller();
+    let current_time = Timestamp::now();
     
     STATE.with(|state| {
         let mut s = state.borrow_mut();
         
         match s.users.get_mut(&caller) {
             Some(account) => {
+                if account.payout.is_some() {
+                    return Result_::err(DeadManError::PayoutInProgress);
+                }
                 if account.trusted_parties.contains(&trusted_party) {
-                    return Result_::err("Trusted party already added".to_string());
+                    return Result_::err(DeadManError::conflict("Trusted party already added"));
                 }
                 account.trusted_parties.push(trusted_party);
-                account.transaction_history.push(TransactionLog {
-                    timestamp: current_time,
-                    transaction_type: "update".to_string(),
-                    amount: None,
-                    details: format!("Added trusted party: {}", trusted_party),
-                });
-                if account.transaction_history.len() > 100 {
-                    account.transaction_history.remove(0);
-                }
+                account.record(current_time, AccountEvent::TrustedPartyAdded { principal: trusted_party });
                 ic_cdk::println!("Trusted party added: {} for user {}", trusted_party, caller);
                 Result_::ok(format!("Trusted party {} added", trusted_party))
             }
-            None => Result_::err("User not registered".to_string()),
+            None => Result_::err(DeadManError::NotRegistered),
         }
     })
 }
@@ -988,98 +2453,169 @@ async fn add_trusted_party(trusted_party: Principal) -> Result_ {
 /// Remove trusted party
 #[update]
 async fn remove_trusted_party(trusted_party: Principal) -> Result_ {
-    let caller = caller();
-    let current_time = time();
+    let caller = msg_caller();
+    let current_time = Timestamp::now();
     
     STATE.with(|state| {
         let mut s = state.borrow_mut();
         
         match s.users.get_mut(&caller) {
             Some(account) => {
+                if account.payout.is_some() {
+                    return Result_::err(DeadManError::PayoutInProgress);
+                }
                 if let Some(pos) = account.trusted_parties.iter().position(|&x| x == trusted_party) {
                     account.trusted_parties.remove(pos);
-                    account.transaction_history.push(TransactionLog {
-                        timestamp: current_time,
-                        transaction_type: "update".to_string(),
-                        amount: None,
-                        details: format!("Removed trusted party: {}", trusted_party),
-                    });
-                    if account.transaction_history.len() > 100 {
-                        account.transaction_history.remove(0);
-                    }
+                    account.record(current_time, AccountEvent::TrustedPartyRemoved { principal: trusted_party });
                     ic_cdk::println!("Trusted party removed: {} for user {}", trusted_party, caller);
                     Result_::ok(format!("Trusted party {} removed", trusted_party))
                 } else {
-                    Result_::err("Trusted party not found".to_string())
+                    Result_::err(DeadManError::not_found("Trusted party"))
                 }
             }
-            None => Result_::err("User not registered".to_string()),
+            None => Result_::err(DeadManError::NotRegistered),
         }
     })
 }
 
+/// Choose where funds are held until payout.
+/// Custodial: deposit into your subaccount of this canister.
+/// Allowance: keep funds in your own account and grant this canister an ICRC-2 allowance
+/// (icrc2_approve with this canister as spender); funds are pulled on trigger.
+#[update]
+async fn set_custody_mode(mode: CustodyMode) -> Result_ {
+    let caller = msg_caller();
+    let current_time = Timestamp::now();
+
+    let result = STATE.with(|state| {
+        let mut s = state.borrow_mut();
+        let allowance_mode_enabled = s.config.features.allowance_mode;
+
+        match s.users.get_mut(&caller) {
+            Some(account) => {
+                if account.custody_mode == mode {
+                    return Result_::err(DeadManError::conflict(format!("Custody mode is already {:?}", mode)));
+                }
+                if mode == CustodyMode::Allowance && !allowance_mode_enabled {
+                    return Result_::err(DeadManError::FeatureDisabled {
+                        feature: "Allowance mode".to_string(),
+                    });
+                }
+                if account.payout.is_some() {
+                    return Result_::err(DeadManError::PayoutInProgress);
+                }
+                if mode == CustodyMode::Allowance && account.legacy_balance.is_some() {
+                    return Result_::err(DeadManError::conflict(
+                        "Your balance from before deposit subaccounts is still being moved into your deposit subaccount",
+                    ));
+                }
+                if mode == CustodyMode::Allowance && account.balance > 0 {
+                    return Result_::err(DeadManError::conflict(
+                        "Withdraw your deposited balance before switching to allowance mode",
+                    ));
+                }
+                let previous = std::mem::replace(&mut account.custody_mode, mode);
+                if mode == CustodyMode::Custodial {
+                    account.allowance = None;
+                }
+                account.record(current_time, AccountEvent::CustodyModeChanged { old: previous, new: mode });
+                ic_cdk::println!("Custody mode for user {} set to {:?}", caller, mode);
+                Result_::ok(format!("Custody mode set to {:?}", mode))
+            }
+            None => Result_::err(DeadManError::NotRegistered),
+        }
+    });
+    certification::refresh(&[caller]);
+    result
+}
+
+/// Re-read the caller's ICRC-2 allowance and balance from the ledger (allowance mode)
+#[update]
+async fn refresh_allowance() -> AllowanceResult {
+    let caller = msg_caller();
+
+    if !STATE.with(|state| state.borrow().users.contains_key(&caller)) {
+        return AllowanceResult::err(DeadManError::NotRegistered);
+    }
+    if !is_allowance_mode(&caller) {
+        return AllowanceResult::err(DeadManError::WrongCustodyMode {
+            mode: CustodyMode::Custodial,
+        });
+    }
+
+    let ledger = STATE.with(|state| {
+        let s = state.borrow();
+        s.config.ckbtc_ledger
+    });
+
+    match refresh_allowance_status(ledger, caller).await {
+        Ok(status) => AllowanceResult::ok(status),
+        Err(e) => AllowanceResult::err(e.into()),
+    }
+}
+
 /// Update contestation period
 #[update]
 async fn update_contestation_period(contestation_period_seconds: u64) -> Result_ {
-    let caller = caller();
-    let current_time = time();
+    let caller = msg_caller();
+    let current_time = Timestamp::now();
     
-    STATE.with(|state| {
+    let result = STATE.with(|state| {
         let mut s = state.borrow_mut();
         
         match s.users.get_mut(&caller) {
             Some(account) => {
-                account.contestation_period_seconds = contestation_period_seconds;
-                account.transaction_history.push(TransactionLog {
-                    timestamp: current_time,
-                    transaction_type: "update".to_string(),
-                    amount: None,
-                    details: format!("Contestation period updated to {}s", contestation_period_seconds),
-                });
-                if account.transaction_history.len() > 100 {
-                    account.transaction_history.remove(0);
+                if account.payout.is_some() {
+                    return Result_::err(DeadManError::PayoutInProgress);
                 }
+                let new = DurationSecs::from_secs(contestation_period_seconds);
+                let old = std::mem::replace(&mut account.contestation_period_seconds, new);
+                account.record(current_time, AccountEvent::ContestationPeriodChanged { old, new });
                 ic_cdk::println!("Contestation period updated for user {}: {}s", caller, contestation_period_seconds);
                 Result_::ok(format!("Contestation period updated to {}s", contestation_period_seconds))
             }
-            None => Result_::err("User not registered".to_string()),
+            None => Result_::err(DeadManError::NotRegistered),
         }
-    })
+    });
+
+    scheduler::reschedule(caller);
+    certification::refresh(&[caller]);
+    result
 }
 
-/// Get timeout status including grace period information
+/// Get timeout status including grace period information.
+/// Deprecated: the response is not certified; use `get_certified_status` where it matters.
 #[query]
 fn get_timeout_status() -> TimeoutStatusResult {
-    let caller = caller();
-    let current_time = time();
+    let caller = msg_caller();
+    let current_time = Timestamp::now();
     
     STATE.with(|state| {
         let s = state.borrow();
         match s.users.get(&caller) {
             Some(account) => {
-                let time_since_heartbeat = current_time.saturating_sub(account.last_heartbeat);
-                let timeout_reached = time_since_heartbeat >= account.timeout_duration_seconds;
-                
-                let grace_period_end = if let Some(timeout_at) = account.timeout_detected_at {
-                    timeout_at + account.contestation_period_seconds
-                } else if timeout_reached {
-                    account.last_heartbeat + account.timeout_duration_seconds + account.contestation_period_seconds
-                } else {
-                    0
-                };
+                // Both are pushed back by any time heartbeats were paused, including a
+                // pause still in effect (projected as if it ended now)
+                let timeout_at = account.timeout_at(&s.pauses);
+                let timeout_reached = current_time >= timeout_at;
+                let grace_period_end = account.grace_period_end(&s.pauses);
                 
                 let in_grace_period = timeout_reached && current_time < grace_period_end;
-                let time_until_timeout = if timeout_reached {
-                    0
-                } else {
-                    account.timeout_duration_seconds.saturating_sub(time_since_heartbeat)
-                };
+                let time_until_timeout = timeout_at.saturating_duration_since(current_time);
                 
                 let time_until_transfer = if in_grace_period {
-                    grace_period_end.saturating_sub(current_time)
+                    grace_period_end.saturating_duration_since(current_time)
                 } else {
-                    0
+                    DurationSecs::ZERO
                 };
+
+                // Flag allowances that will lapse before the funds can be pulled
+                let allowance_expires_before_transfer = account.custody_mode == CustodyMode::Allowance
+                    && account
+                        .allowance
+                        .as_ref()
+                        .and_then(|a| a.expires_at)
+                        .is_some_and(|expires_at| expires_at < grace_period_end);
                 
                 TimeoutStatusResult::ok(TimeoutStatus {
                     timeout_reached,
@@ -1090,9 +2626,16 @@ fn get_timeout_status() -> TimeoutStatusResult {
                     last_heartbeat: account.last_heartbeat,
                     timeout_duration: account.timeout_duration_seconds,
                     contestation_period: account.contestation_period_seconds,
+                    custody_mode: account.custody_mode,
+                    allowance: account.allowance.clone(),
+                    allowance_expires_before_transfer,
+                    payout: account.payout.clone(),
+                    heartbeats_paused: s.pauses.is_paused(PauseScope::Heartbeats),
+                    payouts_paused: s.pauses.is_paused(PauseScope::Payouts),
+                    balance_drift: account.balance_drift.clone(),
                 })
             }
-            None => TimeoutStatusResult::err("User not registered".to_string()),
+            None => TimeoutStatusResult::err(DeadManError::NotRegistered),
         }
     })
 }
@@ -1101,12 +2644,37 @@ fn get_timeout_status() -> TimeoutStatusResult {
 pub struct TimeoutStatus {
     pub timeout_reached: bool,
     pub in_grace_period: bool,
-    pub time_until_timeout: u64,
-    pub time_until_transfer: u64,
-    pub grace_period_end: u64,
-    pub last_heartbeat: u64,
-    pub timeout_duration: u64,
-    pub contestation_period: u64,
+    pub time_until_timeout: DurationSecs,
+    pub time_until_transfer: DurationSecs,
+    pub grace_period_end: Timestamp, // Projected from the last heartbeat until the timeout is detected
+    pub last_heartbeat: Timestamp,
+    pub timeout_duration: DurationSecs,
+    pub contestation_period: DurationSecs,
+    pub custody_mode: CustodyMode,
+    pub allowance: Option<AllowanceStatus>, // Last observed allowance (allowance mode only)
+    pub allowance_expires_before_transfer: bool,
+    pub payout: Option<PayoutPlan>, // Per-beneficiary progress once the payout has started
+    pub heartbeats_paused: bool, // Deadlines above keep moving back until heartbeats resume
+    pub payouts_paused: bool, // Transfers are held even if the contestation period is over
+    pub balance_drift: Option<BalanceDrift>, // Transfers are held until the deposit subaccount covers the balance
+}
+
+/// Deployment configuration (ledger, minter and index canisters, feature toggles)
+#[query]
+fn get_config() -> CanisterConfig {
+    STATE.with(|state| state.borrow().config.clone())
+}
+
+/// Backlog and last-round statistics of the timeout checker
+#[query]
+fn get_checker_status() -> scheduler::CheckerStatus {
+    scheduler::status()
+}
+
+/// Which operations are currently paused by the operators, and past heartbeat pauses
+#[query]
+fn get_pause_status() -> PauseState {
+    STATE.with(|state| state.borrow().pauses.clone())
 }
 
 #[query]
@@ -1114,53 +2682,414 @@ fn greet(name: String) -> String {
     format!("Hello, {}! This is the Dead Man Switch Canister.", name)
 }
 
-/// DEV ONLY: Set mock balance for demo purposes (local development)
-/// This bypasses ledger verification and directly sets the balance
-/// Only works in local development - will fail on mainnet
+/// DEMO ONLY: fund the caller's deposit account by minting on the mock ledger
+/// (`mock_ledger` crate), then sync the balance like a real deposit.
+/// Compiled only with the `demo` feature and callable only if enabled in the config.
+#[cfg(feature = "demo")]
 #[update]
 async fn set_mock_balance(amount: u64) -> Result_ {
-    let caller = caller();
-    let current_time = time();
+    let caller = msg_caller();
+
+    if !STATE.with(|state| state.borrow().config.features.mock_balance) {
+        return Result_::err(DeadManError::FeatureDisabled {
+            feature: "Mock balances".to_string(),
+        });
+    }
     
     // Check if user is registered
     if !STATE.with(|state| {
         let s = state.borrow();
         s.users.contains_key(&caller)
     }) {
-        return Result_::err("User not registered. Please register first.".to_string());
+        return Result_::err(DeadManError::NotRegistered);
     }
 
-    STATE.with(|state| {
-        let mut s = state.borrow_mut();
-        if let Some(account) = s.users.get_mut(&caller) {
-            let previous_balance = account.balance;
-            // Add to existing balance instead of replacing
-            account.balance = previous_balance.saturating_add(amount);
-            let new_balance = account.balance;
-            
-            // Only log if amount was actually added
-            if amount > 0 {
-                account.transaction_history.push(TransactionLog {
-                    timestamp: current_time,
-                    transaction_type: "mock_deposit".to_string(),
-                    amount: Some(amount),
-                    details: format!("Mock balance top-up: +{} ckBTC (total: {} ckBTC, demo mode)", 
-                        amount as f64 / 100_000_000.0, 
-                        new_balance as f64 / 100_000_000.0),
-                });
-                
-                if account.transaction_history.len() > 100 {
-                    account.transaction_history.remove(0);
-                }
+    if is_allowance_mode(&caller) {
+        return Result_::err(DeadManError::WrongCustodyMode {
+            mode: CustodyMode::Allowance,
+        });
+    }
+
+    let ledger = STATE.with(|state| state.borrow().config.ckbtc_ledger);
+    let minted = match Call::unbounded_wait(ledger, "mint")
+        .with_args(&(deposit_account(&caller), Nat::from(amount)))
+        .await
+    {
+        Ok(response) => match response.candid::<Result<Nat, String>>() {
+            Ok(result) => result.map_err(|e| DeadManError::internal(format!("Mint refused: {}", e))),
+            Err(e) => Err(LedgerError::Decode(e.to_string()).into()),
+        },
+        // Only the mock ledger has a `mint` method
+        Err(e) => Err(LedgerError::from_call_failed(e).into()),
+    };
+    if let Err(e) = minted {
+        ic_cdk::println!("Mock mint for {} failed: {}", caller, e);
+        return Result_::err(e);
+    }
+
+    ic_cdk::println!("Minted {} mock ckBTC to the deposit account of {}", amount, caller);
+    sync_balance().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::future::Future;
+    use std::pin::{pin, Pin};
+    use std::task::{Context, Poll, Waker};
+
+    /// Stand-in for the ckBTC ledger. Transfers wait while `hold` is set, like a call
+    /// suspended at its await, and execute once it is cleared.
+    pub(crate) mod ledger {
+        use super::super::*;
+        use std::task::Poll;
+
+        #[derive(Default)]
+        pub struct TestLedger {
+            pub fee: u64,
+            pub hold: bool,
+            pub transfers: Vec<(Option<Subaccount>, Account, u64)>,
+        }
+
+        thread_local! {
+            pub static LEDGER: RefCell<TestLedger> = RefCell::default();
+        }
+
+        pub async fn ledger_fee(_ledger: Principal) -> Result<u64, LedgerError> {
+            Ok(LEDGER.with(|l| l.borrow().fee))
+        }
+
+        pub async fn transfer_ckbtc(
+            _ledger: Principal,
+            from_subaccount: Option<Subaccount>,
+            to: Account,
+            amount: u64,
+            _fee: u64,
+            _created_at_time: Timestamp,
+            _memo: Vec<u8>,
+        ) -> Result<u64, LedgerError> {
+            std::future::poll_fn(|_| match LEDGER.with(|l| l.borrow().hold) {
+                true => Poll::Pending,
+                false => Poll::Ready(()),
+            })
+            .await;
+            LEDGER.with(|l| {
+                let mut l = l.borrow_mut();
+                l.transfers.push((from_subaccount, to, amount));
+                Ok(l.transfers.len() as u64 - 1)
+            })
+        }
+    }
+
+    const OWNER: Principal = Principal::from_slice(&[1]);
+    const HEIR: Principal = Principal::from_slice(&[2]);
+
+    fn account(balance: u64) -> UserAccount {
+        UserAccount {
+            principal: OWNER,
+            last_heartbeat: Timestamp::from_nanos(0),
+            timeout_duration_seconds: DurationSecs::from_secs(3_600),
+            beneficiary: HEIR,
+            beneficiaries: vec![Beneficiary {
+                principal: HEIR,
+                percentage: 100,
+                subaccount: None,
+            }],
+            balance,
+            contestation_period_seconds: DEFAULT_CONTESTATION_PERIOD,
+            timeout_detected_at: None,
+            trusted_parties: vec![],
+            custody_mode: CustodyMode::Custodial,
+            allowance: None,
+            payout: None,
+            balance_drift: None,
+            last_deposit_block: None,
+            legacy_balance: None,
+            deposit_scan: None,
+            pending_withdrawal: None,
+        }
+    }
+
+    fn target(id: u8, percentage: u8) -> (Account, u8) {
+        (
+            Account {
+                owner: Principal::from_slice(&[id]),
+                subaccount: None,
+            },
+            percentage,
+        )
+    }
+
+    fn total_sent(plan: &DistributionPlan) -> u64 {
+        plan.transfers.iter().map(|t| t.amount + t.fee).sum()
+    }
+
+    #[test]
+    fn distribution_uses_up_the_balance_including_fees() {
+        for (balance, fee) in [(1_000_003, 10), (10_000, 0), (12_345_678, 2_000)] {
+            let plan = plan_distribution(balance, fee, &[target(1, 50), target(2, 30), target(3, 20)]).unwrap();
+            assert_eq!(plan.total, balance);
+            assert_eq!(plan.transfers.len(), 3);
+            assert_eq!(total_sent(&plan), balance);
+            assert!(plan.transfers.iter().all(|t| t.fee == fee));
+        }
+    }
+
+    #[test]
+    fn rounding_remainder_goes_to_the_primary_beneficiary() {
+        // 101 to distribute after fees: 33.33 / 33.33 / 34.34
+        let plan = plan_distribution(131, 10, &[target(1, 33), target(2, 33), target(3, 34)]).unwrap();
+        let amounts: Vec<u64> = plan.transfers.iter().map(|t| t.amount).collect();
+        assert_eq!(amounts, vec![34, 33, 34]);
+        assert_eq!(plan.transfers[0].to, target(1, 0).0);
+        assert_eq!(total_sent(&plan), 131);
+    }
+
+    #[test]
+    fn legs_not_above_the_fee_are_dropped_and_their_share_redistributed() {
+        // 1% of 970 is 9, below the fee of 10
+        let plan = plan_distribution(1_000, 10, &[target(1, 89), target(2, 10), target(3, 1)]).unwrap();
+        assert_eq!(plan.transfers.len(), 2);
+        assert_eq!(plan.transfers[1].to, target(2, 0).0);
+        // 980 after two fees, split 89:10
+        assert_eq!(plan.transfers[1].amount, 980 * 10 / 99);
+        assert_eq!(total_sent(&plan), 1_000);
+
+        // A share of exactly the fee is dropped too, leaving everything to the primary
+        let plan = plan_distribution(1_020, 10, &[target(1, 99), target(2, 1)]).unwrap();
+        assert_eq!(plan.transfers.len(), 1);
+        assert_eq!(plan.transfers[0].amount, 1_010);
+    }
+
+    #[test]
+    fn too_little_for_every_fee_falls_back_to_the_primary() {
+        let plan = plan_distribution(25, 10, &[target(1, 50), target(2, 25), target(3, 25)]).unwrap();
+        assert_eq!(plan.transfers.len(), 1);
+        assert_eq!(plan.transfers[0].amount, 15);
+
+        assert!(plan_distribution(10, 10, &[target(1, 100)]).is_err());
+    }
+
+    #[test]
+    fn bad_percentage_sums_are_rejected() {
+        assert!(plan_distribution(1_000, 10, &[target(1, 60), target(2, 30)]).is_err());
+        assert!(plan_distribution(1_000, 10, &[target(1, 70), target(2, 40)]).is_err());
+        assert!(plan_distribution(1_000, 10, &[target(1, 0)]).is_err());
+        assert!(plan_distribution(1_000, 10, &[]).is_err());
+    }
+
+    #[test]
+    fn second_acquire_fails_while_the_first_guard_lives() {
+        let _guard = AccountGuard::acquire(OWNER).unwrap();
+        assert!(matches!(AccountGuard::acquire(OWNER), Err(DeadManError::Busy)));
+        // Other accounts are not affected
+        assert!(AccountGuard::acquire(HEIR).is_ok());
+    }
+
+    #[test]
+    fn dropping_the_guard_releases_the_lock() {
+        let guard = AccountGuard::acquire(OWNER).unwrap();
+        drop(guard);
+        assert!(AccountGuard::acquire(OWNER).is_ok());
+    }
+
+    #[test]
+    fn lock_set_tracks_each_principal_once() {
+        let mut locks = BTreeSet::new();
+        assert!(try_lock(&mut locks, OWNER));
+        assert!(!try_lock(&mut locks, OWNER));
+        unlock(&mut locks, &OWNER);
+        assert!(try_lock(&mut locks, OWNER));
+    }
+
+    #[test]
+    fn refused_withdrawal_restores_the_balance_exactly_once() {
+        let mut account = account(1_500);
+        let reservation = account.reserve_withdrawal(1_010).unwrap();
+        assert_eq!(account.balance, 490);
+
+        // A deposit credited while the transfer was in flight is kept
+        account.balance += 100;
+        account.release_withdrawal(reservation);
+        assert_eq!(account.balance, 1_600);
+    }
+
+    #[test]
+    fn concurrent_reservations_are_released_independently() {
+        let mut account = account(1_000);
+        let first = account.reserve_withdrawal(300).unwrap();
+        let second = account.reserve_withdrawal(200).unwrap();
+        assert_eq!(account.balance, 500);
+        account.release_withdrawal(second);
+        assert_eq!(account.balance, 700);
+        account.release_withdrawal(first);
+        assert_eq!(account.balance, 1_000);
+    }
+
+    #[test]
+    fn withdrawal_is_not_reserved_without_funds_or_during_a_payout() {
+        let mut short = account(1_000);
+        assert!(matches!(
+            short.reserve_withdrawal(1_001),
+            Err(DeadManError::InsufficientBalance {
+                available: 1_000,
+                required: 1_001
+            })
+        ));
+        assert_eq!(short.balance, 1_000);
+
+        let mut paying = account(1_000);
+        paying.payout = Some(PayoutPlan {
+            created_at: Timestamp::from_nanos(0),
+            custody_mode: CustodyMode::Custodial,
+            legs: vec![],
+        });
+        assert!(matches!(paying.reserve_withdrawal(10), Err(DeadManError::PayoutInProgress)));
+        assert_eq!(paying.balance, 1_000);
+    }
+
+    fn pending_withdrawal(amount: u64, fee: u64) -> PendingWithdrawal {
+        PendingWithdrawal {
+            amount,
+            fee,
+            to: Account {
+                owner: HEIR,
+                subaccount: None,
+            },
+            created_at_time: Timestamp::from_nanos(5),
+            memo: b"DEADMANW".to_vec(),
+        }
+    }
+
+    #[test]
+    fn no_new_withdrawal_is_reserved_while_one_is_unsettled() {
+        let mut unsettled = account(890);
+        unsettled.pending_withdrawal = Some(pending_withdrawal(100, 10));
+        assert!(matches!(unsettled.reserve_withdrawal(10), Err(DeadManError::Conflict { .. })));
+        assert_eq!(unsettled.balance, 890);
+    }
+
+    #[test]
+    fn cancelled_pending_withdrawal_restores_the_balance_exactly_once() {
+        let mut unsettled = account(890);
+        unsettled.pending_withdrawal = Some(pending_withdrawal(100, 10));
+
+        assert_eq!(unsettled.cancel_pending_withdrawal(), Some(pending_withdrawal(100, 10)));
+        assert_eq!(unsettled.balance, 1_000);
+        assert_eq!(unsettled.cancel_pending_withdrawal(), None);
+        assert_eq!(unsettled.balance, 1_000);
+    }
+
+    #[test]
+    fn only_insufficient_funds_rules_out_an_earlier_transfer() {
+        let short = LedgerError::Transfer(TransferError::InsufficientFunds {
+            balance: Nat::from(0u64),
+        });
+        assert!(short.rules_out_duplicate());
+        let short_allowance = LedgerError::TransferFrom(TransferFromError::InsufficientFunds {
+            balance: Nat::from(0u64),
+        });
+        assert!(short_allowance.rules_out_duplicate());
+
+        let too_old = LedgerError::Transfer(TransferError::TooOld);
+        assert!(!too_old.rules_out_duplicate());
+        let bad_fee = LedgerError::Transfer(TransferError::BadFee {
+            expected_fee: Nat::from(10u64),
+        });
+        assert!(!bad_fee.rules_out_duplicate());
+    }
+
+    fn poll<F: Future>(future: Pin<&mut F>) -> Poll<F::Output> {
+        future.poll(&mut Context::from_waker(Waker::noop()))
+    }
+
+    fn release_ledger() {
+        ledger::LEDGER.with(|l| l.borrow_mut().hold = false);
+    }
+
+    fn ledger_transfers() -> usize {
+        ledger::LEDGER.with(|l| l.borrow().transfers.len())
+    }
+
+    fn tracked(owner: &Principal) -> UserAccount {
+        STATE.with(|state| state.borrow().users.get(owner).cloned().unwrap())
+    }
+
+    /// OWNER holds 1_000 and is past the contestation period, with the ledger holding transfers
+    fn payable_account() -> UserAccount {
+        let mut account = account(1_000);
+        account.timeout_detected_at = Some(Timestamp::from_nanos(0));
+        STATE.with(|state| state.borrow_mut().users.insert(OWNER, account.clone()));
+        timestamp::set_test_time(Timestamp::from_nanos(0).saturating_add(DurationSecs::from_days(30)));
+        ledger::LEDGER.with(|l| {
+            *l.borrow_mut() = ledger::TestLedger {
+                fee: 10,
+                hold: true,
+                transfers: vec![],
             }
-            
-            ic_cdk::println!("Mock balance top-up for {}: +{} ckBTC (was: {}, now: {})", 
-                caller, amount, previous_balance, new_balance);
-            Result_::ok(format!("Mock balance top-up: +{} ckBTC (total: {} ckBTC)", 
-                amount as f64 / 100_000_000.0,
-                new_balance as f64 / 100_000_000.0))
-        } else {
-            Result_::err("User account not found".to_string())
+        });
+        account
+    }
+
+    fn heir_account() -> Account {
+        Account {
+            owner: HEIR,
+            subaccount: None,
         }
-    })
+    }
+
+    #[test]
+    fn payout_is_deferred_while_a_withdrawal_awaits_the_ledger() {
+        let account = payable_account();
+
+        let mut withdrawal = pin!(withdraw_from(OWNER, 500, heir_account()));
+        assert!(poll(withdrawal.as_mut()).is_pending());
+        assert_eq!(tracked(&OWNER).balance, 490);
+
+        // The timer's payout runs while the withdrawal is suspended at its transfer
+        match poll(pin!(check_and_transfer(&account, 10))) {
+            Poll::Ready(Ok(result)) => {
+                assert!(!result.success);
+                assert_eq!(result.transfers_attempted, 0);
+            }
+            other => panic!("payout should have been deferred, got {:?}", other.map(|r| r.err())),
+        }
+        assert!(tracked(&OWNER).payout.is_none());
+
+        release_ledger();
+        assert!(matches!(poll(withdrawal.as_mut()), Poll::Ready(Result_::ok(_))));
+        let after = tracked(&OWNER);
+        assert_eq!(after.balance, 490);
+        assert!(after.pending_withdrawal.is_none());
+        assert_eq!(ledger_transfers(), 1);
+    }
+
+    #[test]
+    fn withdrawal_is_rejected_while_a_payout_awaits_the_ledger() {
+        let account = payable_account();
+
+        let mut payout = pin!(check_and_transfer(&account, 10));
+        assert!(poll(payout.as_mut()).is_pending());
+        assert!(tracked(&OWNER).payout.is_some());
+
+        // The owner tries to withdraw while the payout leg is suspended at its transfer
+        assert!(matches!(
+            poll(pin!(withdraw_from(OWNER, 500, heir_account()))),
+            Poll::Ready(Result_::err(DeadManError::Busy))
+        ));
+        assert_eq!(tracked(&OWNER).balance, 1_000);
+
+        release_ledger();
+        match poll(payout.as_mut()) {
+            Poll::Ready(Ok(result)) => assert!(result.success),
+            other => panic!("payout should have completed, got {:?}", other.map(|r| r.err())),
+        }
+        // Paid once: 990 to the heir plus the fee
+        assert_eq!(tracked(&OWNER).balance, 0);
+        assert_eq!(ledger::LEDGER.with(|l| l.borrow().transfers.clone()), vec![(
+            Some(deposit_subaccount(&OWNER)),
+            heir_account(),
+            990
+        )]);
+    }
 }
@@ -0,0 +1,1148 @@
+//! Upgrade path for the state saved in stable memory.
+//!
+//! Every layout that was ever written by `pre_upgrade` is frozen here under its
+//! `StateVersion`. On `post_upgrade` the saved bytes are decoded with the layout
+//! matching their version tag and then walked forward one migration at a time
+//! until they reach the current `DeadManSwitchState`.
+//!
+//! When changing `DeadManSwitchState` or anything it contains in a way Candid
+//! cannot decode from the previous layout:
+//! 1. copy the previous layout into a new `vN` module below, together with every
+//!    type it contains as it was at that version, and convert them in the migration,
+//! 2. add a `StateVersion` variant and bump `StateVersion::CURRENT`,
+//! 3. add a `migrate_vN_to_vM` step and wire it into `decode_state`.
+
+use crate::{
+    history, DeadManSwitchState, DurationSecs, LegacyBalance, StateVersion, Timestamp, UserAccount,
+    DEFAULT_CONTESTATION_PERIOD,
+};
+use std::collections::BTreeMap;
+
+/// Layout written by the first stable-memory build (no version tag inside the state).
+/// Beneficiaries and history entries are unchanged until V3 and V7.
+pub mod v1 {
+    use super::{v6::TransactionLog, v7::Beneficiary};
+    use candid::{CandidType, Deserialize, Principal};
+    use std::collections::HashMap;
+
+    #[derive(CandidType, Deserialize, Clone, Debug)]
+    pub struct UserAccount {
+        pub user_principal: Principal,
+        pub last_heartbeat: u64,
+        pub timeout_duration_seconds: u64,
+        pub beneficiary: Principal,
+        pub beneficiaries: Vec<Beneficiary>,
+        pub balance: u64,
+        pub transaction_history: Vec<TransactionLog>,
+        pub contestation_period_seconds: u64,
+        pub timeout_detected_at: Option<u64>,
+        pub trusted_parties: Vec<Principal>,
+    }
+
+    #[derive(CandidType, Deserialize, Clone, Debug)]
+    pub struct DeadManSwitchState {
+        pub users: HashMap<Principal, UserAccount>,
+        pub ckbtc_ledger: Principal,
+    }
+}
+
+/// Layout with the version tag, before custody modes existed.
+pub mod v2 {
+    use super::{v6::TransactionLog, v7::Beneficiary};
+    use crate::StateVersion;
+    use candid::{CandidType, Deserialize, Principal};
+    use std::collections::HashMap;
+
+    #[derive(CandidType, Deserialize, Clone, Debug)]
+    pub struct UserAccount {
+        pub user_principal: Principal,
+        pub last_heartbeat: u64,
+        pub timeout_duration_seconds: u64,
+        pub beneficiary: Principal,
+        pub beneficiaries: Vec<Beneficiary>,
+        pub balance: u64,
+        pub transaction_history: Vec<TransactionLog>,
+        pub contestation_period_seconds: u64,
+        pub timeout_detected_at: Option<u64>,
+        pub trusted_parties: Vec<Principal>,
+    }
+
+    #[derive(CandidType, Deserialize, Clone, Debug)]
+    pub struct DeadManSwitchState {
+        pub version: StateVersion,
+        pub users: HashMap<Principal, UserAccount>,
+        pub ckbtc_ledger: Principal,
+    }
+}
+
+/// Layout before the deployment config, when only the ledger was configurable.
+/// Accounts are unchanged from V3 to V6.
+pub mod v3 {
+    use super::v6::UserAccount;
+    use crate::StateVersion;
+    use candid::{CandidType, Deserialize, Principal};
+    use std::collections::HashMap;
+
+    #[derive(CandidType, Deserialize, Clone, Debug)]
+    pub struct DeadManSwitchState {
+        pub version: StateVersion,
+        pub users: HashMap<Principal, UserAccount>,
+        pub ckbtc_ledger: Principal,
+    }
+}
+
+/// Layout before roles and the checker pause switch. The config is unchanged until V7.
+pub mod v4 {
+    use super::{v6::UserAccount, v7::CanisterConfig};
+    use crate::StateVersion;
+    use candid::{CandidType, Deserialize, Principal};
+    use std::collections::HashMap;
+
+    #[derive(CandidType, Deserialize, Clone, Debug)]
+    pub struct DeadManSwitchState {
+        pub version: StateVersion,
+        pub users: HashMap<Principal, UserAccount>,
+        pub config: CanisterConfig,
+    }
+}
+
+/// Layout before per-operation pauses, with a single switch for the checker
+pub mod v5 {
+    use super::{
+        v6::UserAccount,
+        v7::{CanisterConfig, Role},
+    };
+    use crate::StateVersion;
+    use candid::{CandidType, Deserialize, Principal};
+    use std::collections::BTreeMap;
+
+    #[derive(CandidType, Deserialize, Clone, Debug)]
+    pub struct DeadManSwitchState {
+        pub version: StateVersion,
+        pub users: BTreeMap<Principal, UserAccount>,
+        pub config: CanisterConfig,
+        pub roles: BTreeMap<Principal, Role>,
+        pub checker_paused: bool,
+    }
+}
+
+/// Layout before typed history events, when each entry had a free-form type and text
+pub mod v6 {
+    use super::v7::{AllowanceStatus, Beneficiary, CanisterConfig, CustodyMode, PauseState, PayoutPlan, Role};
+    use crate::{DurationSecs, StateVersion, Timestamp};
+    use candid::{CandidType, Deserialize, Principal};
+    use std::collections::BTreeMap;
+
+    #[derive(CandidType, Deserialize, Clone, Debug)]
+    pub struct TransactionLog {
+        pub timestamp: Timestamp,
+        pub transaction_type: String,
+        pub amount: Option<u64>,
+        pub details: String,
+    }
+
+    #[derive(CandidType, Deserialize, Clone, Debug)]
+    pub struct UserAccount {
+        #[serde(rename = "user_principal")]
+        pub principal: Principal,
+        pub last_heartbeat: Timestamp,
+        pub timeout_duration_seconds: DurationSecs,
+        pub beneficiary: Principal,
+        pub beneficiaries: Vec<Beneficiary>,
+        pub balance: u64,
+        pub transaction_history: Vec<TransactionLog>,
+        pub contestation_period_seconds: DurationSecs,
+        pub timeout_detected_at: Option<Timestamp>,
+        pub trusted_parties: Vec<Principal>,
+        pub custody_mode: CustodyMode,
+        pub allowance: Option<AllowanceStatus>,
+        pub payout: Option<PayoutPlan>,
+    }
+
+    #[derive(CandidType, Deserialize, Clone, Debug)]
+    pub struct DeadManSwitchState {
+        pub version: StateVersion,
+        pub users: BTreeMap<Principal, UserAccount>,
+        pub config: CanisterConfig,
+        pub roles: BTreeMap<Principal, Role>,
+        pub pauses: PauseState,
+    }
+}
+
+/// Layout before the history moved to its own stable map, when each account
+/// carried its latest 100 entries. Also holds the last layout of every type that
+/// earlier versions share with it, and their conversions to the current types.
+/// `Timestamp`, `DurationSecs` and ledger `Account`s are plain Candid values and
+/// are not copied.
+pub mod v7 {
+    use crate::{DurationSecs, StateVersion, Timestamp, DEFAULT_CONTESTATION_PERIOD};
+    use candid::{CandidType, Deserialize, Principal};
+    use icrc_ledger_types::icrc1::account::Account;
+    use std::collections::BTreeMap;
+
+    #[derive(CandidType, Deserialize, Clone, Debug)]
+    pub struct Beneficiary {
+        #[serde(rename = "beneficiary_principal")]
+        pub principal: Principal,
+        pub percentage: u8,
+        pub subaccount: Option<Vec<u8>>,
+    }
+
+    #[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum CustodyMode {
+        Custodial,
+        Allowance,
+    }
+
+    #[derive(CandidType, Deserialize, Clone, Debug)]
+    pub struct AllowanceStatus {
+        pub allowance: u64,
+        pub expires_at: Option<Timestamp>,
+        pub owner_balance: u64,
+        pub checked_at: Timestamp,
+    }
+
+    #[derive(CandidType, Deserialize, Clone, Debug)]
+    pub struct PayoutPlan {
+        pub created_at: Timestamp,
+        pub custody_mode: CustodyMode,
+        pub legs: Vec<PayoutLeg>,
+    }
+
+    #[derive(CandidType, Deserialize, Clone, Debug)]
+    pub struct PayoutLeg {
+        pub to: Account,
+        pub amount: u64,
+        pub fee: u64,
+        pub status: PayoutLegStatus,
+        pub attempts: u32,
+        pub created_at_time: Option<Timestamp>,
+        pub memo: Option<Vec<u8>>,
+    }
+
+    #[derive(CandidType, Deserialize, Clone, Debug)]
+    pub enum PayoutLegStatus {
+        Pending,
+        InFlight { started_at: Timestamp },
+        Paid { block_index: u64 },
+        Failed { error: String, next_retry_at: Timestamp },
+        OutcomeUnknown { error: String, next_retry_at: Timestamp },
+    }
+
+    #[derive(CandidType, Deserialize, Clone, Debug)]
+    pub struct TransactionLog {
+        pub timestamp: Timestamp,
+        pub event: AccountEvent,
+    }
+
+    #[derive(CandidType, Deserialize, Clone, Debug)]
+    pub enum AccountEvent {
+        Registered { timeout: DurationSecs, beneficiary: Account },
+        Heartbeat { next_due: Timestamp },
+        BalanceSynced { previous: u64, current: u64 },
+        Withdrawal { amount: u64, fee: u64, to: Account, block_index: u64 },
+        TimeoutDetected { contestation_period: DurationSecs },
+        TimeoutCancelled { by: Principal },
+        PayoutStarted { amount: u64, transfers: u32 },
+        PayoutTransfer { amount: u64, fee: u64, to: Account, block_index: u64 },
+        PayoutTransferFailed { amount: u64, to: Account, attempt: u32, error: String },
+        TimeoutChanged { old: DurationSecs, new: DurationSecs },
+        ContestationPeriodChanged { old: DurationSecs, new: DurationSecs },
+        BeneficiariesChanged { old: Vec<Beneficiary>, new: Vec<Beneficiary> },
+        TrustedPartyAdded { principal: Principal },
+        TrustedPartyRemoved { principal: Principal },
+        CustodyModeChanged { old: CustodyMode, new: CustodyMode },
+        Legacy { transaction_type: String, amount: Option<u64>, details: String },
+    }
+
+    #[derive(CandidType, Deserialize, Clone, Debug)]
+    pub struct FeatureFlags {
+        pub allowance_mode: bool,
+        pub trusted_party_cancellation: bool,
+        pub mock_balance: bool,
+    }
+
+    #[derive(CandidType, Deserialize, Clone, Debug)]
+    pub struct CanisterConfig {
+        pub ckbtc_ledger: Principal,
+        pub ckbtc_minter: Option<Principal>,
+        pub ckbtc_index: Option<Principal>,
+        pub check_interval: DurationSecs,
+        pub default_contestation_period: DurationSecs,
+        pub features: FeatureFlags,
+    }
+
+    impl CanisterConfig {
+        /// The defaults of the first deployment config
+        pub fn with_ledger(ckbtc_ledger: Principal) -> Self {
+            Self {
+                ckbtc_ledger,
+                ckbtc_minter: None,
+                ckbtc_index: None,
+                check_interval: DurationSecs::from_minutes(1),
+                default_contestation_period: DEFAULT_CONTESTATION_PERIOD,
+                features: FeatureFlags {
+                    allowance_mode: true,
+                    trusted_party_cancellation: true,
+                    mock_balance: false,
+                },
+            }
+        }
+    }
+
+    #[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum Role {
+        Auditor,
+        Operator,
+        Controller,
+    }
+
+    #[derive(CandidType, Deserialize, Clone, Debug)]
+    pub struct ActivePause {
+        pub since: Timestamp,
+        pub by: Principal,
+        pub reason: String,
+    }
+
+    #[derive(CandidType, Deserialize, Clone, Copy, Debug)]
+    pub struct PausePeriod {
+        pub started_at: Timestamp,
+        pub ended_at: Timestamp,
+    }
+
+    #[derive(CandidType, Deserialize, Clone, Debug, Default)]
+    pub struct PauseState {
+        pub payouts: Option<ActivePause>,
+        pub withdrawals: Option<ActivePause>,
+        pub registrations: Option<ActivePause>,
+        pub heartbeats: Option<ActivePause>,
+        pub heartbeat_pauses: Vec<PausePeriod>,
+    }
+
+    #[derive(CandidType, Deserialize, Clone, Debug)]
+    pub struct UserAccount {
+        #[serde(rename = "user_principal")]
+        pub principal: Principal,
+        pub last_heartbeat: Timestamp,
+        pub timeout_duration_seconds: DurationSecs,
+        pub beneficiary: Principal,
+        pub beneficiaries: Vec<Beneficiary>,
+        pub balance: u64,
+        pub transaction_history: Vec<TransactionLog>,
+        pub contestation_period_seconds: DurationSecs,
+        pub timeout_detected_at: Option<Timestamp>,
+        pub trusted_parties: Vec<Principal>,
+        pub custody_mode: CustodyMode,
+        pub allowance: Option<AllowanceStatus>,
+        pub payout: Option<PayoutPlan>,
+    }
+
+    #[derive(CandidType, Deserialize, Clone, Debug)]
+    pub struct DeadManSwitchState {
+        pub version: StateVersion,
+        pub users: BTreeMap<Principal, UserAccount>,
+        pub config: CanisterConfig,
+        pub roles: BTreeMap<Principal, Role>,
+        pub pauses: PauseState,
+    }
+
+    impl From<Beneficiary> for crate::Beneficiary {
+        fn from(old: Beneficiary) -> Self {
+            Self {
+                principal: old.principal,
+                percentage: old.percentage,
+                subaccount: old.subaccount,
+            }
+        }
+    }
+
+    impl From<CustodyMode> for crate::CustodyMode {
+        fn from(old: CustodyMode) -> Self {
+            match old {
+                CustodyMode::Custodial => Self::Custodial,
+                CustodyMode::Allowance => Self::Allowance,
+            }
+        }
+    }
+
+    impl From<AllowanceStatus> for crate::AllowanceStatus {
+        fn from(old: AllowanceStatus) -> Self {
+            Self {
+                allowance: old.allowance,
+                expires_at: old.expires_at,
+                owner_balance: old.owner_balance,
+                checked_at: old.checked_at,
+            }
+        }
+    }
+
+    impl From<PayoutLegStatus> for crate::PayoutLegStatus {
+        fn from(old: PayoutLegStatus) -> Self {
+            match old {
+                PayoutLegStatus::Pending => Self::Pending,
+                PayoutLegStatus::InFlight { started_at } => Self::InFlight { started_at },
+                PayoutLegStatus::Paid { block_index } => Self::Paid { block_index },
+                PayoutLegStatus::Failed { error, next_retry_at } => Self::Failed { error, next_retry_at },
+                PayoutLegStatus::OutcomeUnknown { error, next_retry_at } => {
+                    Self::OutcomeUnknown { error, next_retry_at }
+                }
+            }
+        }
+    }
+
+    impl From<PayoutLeg> for crate::PayoutLeg {
+        fn from(old: PayoutLeg) -> Self {
+            Self {
+                to: old.to,
+                amount: old.amount,
+                fee: old.fee,
+                status: old.status.into(),
+                attempts: old.attempts,
+                created_at_time: old.created_at_time,
+                memo: old.memo,
+            }
+        }
+    }
+
+    impl From<PayoutPlan> for crate::PayoutPlan {
+        fn from(old: PayoutPlan) -> Self {
+            Self {
+                created_at: old.created_at,
+                custody_mode: old.custody_mode.into(),
+                legs: old.legs.into_iter().map(Into::into).collect(),
+            }
+        }
+    }
+
+    impl From<AccountEvent> for crate::AccountEvent {
+        fn from(old: AccountEvent) -> Self {
+            use AccountEvent::*;
+            match old {
+                Registered { timeout, beneficiary } => Self::Registered { timeout, beneficiary },
+                Heartbeat { next_due } => Self::Heartbeat { next_due },
+                BalanceSynced { previous, current } => Self::BalanceSynced { previous, current },
+                Withdrawal {
+                    amount,
+                    fee,
+                    to,
+                    block_index,
+                } => Self::Withdrawal {
+                    amount,
+                    fee,
+                    to,
+                    block_index,
+                },
+                TimeoutDetected { contestation_period } => Self::TimeoutDetected { contestation_period },
+                TimeoutCancelled { by } => Self::TimeoutCancelled { by },
+                PayoutStarted { amount, transfers } => Self::PayoutStarted { amount, transfers },
+                PayoutTransfer {
+                    amount,
+                    fee,
+                    to,
+                    block_index,
+                } => Self::PayoutTransfer {
+                    amount,
+                    fee,
+                    to,
+                    block_index,
+                },
+                PayoutTransferFailed {
+                    amount,
+                    to,
+                    attempt,
+                    error,
+                } => Self::PayoutTransferFailed {
+                    amount,
+                    to,
+                    attempt,
+                    error,
+                },
+                TimeoutChanged { old, new } => Self::TimeoutChanged { old, new },
+                ContestationPeriodChanged { old, new } => Self::ContestationPeriodChanged { old, new },
+                BeneficiariesChanged { old, new } => Self::BeneficiariesChanged {
+                    old: old.into_iter().map(Into::into).collect(),
+                    new: new.into_iter().map(Into::into).collect(),
+                },
+                TrustedPartyAdded { principal } => Self::TrustedPartyAdded { principal },
+                TrustedPartyRemoved { principal } => Self::TrustedPartyRemoved { principal },
+                CustodyModeChanged { old, new } => Self::CustodyModeChanged {
+                    old: old.into(),
+                    new: new.into(),
+                },
+                Legacy {
+                    transaction_type,
+                    amount,
+                    details,
+                } => Self::Legacy {
+                    transaction_type,
+                    amount,
+                    details,
+                },
+            }
+        }
+    }
+
+    impl From<CanisterConfig> for crate::CanisterConfig {
+        fn from(old: CanisterConfig) -> Self {
+            Self {
+                ckbtc_ledger: old.ckbtc_ledger,
+                ckbtc_minter: old.ckbtc_minter,
+                ckbtc_index: old.ckbtc_index,
+                check_interval: old.check_interval,
+                default_contestation_period: old.default_contestation_period,
+                features: crate::FeatureFlags {
+                    allowance_mode: old.features.allowance_mode,
+                    trusted_party_cancellation: old.features.trusted_party_cancellation,
+                    mock_balance: old.features.mock_balance,
+                },
+                history_retention: None,
+                history_max_entries: None,
+            }
+        }
+    }
+
+    impl From<Role> for crate::Role {
+        fn from(old: Role) -> Self {
+            match old {
+                Role::Auditor => Self::Auditor,
+                Role::Operator => Self::Operator,
+                Role::Controller => Self::Controller,
+            }
+        }
+    }
+
+    impl From<ActivePause> for crate::pause::ActivePause {
+        fn from(old: ActivePause) -> Self {
+            Self {
+                since: old.since,
+                by: old.by,
+                reason: old.reason,
+            }
+        }
+    }
+
+    impl From<PauseState> for crate::PauseState {
+        fn from(old: PauseState) -> Self {
+            Self {
+                payouts: old.payouts.map(Into::into),
+                withdrawals: old.withdrawals.map(Into::into),
+                registrations: old.registrations.map(Into::into),
+                heartbeats: old.heartbeats.map(Into::into),
+                heartbeat_pauses: old
+                    .heartbeat_pauses
+                    .into_iter()
+                    .map(|period| crate::pause::PausePeriod {
+                        started_at: period.started_at,
+                        ended_at: period.ended_at,
+                    })
+                    .collect(),
+            }
+        }
+    }
+}
+
+/// Decode a state saved under `version` and migrate it to the current layout.
+pub fn decode_state(version: u32, bytes: &[u8]) -> Result<DeadManSwitchState, String> {
+    match StateVersion::from_u32(version) {
+        Some(StateVersion::V1) => decode::<v1::DeadManSwitchState>(bytes)
+            .map(migrate_v1_to_v2)
+            .map(migrate_v2_to_v3)
+            .map(migrate_v3_to_v4)
+            .map(migrate_v4_to_v5)
+            .map(migrate_v5_to_v6)
+            .map(migrate_v6_to_v7)
+            .map(migrate_v7_to_v8)
+            .map(move_legacy_balances),
+        Some(StateVersion::V2) => decode::<v2::DeadManSwitchState>(bytes)
+            .map(migrate_v2_to_v3)
+            .map(migrate_v3_to_v4)
+            .map(migrate_v4_to_v5)
+            .map(migrate_v5_to_v6)
+            .map(migrate_v6_to_v7)
+            .map(migrate_v7_to_v8),
+        Some(StateVersion::V3) => decode::<v3::DeadManSwitchState>(bytes)
+            .map(migrate_v3_to_v4)
+            .map(migrate_v4_to_v5)
+            .map(migrate_v5_to_v6)
+            .map(migrate_v6_to_v7)
+            .map(migrate_v7_to_v8),
+        Some(StateVersion::V4) => decode::<v4::DeadManSwitchState>(bytes)
+            .map(migrate_v4_to_v5)
+            .map(migrate_v5_to_v6)
+            .map(migrate_v6_to_v7)
+            .map(migrate_v7_to_v8),
+        Some(StateVersion::V5) => decode::<v5::DeadManSwitchState>(bytes)
+            .map(migrate_v5_to_v6)
+            .map(migrate_v6_to_v7)
+            .map(migrate_v7_to_v8),
+        Some(StateVersion::V6) => decode::<v6::DeadManSwitchState>(bytes)
+            .map(migrate_v6_to_v7)
+            .map(migrate_v7_to_v8),
+        Some(StateVersion::V7) => decode::<v7::DeadManSwitchState>(bytes).map(migrate_v7_to_v8),
+        Some(StateVersion::V8) => decode::<DeadManSwitchState>(bytes),
+        None => Err(format!(
+            "Unsupported state schema version {} (current: {})",
+            version,
+            StateVersion::CURRENT.as_u32()
+        )),
+    }
+}
+
+/// V1 builds kept every balance in the canister's default account, where the balance
+/// checks of later versions do not look. Set the balances aside until `legacy` has moved
+/// them into the deposit subaccounts. V2 states saved before deposit subaccounts existed
+/// cannot be told apart from later ones; reconciliation reports their drift instead.
+fn move_legacy_balances(mut state: DeadManSwitchState) -> DeadManSwitchState {
+    for account in state.users.values_mut() {
+        if account.balance > 0 {
+            account.legacy_balance = Some(LegacyBalance {
+                amount: account.balance,
+                share: None,
+                unsettled: None,
+            });
+            account.balance = 0;
+        }
+    }
+    state
+}
+
+fn decode<T: candid::CandidType + serde::de::DeserializeOwned>(bytes: &[u8]) -> Result<T, String> {
+    candid::decode_one(bytes).map_err(|e| format!("Failed to decode state: {:?}", e))
+}
+
+/// V1 -> V2: adds the version tag and back-fills accounts created before multiple
+/// beneficiaries and the contestation period existed.
+pub fn migrate_v1_to_v2(old: v1::DeadManSwitchState) -> v2::DeadManSwitchState {
+    let users = old
+        .users
+        .into_iter()
+        .map(|(principal, account)| (principal, migrate_account_v1_to_v2(account)))
+        .collect();
+
+    v2::DeadManSwitchState {
+        version: StateVersion::V2,
+        users,
+        ckbtc_ledger: old.ckbtc_ledger,
+    }
+}
+
+fn migrate_account_v1_to_v2(old: v1::UserAccount) -> v2::UserAccount {
+    let beneficiaries = if old.beneficiaries.is_empty() {
+        vec![v7::Beneficiary {
+            principal: old.beneficiary,
+            percentage: 100,
+            subaccount: None,
+        }]
+    } else {
+        old.beneficiaries
+    };
+
+    let contestation_period_seconds = if old.contestation_period_seconds == 0 {
+        DEFAULT_CONTESTATION_PERIOD.as_secs()
+    } else {
+        old.contestation_period_seconds
+    };
+
+    v2::UserAccount {
+        user_principal: old.user_principal,
+        last_heartbeat: old.last_heartbeat,
+        timeout_duration_seconds: old.timeout_duration_seconds,
+        beneficiary: old.beneficiary,
+        beneficiaries,
+        balance: old.balance,
+        transaction_history: old.transaction_history,
+        contestation_period_seconds,
+        timeout_detected_at: old.timeout_detected_at,
+        trusted_parties: old.trusted_parties,
+    }
+}
+
+/// V2 -> V3: every existing account keeps its funds in the canister.
+pub fn migrate_v2_to_v3(old: v2::DeadManSwitchState) -> v3::DeadManSwitchState {
+    let users = old
+        .users
+        .into_iter()
+        .map(|(principal, account)| {
+            let account = v6::UserAccount {
+                principal: account.user_principal,
+                last_heartbeat: Timestamp::from_nanos(account.last_heartbeat),
+                timeout_duration_seconds: DurationSecs::from_secs(account.timeout_duration_seconds),
+                beneficiary: account.beneficiary,
+                beneficiaries: account.beneficiaries,
+                balance: account.balance,
+                transaction_history: account.transaction_history,
+                contestation_period_seconds: DurationSecs::from_secs(account.contestation_period_seconds),
+                timeout_detected_at: account.timeout_detected_at.map(Timestamp::from_nanos),
+                trusted_parties: account.trusted_parties,
+                custody_mode: v7::CustodyMode::Custodial,
+                allowance: None,
+                payout: None,
+            };
+            (principal, account)
+        })
+        .collect();
+
+    v3::DeadManSwitchState {
+        version: StateVersion::V3,
+        users,
+        ckbtc_ledger: old.ckbtc_ledger,
+    }
+}
+
+/// V3 -> V4: the ledger moves into the deployment config; everything else starts
+/// from the defaults and can be changed with upgrade arguments.
+pub fn migrate_v3_to_v4(old: v3::DeadManSwitchState) -> v4::DeadManSwitchState {
+    v4::DeadManSwitchState {
+        version: StateVersion::V4,
+        users: old.users,
+        config: v7::CanisterConfig::with_ledger(old.ckbtc_ledger),
+    }
+}
+
+/// V4 -> V5: no operators or auditors yet, only the controllers can administer.
+pub fn migrate_v4_to_v5(old: v4::DeadManSwitchState) -> v5::DeadManSwitchState {
+    v5::DeadManSwitchState {
+        version: StateVersion::V5,
+        users: old.users.into_iter().collect(),
+        config: old.config,
+        roles: BTreeMap::new(),
+        checker_paused: false,
+    }
+}
+
+/// V5 -> V6: a paused checker becomes paused payouts, which stops the same transfers.
+/// The canister itself is recorded as having paused them.
+pub fn migrate_v5_to_v6(old: v5::DeadManSwitchState) -> v6::DeadManSwitchState {
+    let mut pauses = v7::PauseState::default();
+    if old.checker_paused {
+        pauses.payouts = Some(v7::ActivePause {
+            since: Timestamp::now(),
+            by: ic_cdk::api::canister_self(),
+            reason: "Timeout checker was paused before the upgrade".to_string(),
+        });
+    }
+
+    v6::DeadManSwitchState {
+        version: StateVersion::V6,
+        users: old.users,
+        config: old.config,
+        roles: old.roles,
+        pauses,
+    }
+}
+
+/// V6 -> V7: existing history entries keep their type and text as `AccountEvent::Legacy`;
+/// their details were free-form sentences and cannot be turned into typed fields reliably.
+pub fn migrate_v6_to_v7(old: v6::DeadManSwitchState) -> v7::DeadManSwitchState {
+    let users = old
+        .users
+        .into_iter()
+        .map(|(principal, account)| {
+            let transaction_history = account
+                .transaction_history
+                .into_iter()
+                .map(|log| v7::TransactionLog {
+                    timestamp: log.timestamp,
+                    event: v7::AccountEvent::Legacy {
+                        transaction_type: log.transaction_type,
+                        amount: log.amount,
+                        details: log.details,
+                    },
+                })
+                .collect();
+            let account = v7::UserAccount {
+                principal: account.principal,
+                last_heartbeat: account.last_heartbeat,
+                timeout_duration_seconds: account.timeout_duration_seconds,
+                beneficiary: account.beneficiary,
+                beneficiaries: account.beneficiaries,
+                balance: account.balance,
+                transaction_history,
+                contestation_period_seconds: account.contestation_period_seconds,
+                timeout_detected_at: account.timeout_detected_at,
+                trusted_parties: account.trusted_parties,
+                custody_mode: account.custody_mode,
+                allowance: account.allowance,
+                payout: account.payout,
+            };
+            (principal, account)
+        })
+        .collect();
+
+    v7::DeadManSwitchState {
+        version: StateVersion::V7,
+        users,
+        config: old.config,
+        roles: old.roles,
+        pauses: old.pauses,
+    }
+}
+
+/// V7 -> V8: each account's history is appended, oldest first, to the stable history map.
+/// Unlike the other steps this writes outside the returned state, so it must only run
+/// from `post_upgrade`, once per upgrade.
+pub fn migrate_v7_to_v8(old: v7::DeadManSwitchState) -> DeadManSwitchState {
+    let users = old
+        .users
+        .into_iter()
+        .map(|(principal, account)| {
+            for log in account.transaction_history {
+                history::append(principal, log.timestamp, log.event.into());
+            }
+            let account = UserAccount {
+                principal: account.principal,
+                last_heartbeat: account.last_heartbeat,
+                timeout_duration_seconds: account.timeout_duration_seconds,
+                beneficiary: account.beneficiary,
+                beneficiaries: account.beneficiaries.into_iter().map(Into::into).collect(),
+                balance: account.balance,
+                contestation_period_seconds: account.contestation_period_seconds,
+                timeout_detected_at: account.timeout_detected_at,
+                trusted_parties: account.trusted_parties,
+                custody_mode: account.custody_mode.into(),
+                allowance: account.allowance.map(Into::into),
+                payout: account.payout.map(Into::into),
+                balance_drift: None,
+                last_deposit_block: None,
+                legacy_balance: None,
+                deposit_scan: None,
+                pending_withdrawal: None,
+            };
+            (principal, account)
+        })
+        .collect();
+
+    DeadManSwitchState {
+        version: StateVersion::V8,
+        users,
+        config: old.config.into(),
+        roles: old.roles.into_iter().map(|(principal, role)| (principal, role.into())).collect(),
+        pauses: old.pauses.into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{history::HistoryQuery, AccountEvent, CanisterConfig, CustodyMode, PayoutLegStatus, Role};
+    use candid::{CandidType, Principal};
+    use icrc_ledger_types::icrc1::account::Account;
+    use std::collections::HashMap;
+
+    const LEDGER: Principal = Principal::from_slice(&[0xAA]);
+    const OWNER: Principal = Principal::from_slice(&[1]);
+    const HEIR: Principal = Principal::from_slice(&[2]);
+
+    fn decode_from<T: CandidType>(version: StateVersion, state: &T) -> DeadManSwitchState {
+        let bytes = candid::encode_one(state).unwrap();
+        decode_state(version.as_u32(), &bytes).unwrap()
+    }
+
+    fn legacy_log() -> v6::TransactionLog {
+        v6::TransactionLog {
+            timestamp: Timestamp::from_nanos(5),
+            transaction_type: "deposit".to_string(),
+            amount: Some(1_000),
+            details: "Deposited 1000".to_string(),
+        }
+    }
+
+    fn v2_account() -> v2::UserAccount {
+        v2::UserAccount {
+            user_principal: OWNER,
+            last_heartbeat: 7_000_000_000,
+            timeout_duration_seconds: 3_600,
+            beneficiary: HEIR,
+            beneficiaries: vec![v7::Beneficiary {
+                principal: HEIR,
+                percentage: 100,
+                subaccount: None,
+            }],
+            balance: 1_000,
+            transaction_history: vec![legacy_log()],
+            contestation_period_seconds: 600,
+            timeout_detected_at: None,
+            trusted_parties: vec![],
+        }
+    }
+
+    fn v6_account() -> v6::UserAccount {
+        v6::UserAccount {
+            principal: OWNER,
+            last_heartbeat: Timestamp::from_nanos(7_000_000_000),
+            timeout_duration_seconds: DurationSecs::from_secs(3_600),
+            beneficiary: HEIR,
+            beneficiaries: vec![v7::Beneficiary {
+                principal: HEIR,
+                percentage: 100,
+                subaccount: Some(vec![3; 32]),
+            }],
+            balance: 1_000,
+            transaction_history: vec![legacy_log()],
+            contestation_period_seconds: DurationSecs::from_secs(600),
+            timeout_detected_at: Some(Timestamp::from_nanos(9)),
+            trusted_parties: vec![HEIR],
+            custody_mode: v7::CustodyMode::Custodial,
+            allowance: None,
+            payout: Some(v7::PayoutPlan {
+                created_at: Timestamp::from_nanos(9),
+                custody_mode: v7::CustodyMode::Custodial,
+                legs: vec![v7::PayoutLeg {
+                    to: Account {
+                        owner: HEIR,
+                        subaccount: Some([3; 32]),
+                    },
+                    amount: 990,
+                    fee: 10,
+                    status: v7::PayoutLegStatus::OutcomeUnknown {
+                        error: "timeout".to_string(),
+                        next_retry_at: Timestamp::from_nanos(11),
+                    },
+                    attempts: 1,
+                    created_at_time: Some(Timestamp::from_nanos(10)),
+                    memo: Some(vec![1, 2]),
+                }],
+            }),
+        }
+    }
+
+    fn operator_roles() -> BTreeMap<Principal, v7::Role> {
+        BTreeMap::from([(HEIR, v7::Role::Operator)])
+    }
+
+    fn custom_config() -> v7::CanisterConfig {
+        let mut config = v7::CanisterConfig::with_ledger(LEDGER);
+        config.check_interval = DurationSecs::from_secs(30);
+        config.features.allowance_mode = false;
+        config
+    }
+
+    /// The single account every fixture migrates to, minus what the older layouts lack.
+    /// Balances saved by V1 are set aside until moved, so only the total is checked here.
+    fn assert_account(state: &DeadManSwitchState) -> &UserAccount {
+        assert_eq!(state.version, StateVersion::CURRENT);
+        let account = &state.users[&OWNER];
+        assert_eq!(account.principal, OWNER);
+        assert_eq!(account.last_heartbeat, Timestamp::from_nanos(7_000_000_000));
+        assert_eq!(account.timeout_duration_seconds, DurationSecs::from_secs(3_600));
+        let legacy = account.legacy_balance.as_ref().map_or(0, |legacy| legacy.amount);
+        assert_eq!(account.balance + legacy, 1_000);
+        assert_eq!(account.beneficiaries.len(), 1);
+        assert_eq!(account.beneficiaries[0].principal, HEIR);
+        assert_eq!(account.beneficiaries[0].percentage, 100);
+        assert_eq!(account.custody_mode, CustodyMode::Custodial);
+        assert!(account.balance_drift.is_none());
+        assert!(account.last_deposit_block.is_none());
+
+        let history = history::page(OWNER, &HistoryQuery::default());
+        assert_eq!(history.total, 1);
+        assert!(matches!(
+            &history.entries[0].event,
+            AccountEvent::Legacy { transaction_type, amount: Some(1_000), .. } if transaction_type == "deposit"
+        ));
+        account
+    }
+
+    #[test]
+    fn v1_backfills_beneficiaries_and_contestation_period() {
+        let account = v1::UserAccount {
+            user_principal: OWNER,
+            last_heartbeat: 7_000_000_000,
+            timeout_duration_seconds: 3_600,
+            beneficiary: HEIR,
+            beneficiaries: vec![],
+            balance: 1_000,
+            transaction_history: vec![legacy_log()],
+            contestation_period_seconds: 0,
+            timeout_detected_at: None,
+            trusted_parties: vec![],
+        };
+        let state = decode_from(
+            StateVersion::V1,
+            &v1::DeadManSwitchState {
+                users: HashMap::from([(OWNER, account)]),
+                ckbtc_ledger: LEDGER,
+            },
+        );
+
+        let account = assert_account(&state);
+        assert_eq!(account.beneficiaries[0].subaccount, None);
+        assert_eq!(account.contestation_period_seconds, DEFAULT_CONTESTATION_PERIOD);
+        assert_eq!(state.config.ckbtc_ledger, LEDGER);
+        assert!(state.roles.is_empty());
+        assert_eq!(account.balance, 0);
+        assert_eq!(
+            account.legacy_balance,
+            Some(LegacyBalance {
+                amount: 1_000,
+                share: None,
+                unsettled: None
+            })
+        );
+    }
+
+    #[test]
+    fn v2_keeps_funds_in_custody() {
+        let state = decode_from(
+            StateVersion::V2,
+            &v2::DeadManSwitchState {
+                version: StateVersion::V2,
+                users: HashMap::from([(OWNER, v2_account())]),
+                ckbtc_ledger: LEDGER,
+            },
+        );
+
+        let account = assert_account(&state);
+        assert_eq!(account.contestation_period_seconds, DurationSecs::from_secs(600));
+        assert!(account.payout.is_none() && account.allowance.is_none());
+        assert_eq!(account.balance, 1_000);
+        assert!(account.legacy_balance.is_none());
+    }
+
+    #[test]
+    fn v3_moves_the_ledger_into_the_default_config() {
+        let state = decode_from(
+            StateVersion::V3,
+            &v3::DeadManSwitchState {
+                version: StateVersion::V3,
+                users: HashMap::from([(OWNER, v6_account())]),
+                ckbtc_ledger: LEDGER,
+            },
+        );
+
+        assert_account(&state);
+        assert_eq!(state.config.ckbtc_ledger, LEDGER);
+        assert_eq!(state.config.check_interval, DurationSecs::from_minutes(1));
+        assert!(state.config.features.allowance_mode);
+        assert_eq!(state.config.history_retention, None);
+    }
+
+    #[test]
+    fn v4_keeps_the_config() {
+        let state = decode_from(
+            StateVersion::V4,
+            &v4::DeadManSwitchState {
+                version: StateVersion::V4,
+                users: HashMap::from([(OWNER, v6_account())]),
+                config: custom_config(),
+            },
+        );
+
+        assert_account(&state);
+        assert_eq!(state.config.check_interval, DurationSecs::from_secs(30));
+        assert!(!state.config.features.allowance_mode);
+        assert!(state.roles.is_empty());
+    }
+
+    #[test]
+    fn v5_keeps_roles_and_an_unpaused_checker_pauses_nothing() {
+        let state = decode_from(
+            StateVersion::V5,
+            &v5::DeadManSwitchState {
+                version: StateVersion::V5,
+                users: BTreeMap::from([(OWNER, v6_account())]),
+                config: custom_config(),
+                roles: operator_roles(),
+                checker_paused: false,
+            },
+        );
+
+        assert_account(&state);
+        assert_eq!(state.roles[&HEIR], Role::Operator);
+        assert!(state.pauses.payouts.is_none());
+    }
+
+    #[test]
+    fn v6_keeps_payout_plans_and_pauses() {
+        let pauses = v7::PauseState {
+            withdrawals: Some(v7::ActivePause {
+                since: Timestamp::from_nanos(3),
+                by: HEIR,
+                reason: "incident".to_string(),
+            }),
+            heartbeat_pauses: vec![v7::PausePeriod {
+                started_at: Timestamp::from_nanos(1),
+                ended_at: Timestamp::from_nanos(2),
+            }],
+            ..Default::default()
+        };
+        let state = decode_from(
+            StateVersion::V6,
+            &v6::DeadManSwitchState {
+                version: StateVersion::V6,
+                users: BTreeMap::from([(OWNER, v6_account())]),
+                config: custom_config(),
+                roles: operator_roles(),
+                pauses,
+            },
+        );
+
+        let account = assert_account(&state);
+        assert_eq!(account.beneficiaries[0].subaccount, Some(vec![3; 32]));
+        assert_eq!(account.trusted_parties, vec![HEIR]);
+        let plan = account.payout.as_ref().unwrap();
+        assert_eq!(plan.legs[0].amount, 990);
+        assert_eq!(plan.legs[0].created_at_time, Some(Timestamp::from_nanos(10)));
+        assert_eq!(plan.legs[0].memo, Some(vec![1, 2]));
+        assert!(matches!(plan.legs[0].status, PayoutLegStatus::OutcomeUnknown { .. }));
+        assert_eq!(state.pauses.withdrawals.as_ref().unwrap().reason, "incident");
+        assert_eq!(state.pauses.heartbeat_pauses.len(), 1);
+    }
+
+    #[test]
+    fn v7_moves_typed_history_to_the_stable_map() {
+        let old = v6_account();
+        let account = v7::UserAccount {
+            principal: old.principal,
+            last_heartbeat: old.last_heartbeat,
+            timeout_duration_seconds: old.timeout_duration_seconds,
+            beneficiary: old.beneficiary,
+            beneficiaries: old.beneficiaries,
+            balance: old.balance,
+            transaction_history: vec![v7::TransactionLog {
+                timestamp: Timestamp::from_nanos(5),
+                event: v7::AccountEvent::Legacy {
+                    transaction_type: "deposit".to_string(),
+                    amount: Some(1_000),
+                    details: "Deposited 1000".to_string(),
+                },
+            }],
+            contestation_period_seconds: old.contestation_period_seconds,
+            timeout_detected_at: old.timeout_detected_at,
+            trusted_parties: old.trusted_parties,
+            custody_mode: old.custody_mode,
+            allowance: old.allowance,
+            payout: old.payout,
+        };
+        let state = decode_from(
+            StateVersion::V7,
+            &v7::DeadManSwitchState {
+                version: StateVersion::V7,
+                users: BTreeMap::from([(OWNER, account)]),
+                config: custom_config(),
+                roles: operator_roles(),
+                pauses: v7::PauseState::default(),
+            },
+        );
+
+        let account = assert_account(&state);
+        assert_eq!(account.timeout_detected_at, Some(Timestamp::from_nanos(9)));
+        assert_eq!(state.roles[&HEIR], Role::Operator);
+    }
+
+    #[test]
+    fn current_state_decodes_as_is() {
+        let state = DeadManSwitchState {
+            config: CanisterConfig::with_ledger(LEDGER),
+            ..Default::default()
+        };
+        let decoded = decode_from(StateVersion::CURRENT, &state);
+        assert_eq!(decoded.version, StateVersion::CURRENT);
+        assert_eq!(decoded.config.ckbtc_ledger, LEDGER);
+    }
+
+    #[test]
+    fn unknown_versions_are_rejected() {
+        assert!(decode_state(StateVersion::CURRENT.as_u32() + 1, &[]).is_err());
+    }
+}
@@ -0,0 +1,224 @@
+//! Emergency circuit breakers.
+//!
+//! Operators can pause payouts, withdrawals, registrations and heartbeats
+//! independently, e.g. while a bug or a ledger incident is investigated.
+//!
+//! - Paused payouts: the checker still detects timeouts and runs contestation
+//!   periods, but sends no ledger transfers. Accounts that became payable are paid
+//!   as soon as payouts resume.
+//! - Paused heartbeats: owners cannot prove they are alive, so every timeout and
+//!   contestation period that was running is pushed back by the time heartbeats
+//!   were paused. Nobody is timed out, and no heir is paid, because of the pause.
+//! - Paused withdrawals and registrations are simply rejected.
+//!
+//! Closed heartbeat pauses are kept so deadlines can be recomputed at any time.
+
+use crate::{DeadManError, DurationSecs, Timestamp};
+use candid::{CandidType, Deserialize, Principal};
+use serde::Serialize;
+
+/// Operation that can be paused on its own
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, Serialize, PartialEq, Eq)]
+pub enum PauseScope {
+    Payouts,
+    Withdrawals,
+    Registrations,
+    Heartbeats,
+}
+
+impl PauseScope {
+    pub const ALL: [PauseScope; 4] = [
+        PauseScope::Payouts,
+        PauseScope::Withdrawals,
+        PauseScope::Registrations,
+        PauseScope::Heartbeats,
+    ];
+}
+
+/// A pause that is in effect
+#[derive(CandidType, Deserialize, Clone, Debug, Serialize)]
+pub struct ActivePause {
+    pub since: Timestamp,
+    pub by: Principal,
+    pub reason: String,
+}
+
+/// A heartbeat pause that has ended
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, Serialize)]
+pub struct PausePeriod {
+    pub started_at: Timestamp,
+    pub ended_at: Timestamp,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, Default, Serialize)]
+pub struct PauseState {
+    pub payouts: Option<ActivePause>,
+    pub withdrawals: Option<ActivePause>,
+    pub registrations: Option<ActivePause>,
+    pub heartbeats: Option<ActivePause>,
+    pub heartbeat_pauses: Vec<PausePeriod>, // Oldest first; deadlines are pushed back by these
+}
+
+impl PauseState {
+    fn slot(&self, scope: PauseScope) -> &Option<ActivePause> {
+        match scope {
+            PauseScope::Payouts => &self.payouts,
+            PauseScope::Withdrawals => &self.withdrawals,
+            PauseScope::Registrations => &self.registrations,
+            PauseScope::Heartbeats => &self.heartbeats,
+        }
+    }
+
+    fn slot_mut(&mut self, scope: PauseScope) -> &mut Option<ActivePause> {
+        match scope {
+            PauseScope::Payouts => &mut self.payouts,
+            PauseScope::Withdrawals => &mut self.withdrawals,
+            PauseScope::Registrations => &mut self.registrations,
+            PauseScope::Heartbeats => &mut self.heartbeats,
+        }
+    }
+
+    pub fn is_paused(&self, scope: PauseScope) -> bool {
+        self.slot(scope).is_some()
+    }
+
+    /// Err for the caller if `scope` is paused
+    pub fn check(&self, scope: PauseScope) -> Result<(), DeadManError> {
+        match self.slot(scope) {
+            Some(pause) => Err(DeadManError::Paused {
+                scope,
+                reason: pause.reason.clone(),
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns false if `scope` was already paused
+    pub fn pause(&mut self, scope: PauseScope, by: Principal, reason: String, now: Timestamp) -> bool {
+        let slot = self.slot_mut(scope);
+        if slot.is_some() {
+            return false;
+        }
+        *slot = Some(ActivePause { since: now, by, reason });
+        true
+    }
+
+    /// Returns false if `scope` was not paused
+    pub fn resume(&mut self, scope: PauseScope, now: Timestamp) -> bool {
+        let Some(pause) = self.slot_mut(scope).take() else {
+            return false;
+        };
+        if scope == PauseScope::Heartbeats {
+            self.heartbeat_pauses.push(PausePeriod {
+                started_at: pause.since,
+                ended_at: now,
+            });
+        }
+        true
+    }
+
+    /// `span` after `start`, pushed back by the time heartbeats were paused in between.
+    /// A heartbeat pause still in effect counts as if it ended at `now`.
+    pub fn push_back(&self, start: Timestamp, span: DurationSecs, now: Timestamp) -> Timestamp {
+        let open = self.heartbeats.as_ref().map(|pause| PausePeriod {
+            started_at: pause.since,
+            ended_at: now.max(pause.since),
+        });
+
+        let mut end = start.saturating_add(span);
+        for period in self.heartbeat_pauses.iter().chain(open.iter()) {
+            if period.started_at >= end {
+                break;
+            }
+            if period.ended_at <= start {
+                continue;
+            }
+            end = end.saturating_add(period.ended_at.saturating_duration_since(period.started_at.max(start)));
+        }
+        end
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(secs: u64) -> Timestamp {
+        Timestamp::from_nanos(secs * 1_000_000_000)
+    }
+
+    fn secs(secs: u64) -> DurationSecs {
+        DurationSecs::from_secs(secs)
+    }
+
+    fn closed(periods: &[(u64, u64)]) -> PauseState {
+        PauseState {
+            heartbeat_pauses: periods
+                .iter()
+                .map(|&(started_at, ended_at)| PausePeriod {
+                    started_at: at(started_at),
+                    ended_at: at(ended_at),
+                })
+                .collect(),
+            ..PauseState::default()
+        }
+    }
+
+    #[test]
+    fn pauses_before_start_or_after_the_end_move_nothing() {
+        let pauses = closed(&[(100, 200), (300, 1_000), (1_500, 1_600)]);
+        // Ends exactly at start, and starts exactly at the end
+        assert_eq!(pauses.push_back(at(1_000), secs(500), at(2_000)), at(1_500));
+        assert_eq!(PauseState::default().push_back(at(1_000), secs(500), at(2_000)), at(1_500));
+    }
+
+    #[test]
+    fn a_pause_overlapping_start_only_counts_from_start() {
+        // 400s of the pause fall before start and must not count
+        let pauses = closed(&[(600, 1_100)]);
+        assert_eq!(pauses.push_back(at(1_000), secs(500), at(2_000)), at(1_600));
+    }
+
+    #[test]
+    fn a_pause_still_open_counts_until_now() {
+        let mut pauses = PauseState::default();
+        assert!(pauses.pause(PauseScope::Heartbeats, Principal::anonymous(), "incident".to_string(), at(1_200)));
+        assert_eq!(pauses.push_back(at(1_000), secs(500), at(1_300)), at(1_600));
+        // Keeps moving while it lasts
+        assert_eq!(pauses.push_back(at(1_000), secs(500), at(1_900)), at(2_200));
+        // Resuming keeps exactly the time it lasted
+        assert!(pauses.resume(PauseScope::Heartbeats, at(1_900)));
+        assert_eq!(pauses.push_back(at(1_000), secs(500), at(5_000)), at(2_200));
+        // Other scopes do not push deadlines back
+        assert!(pauses.pause(PauseScope::Payouts, Principal::anonymous(), "incident".to_string(), at(1_000)));
+        assert_eq!(pauses.push_back(at(1_000), secs(500), at(5_000)), at(2_200));
+    }
+
+    #[test]
+    fn pauses_in_a_row_each_add_their_length() {
+        // Active time: 1000-1100, 1200-1300, 1400-1700 adds up to the 500s span
+        let pauses = closed(&[(1_100, 1_200), (1_300, 1_400)]);
+        assert_eq!(pauses.push_back(at(1_000), secs(500), at(5_000)), at(1_700));
+
+        // The second pause only falls inside the span once the first pushed the end back
+        let pauses = closed(&[(1_100, 1_300), (1_550, 1_650)]);
+        assert_eq!(pauses.push_back(at(1_000), secs(500), at(5_000)), at(1_800));
+
+        // Closed pauses followed by one still open
+        let mut pauses = closed(&[(1_100, 1_200)]);
+        pauses.pause(PauseScope::Heartbeats, Principal::anonymous(), "again".to_string(), at(1_500));
+        assert_eq!(pauses.push_back(at(1_000), secs(500), at(1_550)), at(1_650));
+    }
+
+    #[test]
+    fn a_grace_end_moves_by_exactly_the_paused_time() {
+        // As `UserAccount::grace_period_end` chains them: the timeout, then the contestation period
+        let grace_end = |pauses: &PauseState| {
+            let timeout_at = pauses.push_back(at(1_000), secs(500), at(5_000));
+            pauses.push_back(timeout_at, secs(300), at(5_000))
+        };
+        assert_eq!(grace_end(&PauseState::default()), at(1_800));
+        // 200s paused before the timeout and 50s during the contestation period
+        assert_eq!(grace_end(&closed(&[(1_200, 1_400), (1_800, 1_850)])), at(2_050));
+    }
+}
@@ -0,0 +1,261 @@
+//! Reconciliation of tracked balances against the ledger.
+//!
+//! Every hour the canister reads the ledger balance of each custodial account's
+//! deposit subaccount and compares it with the balance it tracks for the account:
+//!
+//! - ledger below tracked: funds the canister counts on are missing, e.g. after an
+//!   outgoing transfer the canister never recorded, or a payout leg sent twice. The
+//!   account is flagged with a `BalanceDrift`, which holds its payout until the
+//!   subaccount covers the tracked balance again, since the transfers would send more
+//!   than is there.
+//! - ledger above tracked: deposits that were not synced yet. Reported, not flagged.
+//!
+//! A flag is lifted by the next run that finds the subaccount covering the balance,
+//! or right away by `sync_balance`, which sets the tracked balance to the ledger's.
+//! Each account is locked while its balance is read, so a withdrawal or payout cannot
+//! move funds between the two reads. Accounts that are busy, or whose payout has
+//! transfers with an unknown outcome, are skipped until the next run.
+//!
+//! The latest report lives on the heap; the flags are kept on the accounts.
+
+use crate::{
+    certification, deposit_account, ledger_balance_of, scheduler, AccountEvent, AccountGuard, CustodyMode,
+    DeadManError, PayoutLegStatus, Timestamp, UserAccount, STATE,
+};
+use candid::{CandidType, Deserialize, Principal};
+use futures::future::join_all;
+use ic_cdk_timers::set_timer_interval;
+use serde::Serialize;
+use std::cell::{Cell, RefCell};
+use std::time::Duration;
+
+// How often the balances are reconciled
+const RECONCILIATION_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+// Ledger balance queries in flight at once; their accounts stay locked until the batch is done
+const LEDGER_BATCH_SIZE: usize = 50;
+
+/// Shortfall found on an account's deposit subaccount
+#[derive(CandidType, Deserialize, Clone, Debug, Serialize, PartialEq, Eq)]
+pub struct BalanceDrift {
+    pub tracked: u64,
+    pub ledger: u64,
+    pub detected_at: Timestamp,
+}
+
+/// Tracked and ledger balance of one account
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct AccountBalances {
+    pub owner: Principal,
+    pub tracked: u64,
+    pub ledger: u64,
+}
+
+/// Outcome of the most recent reconciliation run
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ReconciliationReport {
+    pub started_at: Timestamp,
+    pub finished_at: Timestamp,
+    pub checked: u64,
+    pub skipped: u64,     // Busy, a payout transfer with an unknown outcome, or closed meanwhile
+    pub unreachable: u64, // The ledger balance could not be read
+    pub tracked_total: u64, // Over the checked accounts
+    pub ledger_total: u64,  // Over the checked accounts
+    pub shortfalls: Vec<AccountBalances>, // Flagged, payouts held
+    pub unsynced: Vec<AccountBalances>,   // More on the ledger than tracked
+    pub resolved: Vec<Principal>,         // Flagged before, covered again
+}
+
+thread_local! {
+    static LAST_REPORT: RefCell<Option<ReconciliationReport>> = const { RefCell::new(None) };
+    static RUNNING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Clears RUNNING when dropped, including when a trap drops the run's future
+struct RunGuard;
+
+impl Drop for RunGuard {
+    fn drop(&mut self) {
+        RUNNING.with(|running| running.set(false));
+    }
+}
+
+/// The latest report, if a run finished since the last upgrade
+pub fn latest() -> Option<ReconciliationReport> {
+    LAST_REPORT.with(|report| report.borrow().clone())
+}
+
+/// Reconcile periodically
+pub fn start() {
+    set_timer_interval(RECONCILIATION_INTERVAL, || async {
+        let _ = reconcile().await;
+    });
+}
+
+/// Lift `account`'s flag once its deposit subaccount holds `ledger_balance`, if that
+/// covers the tracked balance. Returns whether a flag was lifted.
+pub fn resolve(account: &mut UserAccount, ledger_balance: u64, now: Timestamp) -> bool {
+    if ledger_balance < account.balance || account.balance_drift.is_none() {
+        return false;
+    }
+    account.balance_drift = None;
+    account.record(
+        now,
+        AccountEvent::BalanceDriftResolved {
+            tracked: account.balance,
+            ledger: ledger_balance,
+        },
+    );
+    true
+}
+
+/// Whether a payout transfer may have executed without being accounted for yet
+fn payout_unsettled(account: &UserAccount) -> bool {
+    account.payout.as_ref().is_some_and(|plan| {
+        plan.legs.iter().any(|leg| {
+            matches!(
+                leg.status,
+                PayoutLegStatus::InFlight { .. } | PayoutLegStatus::OutcomeUnknown { .. }
+            )
+        })
+    })
+}
+
+/// Compare every custodial account with its deposit subaccount and flag or clear drift
+pub async fn reconcile() -> Result<ReconciliationReport, DeadManError> {
+    if RUNNING.with(|running| running.replace(true)) {
+        return Err(DeadManError::Busy);
+    }
+    let _guard = RunGuard;
+
+    let (ledger, owners) = STATE.with(|state| {
+        let s = state.borrow();
+        let owners: Vec<Principal> = s
+            .users
+            .iter()
+            .filter(|(_, account)| account.custody_mode == CustodyMode::Custodial)
+            .map(|(owner, _)| *owner)
+            .collect();
+        (s.config.ckbtc_ledger, owners)
+    });
+
+    let mut report = ReconciliationReport {
+        started_at: Timestamp::now(),
+        finished_at: Timestamp::now(),
+        checked: 0,
+        skipped: 0,
+        unreachable: 0,
+        tracked_total: 0,
+        ledger_total: 0,
+        shortfalls: Vec::new(),
+        unsynced: Vec::new(),
+        resolved: Vec::new(),
+    };
+    let mut changed = Vec::new();
+
+    for batch in owners.chunks(LEDGER_BATCH_SIZE) {
+        let locked: Vec<(Principal, AccountGuard)> = batch
+            .iter()
+            .filter_map(|owner| match AccountGuard::acquire(*owner) {
+                Ok(guard) => Some((*owner, guard)),
+                Err(_) => {
+                    report.skipped += 1;
+                    None
+                }
+            })
+            .collect();
+        let balances = join_all(
+            locked
+                .iter()
+                .map(|(owner, _)| ledger_balance_of(ledger, deposit_account(owner))),
+        )
+        .await;
+
+        let now = Timestamp::now();
+        for ((owner, _guard), balance) in locked.iter().zip(balances) {
+            let Ok(ledger_balance) = balance else {
+                report.unreachable += 1;
+                continue;
+            };
+            STATE.with(|state| {
+                let mut s = state.borrow_mut();
+                // Closed or switched to allowance mode while the ledger was queried
+                let Some(account) = s
+                    .users
+                    .get_mut(owner)
+                    .filter(|account| account.custody_mode == CustodyMode::Custodial)
+                else {
+                    report.skipped += 1;
+                    return;
+                };
+                if payout_unsettled(account) {
+                    report.skipped += 1;
+                    return;
+                }
+
+                let balances = AccountBalances {
+                    owner: *owner,
+                    tracked: account.balance,
+                    ledger: ledger_balance,
+                };
+                report.checked += 1;
+                report.tracked_total = report.tracked_total.saturating_add(balances.tracked);
+                report.ledger_total = report.ledger_total.saturating_add(balances.ledger);
+
+                if balances.ledger < balances.tracked {
+                    match &mut account.balance_drift {
+                        Some(drift) => {
+                            drift.tracked = balances.tracked;
+                            drift.ledger = balances.ledger;
+                        }
+                        None => {
+                            account.balance_drift = Some(BalanceDrift {
+                                tracked: balances.tracked,
+                                ledger: balances.ledger,
+                                detected_at: now,
+                            });
+                            account.record(
+                                now,
+                                AccountEvent::BalanceDriftDetected {
+                                    tracked: balances.tracked,
+                                    ledger: balances.ledger,
+                                },
+                            );
+                            ic_cdk::println!(
+                                "Balance drift on {}: {} ckBTC tracked, {} ckBTC on the ledger, payouts held",
+                                owner, balances.tracked, balances.ledger
+                            );
+                        }
+                    }
+                    changed.push(*owner);
+                    report.shortfalls.push(balances);
+                } else {
+                    if resolve(account, balances.ledger, now) {
+                        changed.push(*owner);
+                        report.resolved.push(*owner);
+                    }
+                    if balances.ledger > balances.tracked {
+                        report.unsynced.push(balances);
+                    }
+                }
+            });
+        }
+    }
+
+    for owner in &changed {
+        scheduler::reschedule(*owner);
+    }
+    certification::refresh(&changed);
+
+    report.finished_at = Timestamp::now();
+    ic_cdk::println!(
+        "Reconciliation: {} account(s) checked, {} short, {} resolved, {} skipped, {} unreachable",
+        report.checked,
+        report.shortfalls.len(),
+        report.resolved.len(),
+        report.skipped,
+        report.unreachable
+    );
+    LAST_REPORT.with(|last| *last.borrow_mut() = Some(report.clone()));
+    Ok(report)
+}
@@ -0,0 +1,397 @@
+//! Proof of reserves.
+//!
+//! Every few hours the canister reads the ledger balance of each custodial account's
+//! deposit subaccount and commits to the tracked balances with a Merkle sum tree:
+//!
+//! - leaf: `sha256(LEAF_DOMAIN || len(owner) || owner || balance)`, sum = balance
+//! - node: `sha256(NODE_DOMAIN || left hash || left sum || right hash || right sum)`,
+//!   sum = left sum + right sum
+//!
+//! Leaves are ordered by owner principal, sums are big-endian u64, and a node without
+//! a sibling moves up a level unchanged. The root's sum is the total owed to users.
+//! The root, that total and the ledger total are certified under `reserves` (see
+//! `certification`), and `get_reserves_proof` hands each user the path from their
+//! leaf to the root. A user who checks their path knows their balance is counted in
+//! the total; anyone can compare the total against the ledger total.
+//!
+//! Snapshots live on the heap; a new one is taken right after every upgrade.
+
+use crate::certification::{self, Reveal};
+use crate::{deposit_account, ledger_balance_of, CustodyMode, DeadManError, Timestamp, STATE};
+use candid::{CandidType, Deserialize, Principal};
+use futures::future::join_all;
+use ic_cdk::api::msg_caller;
+use ic_cdk::query;
+use ic_cdk_timers::{set_timer, set_timer_interval};
+use serde_bytes::ByteBuf;
+use sha2::{Digest, Sha256};
+use std::cell::{Cell, RefCell};
+use std::time::Duration;
+
+const LEAF_DOMAIN: &[u8] = b"\x11dms-reserves-leaf";
+const NODE_DOMAIN: &[u8] = b"\x11dms-reserves-node";
+
+// How often a snapshot is taken
+const SNAPSHOT_INTERVAL: Duration = Duration::from_secs(6 * 60 * 60);
+
+// Ledger balance queries in flight at once while taking a snapshot
+const LEDGER_BATCH_SIZE: usize = 50;
+
+type Hash = [u8; 32];
+
+#[derive(Clone, Copy, Debug)]
+struct Node {
+    hash: Hash,
+    sum: u64,
+}
+
+fn leaf(owner: &Principal, balance: u64) -> Node {
+    let owner = owner.as_slice();
+    let mut h = Sha256::new();
+    h.update(LEAF_DOMAIN);
+    h.update([owner.len() as u8]);
+    h.update(owner);
+    h.update(balance.to_be_bytes());
+    Node {
+        hash: h.finalize().into(),
+        sum: balance,
+    }
+}
+
+fn parent(left: &Node, right: &Node) -> Node {
+    let mut h = Sha256::new();
+    h.update(NODE_DOMAIN);
+    h.update(left.hash);
+    h.update(left.sum.to_be_bytes());
+    h.update(right.hash);
+    h.update(right.sum.to_be_bytes());
+    Node {
+        hash: h.finalize().into(),
+        sum: left.sum.saturating_add(right.sum),
+    }
+}
+
+/// Every level of the tree, leaves first, root last
+struct SumTree {
+    owners: Vec<Principal>, // Ordered, one per leaf
+    levels: Vec<Vec<Node>>,
+}
+
+impl SumTree {
+    fn build(leaves: Vec<(Principal, u64)>) -> Self {
+        let owners = leaves.iter().map(|(owner, _)| *owner).collect();
+        let mut level: Vec<Node> = leaves.iter().map(|(owner, balance)| leaf(owner, *balance)).collect();
+        let mut levels = Vec::new();
+        while level.len() > 1 {
+            let next = level
+                .chunks(2)
+                .map(|pair| match pair {
+                    [left, right] => parent(left, right),
+                    [single] => *single,
+                    _ => unreachable!("chunks(2) yields one or two nodes"),
+                })
+                .collect();
+            levels.push(std::mem::replace(&mut level, next));
+        }
+        levels.push(level);
+        Self { owners, levels }
+    }
+
+    fn root(&self) -> Option<Node> {
+        self.levels.last().and_then(|level| level.first()).copied()
+    }
+
+    /// Siblings from `owner`'s leaf up to the root
+    fn path(&self, owner: &Principal) -> Option<(u64, Vec<ProofStep>)> {
+        let mut index = self.owners.binary_search(owner).ok()?;
+        let balance = self.levels[0][index].sum;
+        let mut path = Vec::new();
+        for level in &self.levels[..self.levels.len() - 1] {
+            let sibling = index ^ 1;
+            if let Some(node) = level.get(sibling) {
+                path.push(ProofStep {
+                    hash: ByteBuf::from(node.hash.to_vec()),
+                    sum: node.sum,
+                    side: if sibling < index { Side::Left } else { Side::Right },
+                });
+            }
+            index /= 2;
+        }
+        Some((balance, path))
+    }
+}
+
+/// What the canister certifies about the latest snapshot
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ReservesSnapshot {
+    pub taken_at: Timestamp,
+    pub root_hash: Option<ByteBuf>, // None if there were no custodial accounts
+    pub liabilities: u64,           // Sum of tracked balances, i.e. the root's sum
+    pub accounts: u64,
+    pub ledger_total: u64,       // icrc1_balance_of summed over the deposit subaccounts
+    pub ledger_unreachable: u32, // Subaccounts whose balance could not be read; ledger_total is then a lower bound
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Side {
+    Left,
+    Right,
+}
+
+/// Sibling to combine with on the way up: `side` says which side of the pair it is on
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ProofStep {
+    pub hash: ByteBuf,
+    pub sum: u64,
+    pub side: Side,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ReservesProof {
+    pub snapshot: ReservesSnapshot,
+    pub balance: u64, // The caller's tracked balance when the snapshot was taken
+    pub path: Vec<ProofStep>,
+    pub certificate: ByteBuf,
+    pub witness: ByteBuf, // CBOR hash tree revealing `reserves`, the Candid encoding of `snapshot`
+}
+
+#[allow(non_camel_case_types)]
+#[derive(CandidType, Deserialize, Debug)]
+pub enum ReservesProofResult {
+    ok(ReservesProof),
+    err(DeadManError),
+}
+
+thread_local! {
+    static SNAPSHOT: RefCell<Option<(ReservesSnapshot, SumTree)>> = const { RefCell::new(None) };
+    static SNAPSHOT_RUNNING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Clears SNAPSHOT_RUNNING when dropped, including when a trap drops the snapshot's future
+struct SnapshotGuard;
+
+impl Drop for SnapshotGuard {
+    fn drop(&mut self) {
+        SNAPSHOT_RUNNING.with(|running| running.set(false));
+    }
+}
+
+/// The latest snapshot, if one was taken since the last upgrade
+pub fn latest() -> Option<ReservesSnapshot> {
+    SNAPSHOT.with(|snapshot| snapshot.borrow().as_ref().map(|(summary, _)| summary.clone()))
+}
+
+/// Take a snapshot now and periodically from then on
+pub fn start() {
+    set_timer(Duration::ZERO, async {
+        let _ = take_snapshot().await;
+    });
+    set_timer_interval(SNAPSHOT_INTERVAL, || async {
+        let _ = take_snapshot().await;
+    });
+}
+
+/// Read the deposit subaccounts from the ledger, then build the tree over the balances
+/// tracked at that point and certify it
+pub async fn take_snapshot() -> Result<ReservesSnapshot, DeadManError> {
+    if SNAPSHOT_RUNNING.with(|running| running.replace(true)) {
+        return Err(DeadManError::Busy);
+    }
+    let _guard = SnapshotGuard;
+
+    let (ledger, owners) = STATE.with(|state| {
+        let s = state.borrow();
+        let owners: Vec<Principal> = s
+            .users
+            .iter()
+            .filter(|(_, account)| account.custody_mode == CustodyMode::Custodial)
+            .map(|(owner, _)| *owner)
+            .collect();
+        (s.config.ckbtc_ledger, owners)
+    });
+
+    let mut ledger_total = 0u64;
+    let mut ledger_unreachable = 0u32;
+    for batch in owners.chunks(LEDGER_BATCH_SIZE) {
+        let balances = join_all(batch.iter().map(|owner| ledger_balance_of(ledger, deposit_account(owner)))).await;
+        for balance in balances {
+            match balance {
+                Ok(balance) => ledger_total = ledger_total.saturating_add(balance),
+                Err(_) => ledger_unreachable += 1,
+            }
+        }
+    }
+
+    let leaves: Vec<(Principal, u64)> = STATE.with(|state| {
+        state
+            .borrow()
+            .users
+            .iter()
+            .filter(|(_, account)| account.custody_mode == CustodyMode::Custodial)
+            .map(|(owner, account)| (*owner, account.balance))
+            .collect()
+    });
+    let tree = SumTree::build(leaves);
+    let root = tree.root();
+    let snapshot = ReservesSnapshot {
+        taken_at: Timestamp::now(),
+        root_hash: root.map(|node| ByteBuf::from(node.hash.to_vec())),
+        liabilities: root.map_or(0, |node| node.sum),
+        accounts: tree.owners.len() as u64,
+        ledger_total,
+        ledger_unreachable,
+    };
+
+    SNAPSHOT.with(|s| *s.borrow_mut() = Some((snapshot.clone(), tree)));
+    certification::refresh(&[]);
+
+    ic_cdk::println!(
+        "Reserves snapshot: {} ckBTC owed to {} account(s), {} ckBTC on the ledger ({} unreachable)",
+        snapshot.liabilities,
+        snapshot.accounts,
+        snapshot.ledger_total,
+        snapshot.ledger_unreachable
+    );
+    Ok(snapshot)
+}
+
+/// The caller's inclusion proof in the latest snapshot. Only works in query calls,
+/// which carry a certificate.
+#[query]
+fn get_reserves_proof() -> ReservesProofResult {
+    let caller = msg_caller();
+    let found = SNAPSHOT.with(|snapshot| {
+        let snapshot = snapshot.borrow();
+        let (summary, tree) = snapshot.as_ref()?;
+        tree.path(&caller).map(|(balance, path)| (summary.clone(), balance, path))
+    });
+    let Some((snapshot, balance, path)) = found else {
+        return ReservesProofResult::err(DeadManError::not_found("Account in the latest reserves snapshot"));
+    };
+
+    let Some(certificate) = ic_cdk::api::data_certificate() else {
+        return ReservesProofResult::err(DeadManError::internal("No certificate available, call as a query"));
+    };
+    let Some(witness) = certification::encode_witness(&certification::witness(Reveal::Reserves)) else {
+        return ReservesProofResult::err(DeadManError::internal("Failed to encode the witness"));
+    };
+    ReservesProofResult::ok(ReservesProof {
+        snapshot,
+        balance,
+        path,
+        certificate: ByteBuf::from(certificate),
+        witness,
+    })
+}
+
+/// The latest reserves snapshot
+#[query]
+fn get_reserves() -> Option<ReservesSnapshot> {
+    latest()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn owner(n: u8) -> Principal {
+        Principal::from_slice(&[n])
+    }
+
+    fn tree(balances: &[u64]) -> SumTree {
+        let mut leaves: Vec<(Principal, u64)> =
+            balances.iter().enumerate().map(|(i, balance)| (owner(i as u8), *balance)).collect();
+        leaves.sort_by_key(|(owner, _)| *owner);
+        SumTree::build(leaves)
+    }
+
+    /// What a user does with a proof: fold the path from their leaf up to the root
+    fn recompute(owner: &Principal, balance: u64, path: &[ProofStep]) -> Node {
+        path.iter().fold(leaf(owner, balance), |node, step| {
+            let sibling = Node {
+                hash: step.hash.as_slice().try_into().expect("32 byte hash"),
+                sum: step.sum,
+            };
+            match step.side {
+                Side::Left => parent(&sibling, &node),
+                Side::Right => parent(&node, &sibling),
+            }
+        })
+    }
+
+    fn proves(tree: &SumTree, owner: &Principal, balance: u64, path: &[ProofStep]) -> bool {
+        let (root, node) = (tree.root().unwrap(), recompute(owner, balance, path));
+        node.hash == root.hash && node.sum == root.sum
+    }
+
+    #[test]
+    fn every_path_recomputes_the_root_hash_and_sum() {
+        for leaves in 1..=9u8 {
+            let balances: Vec<u64> = (0..leaves).map(|i| 1_000 * (u64::from(i) + 1)).collect();
+            let tree = tree(&balances);
+            let root = tree.root().unwrap();
+            assert_eq!(root.sum, balances.iter().sum::<u64>());
+            for (i, expected) in balances.iter().enumerate() {
+                let owner = owner(i as u8);
+                let (balance, path) = tree.path(&owner).unwrap();
+                assert_eq!(balance, *expected);
+                assert!(proves(&tree, &owner, balance, &path), "leaf {} of {}", i, leaves);
+            }
+        }
+    }
+
+    #[test]
+    fn an_odd_leaf_is_carried_up_unchanged() {
+        // Five leaves: the fifth has no sibling on the two lowest levels
+        let tree = tree(&[1, 2, 3, 4, 5]);
+        let (balance, path) = tree.path(&owner(4)).unwrap();
+        assert_eq!(balance, 5);
+        assert_eq!(path.len(), 1);
+        assert_eq!(path[0].side, Side::Left);
+        assert_eq!(path[0].sum, 10);
+
+        let carried = leaf(&owner(4), 5);
+        assert_eq!(tree.levels[1][2].hash, carried.hash);
+        assert_eq!(tree.levels[2][1].hash, carried.hash);
+        let root = tree.root().unwrap();
+        assert_eq!(root.hash, parent(&tree.levels[2][0], &carried).hash);
+        assert_eq!(root.sum, 15);
+        assert!(proves(&tree, &owner(4), balance, &path));
+    }
+
+    #[test]
+    fn a_changed_balance_or_sibling_fails_the_proof() {
+        let tree = tree(&[100, 200, 300, 400, 500]);
+        let (balance, path) = tree.path(&owner(1)).unwrap();
+        assert!(proves(&tree, &owner(1), balance, &path));
+
+        assert!(!proves(&tree, &owner(1), balance + 1, &path));
+        assert!(!proves(&tree, &owner(1), balance - 1, &path));
+        assert!(!proves(&tree, &owner(2), balance, &path));
+
+        let mut hash = path.clone();
+        let mut bytes = hash[0].hash.to_vec();
+        bytes[0] ^= 1;
+        hash[0].hash = ByteBuf::from(bytes);
+        assert!(!proves(&tree, &owner(1), balance, &hash));
+
+        // Moving value from the sibling to the leaf keeps the total but not the hash
+        let mut sum = path.clone();
+        sum[0].sum -= 1;
+        assert!(!proves(&tree, &owner(1), balance + 1, &sum));
+
+        let mut side = path.clone();
+        side[0].side = Side::Right;
+        assert!(!proves(&tree, &owner(1), balance, &side));
+
+        let mut short = path.clone();
+        short.pop();
+        assert!(!proves(&tree, &owner(1), balance, &short));
+    }
+
+    #[test]
+    fn owners_outside_the_snapshot_get_no_path() {
+        let tree = tree(&[1, 2, 3]);
+        assert!(tree.path(&owner(3)).is_none());
+    }
+}
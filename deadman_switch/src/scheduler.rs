@@ -0,0 +1,309 @@
+//! Deadline index driving the timeout checker.
+//!
+//! Every account has at most one pending deadline: its timeout while the owner is
+//! expected to send heartbeats, the end of its contestation period once the timeout
+//! was detected, and the next retry of its payout after that. Deadlines are kept in
+//! a `BTreeSet` ordered by time, and a single one-shot timer is armed for the
+//! earliest one, so a round only touches accounts that are actually due.
+//!
+//! The index lives on the heap only. It is rebuilt from `STATE` on `init` and
+//! `post_upgrade`, and every endpoint that moves a deadline calls `reschedule`.
+//! A round stops before it runs out of instructions or after sending
+//! `MAX_TRANSFERS_PER_ROUND` ledger transfers, and leaves the remaining due accounts
+//! for an immediate follow-up round. Payout progress is kept on each account's
+//! `PayoutPlan`, so a round can stop between any two accounts or legs.
+//!
+//! Accounts held by a pause (see `pause`) are left out of the index and put back by
+//! `reindex` when the pause is lifted. The same goes for payouts held by a balance
+//! drift (see `reconciliation`), which are put back by `reschedule`.
+
+use crate::{
+    process_deadline, DeadManError, PauseScope, PauseState, PayoutLegStatus, Timestamp, UserAccount, PAYOUT_IN_FLIGHT_STALE,
+    STATE,
+};
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk_timers::{clear_timer, set_timer, set_timer_interval, TimerId};
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeSet, HashMap};
+use std::time::Duration;
+
+// Stop starting new accounts once a round has used this many instructions in the
+// current message (update and timer messages are capped at 40B)
+const ROUND_INSTRUCTION_BUDGET: u64 = 20_000_000_000;
+
+// Ledger transfers a single round may send; a mass expiry is spread over several rounds
+const MAX_TRANSFERS_PER_ROUND: usize = 50;
+
+// Re-arms the timer in case a round trapped before it could do so itself
+const WATCHDOG_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Default)]
+struct Schedule {
+    by_deadline: BTreeSet<(Timestamp, Principal)>,
+    by_principal: HashMap<Principal, Timestamp>,
+}
+
+impl Schedule {
+    fn set(&mut self, principal: Principal, deadline: Option<Timestamp>) {
+        if let Some(previous) = self.by_principal.remove(&principal) {
+            self.by_deadline.remove(&(previous, principal));
+        }
+        if let Some(deadline) = deadline {
+            self.by_principal.insert(principal, deadline);
+            self.by_deadline.insert((deadline, principal));
+        }
+    }
+
+    fn earliest(&self) -> Option<(Timestamp, Principal)> {
+        self.by_deadline.first().copied()
+    }
+}
+
+thread_local! {
+    static SCHEDULE: RefCell<Schedule> = RefCell::default();
+
+    // The armed one-shot timer and the deadline it fires at
+    static TIMER: Cell<Option<(TimerId, Timestamp)>> = const { Cell::new(None) };
+
+    static ROUND_RUNNING: Cell<bool> = const { Cell::new(false) };
+
+    static LAST_ROUND: RefCell<Option<RoundStats>> = const { RefCell::new(None) };
+}
+
+/// Outcome of the most recent checker round
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct RoundStats {
+    pub started_at: Timestamp,
+    pub finished_at: Timestamp,
+    pub accounts_processed: u64,
+    pub transfers_attempted: u64,
+    pub stopped_early: bool, // Hit the transfer cap or instruction budget with accounts still due
+}
+
+/// How far behind the timeout checker is
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct CheckerStatus {
+    pub scheduled_accounts: u64,
+    pub backlog: u64, // Accounts whose deadline has already passed
+    pub next_deadline: Option<Timestamp>,
+    pub round_running: bool,
+    pub payouts_paused: bool, // Set by operators; timeouts are still detected but nothing is paid
+    pub last_round: Option<RoundStats>,
+}
+
+/// Clears the running flag even if the round's future is dropped after a trap
+struct RoundGuard;
+
+impl Drop for RoundGuard {
+    fn drop(&mut self) {
+        ROUND_RUNNING.with(|running| running.set(false));
+    }
+}
+
+/// The next time the checker has to look at this account, if ever.
+/// None while a pause, a balance drift or a legacy balance not yet moved holds the
+/// account; `reindex` picks it up again on resume, and `reschedule` once the drift is
+/// resolved or the balance moved.
+pub fn next_deadline(account: &UserAccount, pauses: &PauseState) -> Option<Timestamp> {
+    let now = Timestamp::now();
+    let payable = account.payout.is_some()
+        || (account.timeout_detected_at.is_some() && account.grace_period_end(pauses) <= now);
+    if payable && (pauses.is_paused(PauseScope::Payouts)
+            || account.balance_drift.is_some()
+            || account.legacy_balance.is_some()) {
+        return None;
+    }
+
+    if let Some(plan) = &account.payout {
+        if plan.legs.iter().all(|leg| matches!(leg.status, PayoutLegStatus::Paid { .. })) {
+            // Paid out but not cleaned up yet
+            return Some(plan.created_at);
+        }
+        return plan
+            .legs
+            .iter()
+            .filter_map(|leg| match &leg.status {
+                PayoutLegStatus::Pending => Some(plan.created_at),
+                PayoutLegStatus::InFlight { started_at } => Some(started_at.saturating_add(PAYOUT_IN_FLIGHT_STALE)),
+                PayoutLegStatus::Failed { next_retry_at, .. }
+                | PayoutLegStatus::OutcomeUnknown { next_retry_at, .. } => {
+                    // Timestamp::MAX marks legs held for manual review
+                    (*next_retry_at != Timestamp::MAX).then_some(*next_retry_at)
+                }
+                PayoutLegStatus::Paid { .. } => None,
+            })
+            .min();
+    }
+
+    let deadline = match account.timeout_detected_at {
+        None => account.timeout_at(pauses),
+        Some(_) => account.grace_period_end(pauses),
+    };
+    // While heartbeats are paused every deadline that has not passed yet keeps moving back
+    if deadline > now && pauses.is_paused(PauseScope::Heartbeats) {
+        return None;
+    }
+    Some(deadline)
+}
+
+fn deadline_of(principal: &Principal) -> Option<Timestamp> {
+    STATE.with(|state| {
+        let s = state.borrow();
+        s.users.get(principal).and_then(|account| next_deadline(account, &s.pauses))
+    })
+}
+
+/// Re-index an account after anything affecting its deadline changed
+pub fn reschedule(principal: Principal) {
+    let deadline = deadline_of(&principal);
+    SCHEDULE.with(|schedule| schedule.borrow_mut().set(principal, deadline));
+    arm_timer();
+}
+
+/// Index every account from scratch and start the checker
+pub fn start() {
+    reindex();
+    ic_cdk::println!(
+        "Timeout checker started - {} account(s) scheduled",
+        SCHEDULE.with(|schedule| schedule.borrow().by_principal.len())
+    );
+
+    set_timer_interval(WATCHDOG_INTERVAL, || async { arm_timer() });
+}
+
+/// Recompute every account's deadline, e.g. after a pause was set or lifted
+pub fn reindex() {
+    SCHEDULE.with(|schedule| {
+        let mut schedule = schedule.borrow_mut();
+        *schedule = Schedule::default();
+        STATE.with(|state| {
+            let s = state.borrow();
+            for (principal, account) in s.users.iter() {
+                schedule.set(*principal, next_deadline(account, &s.pauses));
+            }
+        });
+    });
+    arm_timer();
+}
+
+/// Snapshot of the index for monitoring
+pub fn status() -> CheckerStatus {
+    let now = Timestamp::now();
+    SCHEDULE.with(|schedule| {
+        let schedule = schedule.borrow();
+        CheckerStatus {
+            scheduled_accounts: schedule.by_principal.len() as u64,
+            backlog: schedule
+                .by_deadline
+                .iter()
+                .take_while(|(deadline, _)| *deadline <= now)
+                .count() as u64,
+            next_deadline: schedule.earliest().map(|(deadline, _)| deadline),
+            round_running: ROUND_RUNNING.with(|running| running.get()),
+            payouts_paused: STATE.with(|state| state.borrow().pauses.is_paused(PauseScope::Payouts)),
+            last_round: LAST_ROUND.with(|last| last.borrow().clone()),
+        }
+    })
+}
+
+/// Process one account right away, whatever its deadline, outside the regular rounds.
+/// Returns the number of ledger transfers sent.
+pub async fn process_now(principal: Principal) -> Result<usize, DeadManError> {
+    // A checker round is in progress
+    if ROUND_RUNNING.with(|running| running.replace(true)) {
+        return Err(DeadManError::Busy);
+    }
+    let guard = RoundGuard;
+
+    let attempted = process_deadline(principal, MAX_TRANSFERS_PER_ROUND).await;
+    let deadline = deadline_of(&principal);
+    SCHEDULE.with(|schedule| schedule.borrow_mut().set(principal, deadline));
+
+    drop(guard);
+    arm_timer();
+    Ok(attempted)
+}
+
+/// Make sure a timer is set for the earliest deadline
+fn arm_timer() {
+    // The running round re-arms once it is done
+    if ROUND_RUNNING.with(|running| running.get()) {
+        return;
+    }
+
+    let earliest = SCHEDULE.with(|schedule| schedule.borrow().earliest().map(|(deadline, _)| deadline));
+    let armed = TIMER.with(|timer| timer.get());
+    if armed.map(|(_, at)| at) == earliest {
+        return;
+    }
+    if let Some((id, _)) = armed {
+        clear_timer(id);
+    }
+
+    let timer = earliest.map(|deadline| {
+        let delay = deadline.as_nanos().saturating_sub(Timestamp::now().as_nanos());
+        let id = set_timer(Duration::from_nanos(delay), run_round());
+        (id, deadline)
+    });
+    TIMER.with(|t| t.set(timer));
+}
+
+/// Process every account whose deadline has passed, earliest first
+async fn run_round() {
+    TIMER.with(|timer| timer.set(None));
+    if ROUND_RUNNING.with(|running| running.replace(true)) {
+        return;
+    }
+    let guard = RoundGuard;
+
+    let mut stats = RoundStats {
+        started_at: Timestamp::now(),
+        finished_at: Timestamp::now(),
+        accounts_processed: 0,
+        transfers_attempted: 0,
+        stopped_early: false,
+    };
+    let mut transfers_left = MAX_TRANSFERS_PER_ROUND;
+    // An account still due after being processed (ledger busy, account locked) is
+    // looked at again after this long instead of spinning
+    let recheck_delay = STATE.with(|state| state.borrow().config.check_interval);
+    loop {
+        let now = Timestamp::now();
+        let due = SCHEDULE.with(|schedule| schedule.borrow().earliest().filter(|(deadline, _)| *deadline <= now));
+        let Some((_, principal)) = due else {
+            break;
+        };
+
+        if transfers_left == 0 || ic_cdk::api::instruction_counter() > ROUND_INSTRUCTION_BUDGET {
+            ic_cdk::println!(
+                "Timeout round paused after {} account(s) and {} transfer(s), resuming in the next round",
+                stats.accounts_processed,
+                stats.transfers_attempted
+            );
+            stats.stopped_early = true;
+            break;
+        }
+
+        let attempted = process_deadline(principal, transfers_left).await;
+        transfers_left -= attempted;
+        stats.accounts_processed += 1;
+        stats.transfers_attempted += attempted as u64;
+
+        // Anything still due after processing is waiting on something else (ledger errors,
+        // a locked account) and is checked back later, unless the transfer cap cut it short
+        let now = Timestamp::now();
+        let deadline = deadline_of(&principal).map(|d| {
+            if d <= now && transfers_left > 0 {
+                now.saturating_add(recheck_delay)
+            } else {
+                d
+            }
+        });
+        SCHEDULE.with(|schedule| schedule.borrow_mut().set(principal, deadline));
+    }
+
+    stats.finished_at = Timestamp::now();
+    LAST_ROUND.with(|last| *last.borrow_mut() = Some(stats));
+    drop(guard);
+    arm_timer();
+}
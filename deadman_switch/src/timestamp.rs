@@ -0,0 +1,174 @@
+//! Typed time values.
+//!
+//! `ic_cdk::api::time()` reports nanoseconds since the Unix epoch while every
+//! user-facing duration (timeouts, contestation periods) is configured in seconds.
+//! Keeping both as bare `u64` made it easy to add one to the other, so points in
+//! time are `Timestamp`s and spans are `DurationSecs`; the only way to combine them
+//! goes through the conversions below.
+//!
+//! Both are Candid newtypes and encode exactly like the `nat64` they wrap, so the
+//! stable-memory layout and the public interface are unchanged.
+
+use candid::{CandidType, Deserialize};
+use serde::Serialize;
+#[cfg(test)]
+use std::cell::Cell;
+
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+
+/// A point in time, in nanoseconds since the Unix epoch (the unit of `ic_cdk::api::time()`)
+#[derive(
+    CandidType, Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash,
+)]
+pub struct Timestamp(u64);
+
+impl Timestamp {
+    /// Used as "never" for retries that need manual review
+    pub const MAX: Timestamp = Timestamp(u64::MAX);
+
+    /// Current IC time
+    #[cfg(not(test))]
+    pub fn now() -> Self {
+        Timestamp(ic_cdk::api::time())
+    }
+
+    /// Unit tests run outside a canister and read the clock `set_test_time` sets
+    #[cfg(test)]
+    pub fn now() -> Self {
+        TEST_TIME.with(Cell::get)
+    }
+
+    pub const fn from_nanos(nanos: u64) -> Self {
+        Timestamp(nanos)
+    }
+
+    pub const fn as_nanos(self) -> u64 {
+        self.0
+    }
+
+    /// The point `duration` after this one, clamped at `Timestamp::MAX`
+    pub fn saturating_add(self, duration: DurationSecs) -> Self {
+        Timestamp(self.0.saturating_add(duration.as_nanos()))
+    }
+
+    /// Whole seconds elapsed from `earlier` to this point, zero if `earlier` is later
+    pub fn saturating_duration_since(self, earlier: Timestamp) -> DurationSecs {
+        DurationSecs(self.0.saturating_sub(earlier.0) / NANOS_PER_SECOND)
+    }
+}
+
+#[cfg(test)]
+thread_local! {
+    static TEST_TIME: Cell<Timestamp> = const { Cell::new(Timestamp(0)) };
+}
+
+/// Set what `Timestamp::now` returns in unit tests
+#[cfg(test)]
+pub fn set_test_time(time: Timestamp) {
+    TEST_TIME.with(|t| t.set(time));
+}
+
+/// A span of time in whole seconds, the unit all user settings are expressed in
+#[derive(
+    CandidType, Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash,
+)]
+pub struct DurationSecs(u64);
+
+impl DurationSecs {
+    pub const ZERO: DurationSecs = DurationSecs(0);
+
+    pub const fn from_secs(secs: u64) -> Self {
+        DurationSecs(secs)
+    }
+
+    pub const fn from_minutes(minutes: u64) -> Self {
+        DurationSecs(minutes * 60)
+    }
+
+    pub const fn from_hours(hours: u64) -> Self {
+        DurationSecs(hours * 60 * 60)
+    }
+
+    pub const fn from_days(days: u64) -> Self {
+        DurationSecs(days * 24 * 60 * 60)
+    }
+
+    pub const fn as_secs(self) -> u64 {
+        self.0
+    }
+
+    /// The same span in nanoseconds, clamped at `u64::MAX` (about 584 years)
+    pub const fn as_nanos(self) -> u64 {
+        self.0.saturating_mul(NANOS_PER_SECOND)
+    }
+
+    pub fn saturating_add(self, other: DurationSecs) -> Self {
+        DurationSecs(self.0.saturating_add(other.0))
+    }
+
+    pub fn saturating_sub(self, other: DurationSecs) -> Self {
+        DurationSecs(self.0.saturating_sub(other.0))
+    }
+
+    pub fn saturating_mul(self, factor: u64) -> Self {
+        DurationSecs(self.0.saturating_mul(factor))
+    }
+}
+
+impl From<DurationSecs> for std::time::Duration {
+    fn from(duration: DurationSecs) -> Self {
+        std::time::Duration::from_secs(duration.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candid::types::{Type, TypeInner};
+    use candid::{decode_one, encode_one};
+
+    #[test]
+    fn seconds_round_trip_through_nanoseconds() {
+        let start = Timestamp::from_nanos(1_700_000_000 * NANOS_PER_SECOND + 123);
+        let timeout = DurationSecs::from_days(30);
+
+        let deadline = start.saturating_add(timeout);
+        assert_eq!(deadline.as_nanos(), start.as_nanos() + timeout.as_secs() * NANOS_PER_SECOND);
+        assert_eq!(deadline.saturating_duration_since(start), timeout);
+        assert_eq!(timeout.as_nanos() / NANOS_PER_SECOND, timeout.as_secs());
+    }
+
+    #[test]
+    fn partial_seconds_are_truncated_and_earlier_points_give_zero() {
+        let earlier = Timestamp::from_nanos(10 * NANOS_PER_SECOND);
+        let later = Timestamp::from_nanos(12 * NANOS_PER_SECOND - 1);
+        assert_eq!(later.saturating_duration_since(earlier), DurationSecs::from_secs(1));
+        assert_eq!(earlier.saturating_duration_since(later), DurationSecs::ZERO);
+    }
+
+    #[test]
+    fn saturating_add_stops_at_max() {
+        assert_eq!(Timestamp::MAX.saturating_add(DurationSecs::from_secs(1)), Timestamp::MAX);
+        assert_eq!(Timestamp::MAX.saturating_add(DurationSecs::ZERO), Timestamp::MAX);
+        assert_eq!(
+            Timestamp::from_nanos(1).saturating_add(DurationSecs::from_secs(u64::MAX)),
+            Timestamp::MAX
+        );
+        assert_eq!(DurationSecs::from_secs(u64::MAX).as_nanos(), u64::MAX);
+    }
+
+    #[test]
+    fn candid_encoding_is_a_plain_nat64() {
+        assert_eq!(Timestamp::ty(), Type::from(TypeInner::Nat64));
+        assert_eq!(DurationSecs::ty(), Type::from(TypeInner::Nat64));
+
+        let nanos = 1_700_000_000_123_456_789u64;
+        assert_eq!(encode_one(Timestamp::from_nanos(nanos)).unwrap(), encode_one(nanos).unwrap());
+        assert_eq!(encode_one(DurationSecs::from_secs(600)).unwrap(), encode_one(600u64).unwrap());
+        // Values stored as bare nat64 by older builds still decode
+        assert_eq!(
+            decode_one::<Timestamp>(&encode_one(nanos).unwrap()).unwrap(),
+            Timestamp::from_nanos(nanos)
+        );
+    }
+}
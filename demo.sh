@@ -34,12 +34,17 @@ echo "4️⃣  Creating canister (if needed)..."
 dfx canister create deadman_switch 2>/dev/null || echo "   Canister already exists, continuing..."
 
 echo ""
-echo "5️⃣  Building canister..."
-dfx build deadman_switch
+echo "5️⃣  Building canisters (dead man switch with the demo feature)..."
+cargo build --target wasm32-unknown-unknown --package deadman_switch --release --features demo
 
 echo ""
 echo "6️⃣  Deploying canisters..."
-dfx deploy deadman_switch --no-wallet
+dfx deploy mock_ckbtc_ledger --no-wallet
+MOCK_LEDGER_ID=$(dfx canister id mock_ckbtc_ledger)
+echo "   Mock ckBTC ledger: $MOCK_LEDGER_ID"
+dfx canister install deadman_switch --mode auto --yes \
+    --wasm target/wasm32-unknown-unknown/release/deadman_switch.wasm \
+    --argument "(opt record { ckbtc_ledger_canister_id = opt principal \"$MOCK_LEDGER_ID\"; ckbtc_index_canister_id = opt principal \"$MOCK_LEDGER_ID\"; features = opt record { allowance_mode = true; trusted_party_cancellation = true; mock_balance = true } })"
 dfx deploy internet_identity --no-wallet 2>/dev/null || echo "   Internet Identity already deployed"
 
 echo ""
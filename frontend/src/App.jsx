@@ -18,6 +18,7 @@ import RegistrationForm from './components/RegistrationForm';
 import HeartbeatButton from './components/HeartbeatButton';
 import AccountDashboard from './components/AccountDashboard';
 import TransactionHistory from './components/TransactionHistory';
+import { eventAmount, eventCategory, eventDetails, eventLabel, fetchHistory } from './utils/historyUtils';
 import './App.css';
 import './components.css';
 
@@ -57,6 +58,7 @@ function App() {
   const [priceError, setPriceError] = useState(null);
   const [priceSource, setPriceSource] = useState(null);
   const [transactionHistory, setTransactionHistory] = useState([]);
+  const [mockBalanceEnabled, setMockBalanceEnabled] = useState(false);
   const [showUpdateSettings, setShowUpdateSettings] = useState(false);
   const [showWithdraw, setShowWithdraw] = useState(false);
   const [withdrawAmount, setWithdrawAmount] = useState('');
@@ -620,11 +622,13 @@ function App() {
         generateCanisterAddress();
         // Load wallet assets
         loadWalletAssets();
+        // Offer mock balance top-ups only where the canister enables them
+        loadMockBalanceEnabled(actor);
         // Load transaction history
         try {
-          const historyResult = await actor.get_transaction_history();
+          const historyResult = await fetchHistory(actor);
           if ('ok' in historyResult) {
-            setTransactionHistory(historyResult.ok);
+            setTransactionHistory(historyResult.ok); // Newest first
           }
         } catch (e) {
           console.warn('Could not load transaction history:', e);
@@ -641,6 +645,16 @@ function App() {
     }
   };
 
+  const loadMockBalanceEnabled = async (actor) => {
+    try {
+      const config = await actor.get_config();
+      setMockBalanceEnabled(config.features.mock_balance);
+    } catch (error) {
+      console.error('Error loading canister config:', error);
+      setMockBalanceEnabled(false);
+    }
+  };
+
   const loadLedgerBalance = async (actor) => {
     try {
       const result = await actor.get_ckbtc_balance();
@@ -889,7 +903,8 @@ function App() {
       
       const result = await actor.register({
         timeout_duration_seconds: BigInt(registerForm.timeoutDuration),
-        beneficiary: beneficiaryPrincipal
+        beneficiary: beneficiaryPrincipal,
+        beneficiary_subaccount: []
       });
       
       if ('ok' in result) {
@@ -897,14 +912,15 @@ function App() {
         await loadAccountInfo();
       } else {
         // Improve error messages
-        const errorMsg = result.err || 'Registration failed';
-        let userFriendlyMsg = errorMsg;
-        if (errorMsg.includes('already registered')) {
+        const err = result.err;
+        const field = err.InvalidArgument?.field ?? '';
+        let userFriendlyMsg = formatError(err);
+        if ('AlreadyRegistered' in err) {
           userFriendlyMsg = 'You are already registered. If you need to change your settings, please contact support.';
-        } else if (errorMsg.includes('timeout')) {
+        } else if (field.includes('timeout')) {
           userFriendlyMsg = 'Invalid timeout duration. Please ensure it is at least 60 seconds.';
-        } else if (errorMsg.includes('beneficiary')) {
-          userFriendlyMsg = 'Invalid beneficiary principal. Please check the format and try again.';
+        } else if (field.includes('beneficiar')) {
+          userFriendlyMsg = `Invalid beneficiary: ${err.InvalidArgument.reason}`;
         }
         showMessage(userFriendlyMsg, 'error');
       }
@@ -950,7 +966,7 @@ function App() {
         showMessage(`Heartbeat sent! Next due: ${new Date(nextDue).toLocaleString()}`, 'success');
         await loadAccountInfo();
       } else {
-        showMessage(result.err, 'error');
+        showMessage(formatError(result.err), 'error');
       }
     } catch (error) {
       showMessage(`Heartbeat failed: ${error.message}`, 'error');
@@ -973,7 +989,7 @@ function App() {
         setDepositAmount('');
         await loadAccountInfo();
       } else {
-        showMessage(result.err, 'error');
+        showMessage(formatError(result.err), 'error');
       }
     } catch (error) {
       const errorMsg = formatError(error);
@@ -990,20 +1006,21 @@ function App() {
       const identity = await getIdentity();
       const actor = await createActor(identity);
       
-      const timeout = newTimeout ? BigInt(newTimeout) : null;
-      let beneficiaryPrincipal = null;
+      // Candid options: [] leaves the setting unchanged
+      const timeout = newTimeout ? [BigInt(newTimeout)] : [];
+      let beneficiaryPrincipal = [];
       if (newBeneficiary) {
         const { Principal } = await import('@dfinity/principal');
-        beneficiaryPrincipal = Principal.fromText(newBeneficiary.trim());
+        beneficiaryPrincipal = [Principal.fromText(newBeneficiary.trim())];
       }
 
-      const result = await actor.update_settings(timeout, beneficiaryPrincipal);
+      const result = await actor.update_settings(timeout, beneficiaryPrincipal, []);
       if ('ok' in result) {
         showMessage(result.ok, 'success');
         await loadAccountInfo();
         setShowUpdateSettings(false);
       } else {
-        showMessage(result.err, 'error');
+        showMessage(formatError(result.err), 'error');
       }
     } catch (error) {
       showMessage(`Update failed: ${error.message}`, 'error');
@@ -1023,7 +1040,7 @@ function App() {
       const amount = BigInt(withdrawAmount);
       const toPrincipal = Principal.fromText(withdrawTo.trim());
       
-      const result = await actor.withdraw(amount, toPrincipal);
+      const result = await actor.withdraw(amount, { owner: toPrincipal, subaccount: [] });
       if ('ok' in result) {
         showMessage(result.ok, 'success');
         setWithdrawAmount('');
@@ -1031,7 +1048,7 @@ function App() {
         setShowWithdraw(false);
         await loadAccountInfo();
       } else {
-        showMessage(result.err, 'error');
+        showMessage(formatError(result.err), 'error');
       }
     } catch (error) {
       showMessage(`Withdrawal failed: ${error.message}`, 'error');
@@ -1842,15 +1859,15 @@ function App() {
                       
                       const result = await actor.register({
                         timeout_duration_seconds: BigInt(formData.timeoutDuration),
-                        beneficiary: beneficiaryPrincipal
+                        beneficiary: beneficiaryPrincipal,
+                        beneficiary_subaccount: []
                       });
                       
                       if ('ok' in result) {
                         showMessage(result.ok, 'success');
                         await loadAccountInfo();
                       } else {
-                        const errorMsg = result.err || 'Registration failed';
-                        showMessage(errorMsg, 'error');
+                        showMessage(formatError(result.err), 'error');
                       }
                     } catch (error) {
                       let errorMsg = error.message || 'Registration failed';
@@ -2017,7 +2034,7 @@ function App() {
                       await loadAccountInfo();
                       return result.ok;
                     } else {
-                      throw new Error(result.err);
+                      throw new Error(formatError(result.err));
                     }
                   }}
                   accountInfo={accountInfo}
@@ -2034,7 +2051,7 @@ function App() {
                   onUpdateSettings={() => setShowUpdateSettings(true)}
                   onWithdraw={() => setShowWithdraw(true)}
                   showMessage={showMessage}
-                  onSetMockBalance={async () => {
+                  onSetMockBalance={mockBalanceEnabled ? async () => {
                     const currentBalance = accountInfo?.balance ? Number(accountInfo.balance) / 100_000_000 : 0;
                     const amount = prompt(`Enter amount to ADD in ckBTC (e.g., 0.5 to add 0.5 ckBTC):\n\nCurrent balance: ${currentBalance.toFixed(8)} ckBTC`, '0.5');
                     if (!amount || isNaN(parseFloat(amount)) || parseFloat(amount) <= 0) {
@@ -2051,14 +2068,14 @@ function App() {
                         showMessage(result.ok, 'success');
                         await loadAccountInfo(identity);
                       } else {
-                        showMessage(`Failed: ${result.err}`, 'error');
+                        showMessage(`Failed: ${formatError(result.err)}`, 'error');
                       }
                     } catch (error) {
                       showMessage(`Error: ${error.message}`, 'error');
                     } finally {
                       setLoading(false);
                     }
-                  }}
+                  } : undefined}
                 />
               </div>
 
@@ -2069,7 +2086,7 @@ function App() {
                   onLoadHistory={async () => {
                     const identity = await getIdentity();
                     const actor = await createActor(identity);
-                    return await actor.get_transaction_history();
+                    return await fetchHistory(actor);
                   }}
                 />
               </div>
@@ -2202,25 +2219,25 @@ function App() {
                     <div className="stat-item">
                       <div className="stat-label">Total Heartbeats</div>
                       <div className="stat-value">
-                        {transactionHistory.filter(tx => tx.transaction_type === 'heartbeat').length}
+                        {transactionHistory.filter(tx => eventCategory(tx) === 'heartbeat').length}
                       </div>
                     </div>
                     <div className="stat-item">
                       <div className="stat-label">Total Deposits</div>
                       <div className="stat-value">
-                        {transactionHistory.filter(tx => tx.transaction_type === 'deposit').length}
+                        {transactionHistory.filter(tx => eventCategory(tx) === 'deposit').length}
                       </div>
                     </div>
                     <div className="stat-item">
                       <div className="stat-label">Days Active</div>
                       <div className="stat-value">
-                        {transactionHistory.length > 0 ? Math.ceil((Number(transactionHistory[transactionHistory.length - 1].timestamp) - Number(transactionHistory[0].timestamp)) / 1_000_000 / 86400) : 0}
+                        {transactionHistory.length > 0 ? Math.ceil((Number(transactionHistory[0].timestamp) - Number(transactionHistory[transactionHistory.length - 1].timestamp)) / 1_000_000 / 86400) : 0}
                       </div>
                     </div>
                     <div className="stat-item">
                       <div className="stat-label">Last Activity</div>
                       <div className="stat-value" style={{ fontSize: '0.85rem' }}>
-                        {transactionHistory.length > 0 ? new Date(Number(transactionHistory[transactionHistory.length - 1].timestamp) / 1_000_000).toLocaleDateString() : 'Never'}
+                        {transactionHistory.length > 0 ? new Date(Number(transactionHistory[0].timestamp) / 1_000_000).toLocaleDateString() : 'Never'}
                       </div>
                     </div>
                   </div>
@@ -2231,34 +2248,38 @@ function App() {
                 <h2>Transaction History</h2>
                 {transactionHistory && transactionHistory.length > 0 ? (
                   <div className="transaction-history">
-                    {transactionHistory.slice().reverse().map((tx, index) => (
-                      <div key={index} className="transaction-item">
-                        <div className="transaction-header">
-                          <span className={`transaction-type transaction-${tx.transaction_type}`}>
-                            {tx.transaction_type === 'heartbeat' ? '💓' : 
-                             tx.transaction_type === 'deposit' ? '💰' :
-                             tx.transaction_type === 'withdrawal' ? '💸' :
-                             tx.transaction_type === 'transfer' ? '🔄' :
-                             tx.transaction_type === 'update' ? '⚙️' : '📝'}
-                            {tx.transaction_type.charAt(0).toUpperCase() + tx.transaction_type.slice(1)}
-                          </span>
-                          <span className="transaction-time">
-                            {new Date(Number(tx.timestamp) / 1_000_000).toLocaleString()}
-                          </span>
-                        </div>
-                        {tx.amount && (
-                          <div className="transaction-amount">
-                            {formatBalance(tx.amount)} ckBTC
-                            {ckbtcPrice?.price && (
-                              <span style={{ marginLeft: '8px', color: '#999', fontSize: '0.85rem' }}>
-                                (${((Number(tx.amount) / 100_000_000) * ckbtcPrice.price).toFixed(2)})
-                              </span>
-                            )}
+                    {transactionHistory.map((tx, index) => {
+                      const category = eventCategory(tx);
+                      const amount = eventAmount(tx);
+                      return (
+                        <div key={index} className="transaction-item">
+                          <div className="transaction-header">
+                            <span className={`transaction-type transaction-${category}`}>
+                              {category === 'heartbeat' ? '💓' : 
+                               category === 'deposit' ? '💰' :
+                               category === 'withdrawal' ? '💸' :
+                               category === 'transfer' ? '🔄' :
+                               category === 'update' ? '⚙️' : '📝'}
+                              {eventLabel(tx)}
+                            </span>
+                            <span className="transaction-time">
+                              {new Date(Number(tx.timestamp) / 1_000_000).toLocaleString()}
+                            </span>
                           </div>
-                        )}
-                        <div className="transaction-details">{tx.details}</div>
-                      </div>
-                    ))}
+                          {amount !== null && (
+                            <div className="transaction-amount">
+                              {formatBalance(amount)} ckBTC
+                              {ckbtcPrice?.price && (
+                                <span style={{ marginLeft: '8px', color: '#999', fontSize: '0.85rem' }}>
+                                  (${((Number(amount) / 100_000_000) * ckbtcPrice.price).toFixed(2)})
+                                </span>
+                              )}
+                            </div>
+                          )}
+                          <div className="transaction-details">{eventDetails(tx)}</div>
+                        </div>
+                      );
+                    })}
                   </div>
                 ) : (
                   <div style={{ padding: '20px', textAlign: 'center', color: '#999' }}>
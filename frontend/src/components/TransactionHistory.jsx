@@ -1,4 +1,5 @@
 import React, { useState, useEffect } from 'react';
+import { eventAmount, eventCategory, eventDetails, eventLabel } from '../utils/historyUtils';
 
 /**
  * TransactionHistory Component - Displays transaction history
@@ -11,11 +12,7 @@ export default function TransactionHistory({
   const [history, setHistory] = useState([]);
 
   useEffect(() => {
-    if (accountInfo && accountInfo.transaction_history) {
-      setHistory([...accountInfo.transaction_history].reverse()); // Most recent first
-    } else {
-      loadHistory();
-    }
+    loadHistory();
   }, [accountInfo]);
 
   const loadHistory = async () => {
@@ -25,7 +22,7 @@ export default function TransactionHistory({
     try {
       const result = await onLoadHistory();
       if (result && result.ok) {
-        setHistory([...result.ok].reverse()); // Most recent first
+        setHistory(result.ok); // Most recent first
       }
     } catch (error) {
       console.error('Failed to load transaction history:', error);
@@ -53,6 +50,7 @@ export default function TransactionHistory({
       'deposit': '💰',
       'withdrawal': '💸',
       'transfer': '🔄',
+      'timeout': '⏰',
       'timeout_cancelled': '❌',
       'update': '⚙️',
       'balance_sync': '🔄'
@@ -67,6 +65,7 @@ export default function TransactionHistory({
       'deposit': '#10b981',
       'withdrawal': '#ef4444',
       'transfer': '#8b5cf6',
+      'timeout': '#ef4444',
       'timeout_cancelled': '#f59e0b',
       'update': '#6366f1',
       'balance_sync': '#6b7280'
@@ -115,46 +114,50 @@ export default function TransactionHistory({
         </div>
       ) : (
         <div className="transaction-list">
-          {history.map((tx, index) => (
-            <div 
-              key={index} 
-              className="transaction-item"
-              style={{
-                borderLeft: `4px solid ${getTransactionColor(tx.transaction_type)}`
-              }}
-            >
-              <div className="transaction-header">
-                <div className="transaction-type">
-                  <span style={{ fontSize: '1.2rem', marginRight: '8px' }}>
-                    {getTransactionIcon(tx.transaction_type)}
-                  </span>
-                  <span style={{ 
-                    textTransform: 'capitalize',
-                    fontWeight: '600',
-                    color: getTransactionColor(tx.transaction_type)
-                  }}>
-                    {tx.transaction_type.replace(/_/g, ' ')}
-                  </span>
-                </div>
-                {tx.amount && (
-                  <div className="transaction-amount" style={{ fontWeight: '600' }}>
-                    {formatAmount(tx.amount)} ckBTC
+          {history.map((tx, index) => {
+            const category = eventCategory(tx);
+            const amount = eventAmount(tx);
+            const details = eventDetails(tx);
+            return (
+              <div 
+                key={index} 
+                className="transaction-item"
+                style={{
+                  borderLeft: `4px solid ${getTransactionColor(category)}`
+                }}
+              >
+                <div className="transaction-header">
+                  <div className="transaction-type">
+                    <span style={{ fontSize: '1.2rem', marginRight: '8px' }}>
+                      {getTransactionIcon(category)}
+                    </span>
+                    <span style={{ 
+                      fontWeight: '600',
+                      color: getTransactionColor(category)
+                    }}>
+                      {eventLabel(tx)}
+                    </span>
                   </div>
-                )}
-              </div>
-              
-              <div className="transaction-details">
-                <div className="transaction-date">
-                  {formatDate(tx.timestamp)}
+                  {amount !== null && (
+                    <div className="transaction-amount" style={{ fontWeight: '600' }}>
+                      {formatAmount(amount)} ckBTC
+                    </div>
+                  )}
                 </div>
-                {tx.details && (
-                  <div className="transaction-description">
-                    {tx.details}
+              
+                <div className="transaction-details">
+                  <div className="transaction-date">
+                    {formatDate(tx.timestamp)}
                   </div>
-                )}
+                  {details && (
+                    <div className="transaction-description">
+                      {details}
+                    </div>
+                  )}
+                </div>
               </div>
-            </div>
-          ))}
+            );
+          })}
         </div>
       )}
 
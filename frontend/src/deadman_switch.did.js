@@ -1,48 +1,625 @@
-// This file will be generated from the Candid file
-// For now, we'll create a basic IDL factory
 export const idlFactory = ({ IDL }) => {
-  const RegisterArgs = IDL.Record({
-    'timeout_duration_seconds' : IDL.Nat64,
-    'beneficiary' : IDL.Principal,
-  });
-  const HeartbeatResponse = IDL.Record({
-    'success' : IDL.Bool,
-    'message' : IDL.Text,
-    'next_heartbeat_due' : IDL.Nat64,
+  const ArchivedBlocks = IDL.Rec();
+  const GetBlocksResult = IDL.Rec();
+  const ICRC3Value = IDL.Rec();
+  const Account = IDL.Record({
+    'owner' : IDL.Principal,
+    'subaccount' : IDL.Opt(IDL.Vec(IDL.Nat8)),
   });
-  const TransactionLog = IDL.Record({
-    'timestamp' : IDL.Nat64,
-    'transaction_type' : IDL.Text,
-    'amount' : IDL.Opt(IDL.Nat64),
-    'details' : IDL.Text,
+  const AccountBalances = IDL.Record({
+    'owner' : IDL.Principal,
+    'tracked' : IDL.Nat64,
+    'ledger' : IDL.Nat64,
   });
   const Beneficiary = IDL.Record({
     'beneficiary_principal' : IDL.Principal,
-    'percentage' : IDL.Nat8,
     'subaccount' : IDL.Opt(IDL.Vec(IDL.Nat8)),
+    'percentage' : IDL.Nat8,
+  });
+  const CustodyMode = IDL.Variant({
+    'Allowance' : IDL.Null,
+    'Custodial' : IDL.Null,
+  });
+  const AccountEvent = IDL.Variant({
+    'BeneficiariesChanged' : IDL.Record({
+      'new' : IDL.Vec(Beneficiary),
+      'old' : IDL.Vec(Beneficiary),
+    }),
+    'CustodyModeChanged' : IDL.Record({
+      'new' : CustodyMode,
+      'old' : CustodyMode,
+    }),
+    'TimeoutDetected' : IDL.Record({
+      'contestation_period' : IDL.Nat64,
+    }),
+    'TrustedPartyRemoved' : IDL.Record({
+      'principal' : IDL.Principal,
+    }),
+    'TimeoutCancelled' : IDL.Record({
+      'by' : IDL.Principal,
+    }),
+    'Heartbeat' : IDL.Record({
+      'next_due' : IDL.Nat64,
+    }),
+    'PayoutStarted' : IDL.Record({
+      'transfers' : IDL.Nat32,
+      'amount' : IDL.Nat64,
+    }),
+    'PayoutTransfer' : IDL.Record({
+      'to' : Account,
+      'fee' : IDL.Nat64,
+      'block_index' : IDL.Nat64,
+      'amount' : IDL.Nat64,
+    }),
+    'BalanceSynced' : IDL.Record({
+      'previous' : IDL.Nat64,
+      'current' : IDL.Nat64,
+    }),
+    'BalanceDriftResolved' : IDL.Record({
+      'tracked' : IDL.Nat64,
+      'ledger' : IDL.Nat64,
+    }),
+    'LegacyBalanceMoved' : IDL.Record({
+      'block_index' : IDL.Opt(IDL.Nat64),
+      'legacy' : IDL.Nat64,
+      'credited' : IDL.Nat64,
+    }),
+    'DepositReceived' : IDL.Record({
+      'block_index' : IDL.Nat64,
+      'from' : IDL.Opt(Account),
+      'amount' : IDL.Nat64,
+      'credited' : IDL.Nat64,
+    }),
+    'TrustedPartyAdded' : IDL.Record({
+      'principal' : IDL.Principal,
+    }),
+    'Withdrawal' : IDL.Record({
+      'to' : Account,
+      'fee' : IDL.Nat64,
+      'block_index' : IDL.Nat64,
+      'amount' : IDL.Nat64,
+    }),
+    'ContestationPeriodChanged' : IDL.Record({
+      'new' : IDL.Nat64,
+      'old' : IDL.Nat64,
+    }),
+    'TimeoutChanged' : IDL.Record({
+      'new' : IDL.Nat64,
+      'old' : IDL.Nat64,
+    }),
+    'PayoutTransferFailed' : IDL.Record({
+      'to' : Account,
+      'attempt' : IDL.Nat32,
+      'error' : IDL.Text,
+      'amount' : IDL.Nat64,
+    }),
+    'Legacy' : IDL.Record({
+      'transaction_type' : IDL.Text,
+      'details' : IDL.Text,
+      'amount' : IDL.Opt(IDL.Nat64),
+    }),
+    'Registered' : IDL.Record({
+      'beneficiary' : Account,
+      'timeout' : IDL.Nat64,
+    }),
+    'BalanceDriftDetected' : IDL.Record({
+      'tracked' : IDL.Nat64,
+      'ledger' : IDL.Nat64,
+    }),
+  });
+  const TransferError = IDL.Variant({
+    'GenericError' : IDL.Record({
+      'message' : IDL.Text,
+      'error_code' : IDL.Nat,
+    }),
+    'TemporarilyUnavailable' : IDL.Null,
+    'BadBurn' : IDL.Record({
+      'min_burn_amount' : IDL.Nat,
+    }),
+    'Duplicate' : IDL.Record({
+      'duplicate_of' : IDL.Nat,
+    }),
+    'BadFee' : IDL.Record({
+      'expected_fee' : IDL.Nat,
+    }),
+    'CreatedInFuture' : IDL.Record({
+      'ledger_time' : IDL.Nat64,
+    }),
+    'TooOld' : IDL.Null,
+    'InsufficientFunds' : IDL.Record({
+      'balance' : IDL.Nat,
+    }),
+  });
+  const TransferFromError = IDL.Variant({
+    'GenericError' : IDL.Record({
+      'message' : IDL.Text,
+      'error_code' : IDL.Nat,
+    }),
+    'TemporarilyUnavailable' : IDL.Null,
+    'InsufficientAllowance' : IDL.Record({
+      'allowance' : IDL.Nat,
+    }),
+    'BadBurn' : IDL.Record({
+      'min_burn_amount' : IDL.Nat,
+    }),
+    'Duplicate' : IDL.Record({
+      'duplicate_of' : IDL.Nat,
+    }),
+    'BadFee' : IDL.Record({
+      'expected_fee' : IDL.Nat,
+    }),
+    'CreatedInFuture' : IDL.Record({
+      'ledger_time' : IDL.Nat64,
+    }),
+    'TooOld' : IDL.Null,
+    'InsufficientFunds' : IDL.Record({
+      'balance' : IDL.Nat,
+    }),
+  });
+  const LedgerError = IDL.Variant({
+    'CallFailed' : IDL.Text,
+    'Rejected' : IDL.Record({
+      'code' : IDL.Nat32,
+      'clean' : IDL.Bool,
+      'message' : IDL.Text,
+    }),
+    'Decode' : IDL.Text,
+    'Transfer' : TransferError,
+    'TransferFrom' : TransferFromError,
+  });
+  const PauseScope = IDL.Variant({
+    'Registrations' : IDL.Null,
+    'Withdrawals' : IDL.Null,
+    'Heartbeats' : IDL.Null,
+    'Payouts' : IDL.Null,
+  });
+  const DeadManError = IDL.Variant({
+    'Internal' : IDL.Record({
+      'message' : IDL.Text,
+    }),
+    'WrongCustodyMode' : IDL.Record({
+      'mode' : CustodyMode,
+    }),
+    'NotRegistered' : IDL.Null,
+    'Paused' : IDL.Record({
+      'scope' : PauseScope,
+      'reason' : IDL.Text,
+    }),
+    'Busy' : IDL.Null,
+    'LedgerOutcomeUnknown' : LedgerError,
+    'PayoutInProgress' : IDL.Null,
+    'AlreadyRegistered' : IDL.Null,
+    'InsufficientBalance' : IDL.Record({
+      'available' : IDL.Nat64,
+      'required' : IDL.Nat64,
+    }),
+    'NotFound' : IDL.Record({
+      'what' : IDL.Text,
+    }),
+    'Unauthorized' : IDL.Record({
+      'reason' : IDL.Text,
+    }),
+    'InvalidArgument' : IDL.Record({
+      'field' : IDL.Text,
+      'reason' : IDL.Text,
+    }),
+    'Ledger' : LedgerError,
+    'FeatureDisabled' : IDL.Record({
+      'feature' : IDL.Text,
+    }),
+    'Conflict' : IDL.Record({
+      'reason' : IDL.Text,
+    }),
+  });
+  const AllowanceStatus = IDL.Record({
+    'owner_balance' : IDL.Nat64,
+    'allowance' : IDL.Nat64,
+    'expires_at' : IDL.Opt(IDL.Nat64),
+    'checked_at' : IDL.Nat64,
+  });
+  const BalanceDrift = IDL.Record({
+    'detected_at' : IDL.Nat64,
+    'tracked' : IDL.Nat64,
+    'ledger' : IDL.Nat64,
+  });
+  const DepositScan = IDL.Record({
+    'newest' : IDL.Nat64,
+    'below' : IDL.Nat64,
+  });
+  const UnsettledTransfer = IDL.Record({
+    'fee' : IDL.Nat64,
+    'created_at_time' : IDL.Nat64,
+  });
+  const LegacyBalance = IDL.Record({
+    'unsettled' : IDL.Opt(UnsettledTransfer),
+    'share' : IDL.Opt(IDL.Nat64),
+    'amount' : IDL.Nat64,
+  });
+  const PayoutLegStatus = IDL.Variant({
+    'OutcomeUnknown' : IDL.Record({
+      'error' : IDL.Text,
+      'next_retry_at' : IDL.Nat64,
+    }),
+    'Failed' : IDL.Record({
+      'error' : IDL.Text,
+      'next_retry_at' : IDL.Nat64,
+    }),
+    'Paid' : IDL.Record({
+      'block_index' : IDL.Nat64,
+    }),
+    'InFlight' : IDL.Record({
+      'started_at' : IDL.Nat64,
+    }),
+    'Pending' : IDL.Null,
+  });
+  const PayoutLeg = IDL.Record({
+    'to' : Account,
+    'fee' : IDL.Nat64,
+    'status' : PayoutLegStatus,
+    'memo' : IDL.Opt(IDL.Vec(IDL.Nat8)),
+    'attempts' : IDL.Nat32,
+    'created_at_time' : IDL.Opt(IDL.Nat64),
+    'amount' : IDL.Nat64,
+  });
+  const PayoutPlan = IDL.Record({
+    'legs' : IDL.Vec(PayoutLeg),
+    'created_at' : IDL.Nat64,
+    'custody_mode' : CustodyMode,
+  });
+  const PendingWithdrawal = IDL.Record({
+    'to' : Account,
+    'fee' : IDL.Nat64,
+    'memo' : IDL.Vec(IDL.Nat8),
+    'created_at_time' : IDL.Nat64,
+    'amount' : IDL.Nat64,
   });
   const UserAccount = IDL.Record({
     'user_principal' : IDL.Principal,
-    'last_heartbeat' : IDL.Nat64,
+    'balance' : IDL.Nat64,
+    'pending_withdrawal' : IDL.Opt(PendingWithdrawal),
     'timeout_duration_seconds' : IDL.Nat64,
     'beneficiary' : IDL.Principal,
+    'deposit_scan' : IDL.Opt(DepositScan),
+    'last_deposit_block' : IDL.Opt(IDL.Nat64),
+    'legacy_balance' : IDL.Opt(LegacyBalance),
+    'trusted_parties' : IDL.Vec(IDL.Principal),
+    'last_heartbeat' : IDL.Nat64,
+    'contestation_period_seconds' : IDL.Nat64,
     'beneficiaries' : IDL.Vec(Beneficiary),
+    'custody_mode' : CustodyMode,
+    'allowance' : IDL.Opt(AllowanceStatus),
+    'balance_drift' : IDL.Opt(BalanceDrift),
+    'payout' : IDL.Opt(PayoutPlan),
+    'timeout_detected_at' : IDL.Opt(IDL.Nat64),
+  });
+  const AccountInfoResult = IDL.Variant({
+    'ok' : UserAccount,
+    'err' : DeadManError,
+  });
+  const ActivePause = IDL.Record({
+    'by' : IDL.Principal,
+    'since' : IDL.Nat64,
+    'reason' : IDL.Text,
+  });
+  const AllowanceResult = IDL.Variant({
+    'ok' : AllowanceStatus,
+    'err' : DeadManError,
+  });
+  const GetBlocksRequest = IDL.Record({
+    'start' : IDL.Nat,
+    'length' : IDL.Nat,
+  });
+  ICRC3Value.fill(IDL.Variant({
+    'Int' : IDL.Int,
+    'Map' : IDL.Vec(IDL.Tuple(IDL.Text, ICRC3Value)),
+    'Nat' : IDL.Nat,
+    'Blob' : IDL.Vec(IDL.Nat8),
+    'Text' : IDL.Text,
+    'Array' : IDL.Vec(ICRC3Value),
+  }));
+  const BlockWithId = IDL.Record({
+    'id' : IDL.Nat,
+    'block' : ICRC3Value,
+  });
+  GetBlocksResult.fill(IDL.Record({
+    'log_length' : IDL.Nat,
+    'blocks' : IDL.Vec(BlockWithId),
+    'archived_blocks' : IDL.Vec(ArchivedBlocks),
+  }));
+  ArchivedBlocks.fill(IDL.Record({
+    'args' : IDL.Vec(GetBlocksRequest),
+    'callback' : IDL.Func([IDL.Vec(GetBlocksRequest)], [GetBlocksResult], ['query']),
+  }));
+  const BalanceResult = IDL.Variant({
+    'ok' : IDL.Nat64,
+    'err' : DeadManError,
+  });
+  const FeatureFlags = IDL.Record({
+    'allowance_mode' : IDL.Bool,
+    'trusted_party_cancellation' : IDL.Bool,
+    'mock_balance' : IDL.Bool,
+  });
+  const CanisterConfig = IDL.Record({
+    'features' : FeatureFlags,
+    'history_retention' : IDL.Opt(IDL.Nat64),
+    'check_interval' : IDL.Nat64,
+    'ckbtc_minter' : IDL.Opt(IDL.Principal),
+    'default_contestation_period' : IDL.Nat64,
+    'ckbtc_ledger' : IDL.Principal,
+    'history_max_entries' : IDL.Opt(IDL.Nat64),
+    'ckbtc_index' : IDL.Opt(IDL.Principal),
+  });
+  const CertifiedAccount = IDL.Record({
     'balance' : IDL.Nat64,
-    'transaction_history' : IDL.Vec(TransactionLog),
-    'contestation_period_seconds' : IDL.Nat64,
+    'contestation_period' : IDL.Nat64,
+    'last_heartbeat' : IDL.Nat64,
+    'beneficiaries' : IDL.Vec(Beneficiary),
+    'timeout_duration' : IDL.Nat64,
+    'custody_mode' : CustodyMode,
+    'allowance' : IDL.Opt(AllowanceStatus),
+    'balance_drift' : IDL.Opt(BalanceDrift),
+    'payout' : IDL.Opt(PayoutPlan),
     'timeout_detected_at' : IDL.Opt(IDL.Nat64),
-    'trusted_parties' : IDL.Vec(IDL.Principal),
+  });
+  const PausePeriod = IDL.Record({
+    'ended_at' : IDL.Nat64,
+    'started_at' : IDL.Nat64,
+  });
+  const PauseState = IDL.Record({
+    'withdrawals' : IDL.Opt(ActivePause),
+    'heartbeats' : IDL.Opt(ActivePause),
+    'heartbeat_pauses' : IDL.Vec(PausePeriod),
+    'registrations' : IDL.Opt(ActivePause),
+    'payouts' : IDL.Opt(ActivePause),
+  });
+  const CertifiedStatus = IDL.Record({
+    'certificate' : IDL.Vec(IDL.Nat8),
+    'witness' : IDL.Vec(IDL.Nat8),
+    'account' : IDL.Opt(CertifiedAccount),
+    'pauses' : PauseState,
+  });
+  const CertifiedStatusResult = IDL.Variant({
+    'ok' : CertifiedStatus,
+    'err' : DeadManError,
+  });
+  const RoundStats = IDL.Record({
+    'accounts_processed' : IDL.Nat64,
+    'transfers_attempted' : IDL.Nat64,
+    'stopped_early' : IDL.Bool,
+    'started_at' : IDL.Nat64,
+    'finished_at' : IDL.Nat64,
+  });
+  const CheckerStatus = IDL.Record({
+    'scheduled_accounts' : IDL.Nat64,
+    'payouts_paused' : IDL.Bool,
+    'next_deadline' : IDL.Opt(IDL.Nat64),
+    'last_round' : IDL.Opt(RoundStats),
+    'round_running' : IDL.Bool,
+    'backlog' : IDL.Nat64,
+  });
+  const DepositAccountResult = IDL.Variant({
+    'ok' : Account,
+    'err' : DeadManError,
+  });
+  const EventKind = IDL.Variant({
+    'TimeoutDetected' : IDL.Null,
+    'SettingsChanged' : IDL.Null,
+    'TimeoutCancelled' : IDL.Null,
+    'Deposit' : IDL.Null,
+    'Heartbeat' : IDL.Null,
+    'BalanceDrift' : IDL.Null,
+    'PayoutStarted' : IDL.Null,
+    'PayoutTransfer' : IDL.Null,
+    'BalanceSynced' : IDL.Null,
+    'Withdrawal' : IDL.Null,
+    'PayoutTransferFailed' : IDL.Null,
+    'Legacy' : IDL.Null,
+    'Registered' : IDL.Null,
+  });
+  const HeartbeatResponse = IDL.Record({
+    'next_heartbeat_due' : IDL.Nat64,
+    'message' : IDL.Text,
+    'success' : IDL.Bool,
+  });
+  const HeartbeatResult = IDL.Variant({
+    'ok' : HeartbeatResponse,
+    'err' : DeadManError,
+  });
+  const HistoryEntry = IDL.Record({
+    'seq' : IDL.Nat64,
+    'event' : AccountEvent,
+    'timestamp' : IDL.Nat64,
+  });
+  const HistoryPage = IDL.Record({
+    'total' : IDL.Nat64,
+    'next' : IDL.Opt(IDL.Nat64),
+    'entries' : IDL.Vec(HistoryEntry),
+  });
+  const HistoryQuery = IDL.Record({
+    'to' : IDL.Opt(IDL.Nat64),
+    'from' : IDL.Opt(IDL.Nat64),
+    'limit' : IDL.Opt(IDL.Nat32),
+    'before' : IDL.Opt(IDL.Nat64),
+    'kinds' : IDL.Opt(IDL.Vec(EventKind)),
+  });
+  const ICRC3DataCertificate = IDL.Record({
+    'certificate' : IDL.Vec(IDL.Nat8),
+    'hash_tree' : IDL.Vec(IDL.Nat8),
+  });
+  const InitArgs = IDL.Record({
+    'ckbtc_index_canister_id' : IDL.Opt(IDL.Principal),
+    'ckbtc_ledger_canister_id' : IDL.Opt(IDL.Principal),
+    'features' : IDL.Opt(FeatureFlags),
+    'default_contestation_period_seconds' : IDL.Opt(IDL.Nat64),
+    'ckbtc_minter_canister_id' : IDL.Opt(IDL.Principal),
+    'history_retention_seconds' : IDL.Opt(IDL.Nat64),
+    'history_max_entries' : IDL.Opt(IDL.Nat64),
+    'check_interval_seconds' : IDL.Opt(IDL.Nat64),
+  });
+  const LegResolution = IDL.Variant({
+    'MarkPaid' : IDL.Record({
+      'block_index' : IDL.Nat64,
+    }),
+    'Retry' : IDL.Null,
+  });
+  const Side = IDL.Variant({
+    'Left' : IDL.Null,
+    'Right' : IDL.Null,
+  });
+  const ProofStep = IDL.Record({
+    'sum' : IDL.Nat64,
+    'hash' : IDL.Vec(IDL.Nat8),
+    'side' : Side,
+  });
+  const ReconciliationReport = IDL.Record({
+    'resolved' : IDL.Vec(IDL.Principal),
+    'checked' : IDL.Nat64,
+    'tracked_total' : IDL.Nat64,
+    'skipped' : IDL.Nat64,
+    'unreachable' : IDL.Nat64,
+    'ledger_total' : IDL.Nat64,
+    'shortfalls' : IDL.Vec(AccountBalances),
+    'unsynced' : IDL.Vec(AccountBalances),
+    'started_at' : IDL.Nat64,
+    'finished_at' : IDL.Nat64,
+  });
+  const ReconciliationReportResult = IDL.Variant({
+    'ok' : IDL.Opt(ReconciliationReport),
+    'err' : DeadManError,
+  });
+  const RegisterArgs = IDL.Record({
+    'timeout_duration_seconds' : IDL.Nat64,
+    'beneficiary' : IDL.Principal,
+    'beneficiary_subaccount' : IDL.Opt(IDL.Vec(IDL.Nat8)),
+  });
+  const ReservesSnapshot = IDL.Record({
+    'liabilities' : IDL.Nat64,
+    'root_hash' : IDL.Opt(IDL.Vec(IDL.Nat8)),
+    'accounts' : IDL.Nat64,
+    'ledger_total' : IDL.Nat64,
+    'taken_at' : IDL.Nat64,
+    'ledger_unreachable' : IDL.Nat32,
+  });
+  const ReservesProof = IDL.Record({
+    'certificate' : IDL.Vec(IDL.Nat8),
+    'snapshot' : ReservesSnapshot,
+    'balance' : IDL.Nat64,
+    'path' : IDL.Vec(ProofStep),
+    'witness' : IDL.Vec(IDL.Nat8),
+  });
+  const ReservesProofResult = IDL.Variant({
+    'ok' : ReservesProof,
+    'err' : DeadManError,
+  });
+  const Result = IDL.Variant({
+    'ok' : IDL.Text,
+    'err' : DeadManError,
+  });
+  const Role = IDL.Variant({
+    'Operator' : IDL.Null,
+    'Auditor' : IDL.Null,
+    'Controller' : IDL.Null,
+  });
+  const RolesResult = IDL.Variant({
+    'ok' : IDL.Vec(IDL.Tuple(IDL.Principal, Role)),
+    'err' : DeadManError,
+  });
+  const TimeoutStatus = IDL.Record({
+    'heartbeats_paused' : IDL.Bool,
+    'grace_period_end' : IDL.Nat64,
+    'payouts_paused' : IDL.Bool,
+    'allowance_expires_before_transfer' : IDL.Bool,
+    'in_grace_period' : IDL.Bool,
+    'contestation_period' : IDL.Nat64,
+    'time_until_transfer' : IDL.Nat64,
+    'time_until_timeout' : IDL.Nat64,
+    'last_heartbeat' : IDL.Nat64,
+    'timeout_reached' : IDL.Bool,
+    'timeout_duration' : IDL.Nat64,
+    'custody_mode' : CustodyMode,
+    'allowance' : IDL.Opt(AllowanceStatus),
+    'balance_drift' : IDL.Opt(BalanceDrift),
+    'payout' : IDL.Opt(PayoutPlan),
+  });
+  const TimeoutStatusResult = IDL.Variant({
+    'ok' : TimeoutStatus,
+    'err' : DeadManError,
+  });
+  const TransactionHistoryResult = IDL.Variant({
+    'ok' : HistoryPage,
+    'err' : DeadManError,
+  });
+  const UserPage = IDL.Record({
+    'total' : IDL.Nat64,
+    'next' : IDL.Opt(IDL.Principal),
+    'users' : IDL.Vec(IDL.Tuple(IDL.Principal, UserAccount)),
+  });
+  const UserPageResult = IDL.Variant({
+    'ok' : UserPage,
+    'err' : DeadManError,
   });
   return IDL.Service({
-    'get_account_info' : IDL.Func([], [IDL.Variant({ 'ok' : UserAccount, 'err' : IDL.Text })], ['query']),
-    'get_ckbtc_balance' : IDL.Func([], [IDL.Variant({ 'ok' : IDL.Nat64, 'err' : IDL.Text })], []),
-    'get_user_balance' : IDL.Func([], [IDL.Variant({ 'ok' : IDL.Nat64, 'err' : IDL.Text })], ['query']),
+    'add_beneficiary' : IDL.Func([Beneficiary], [Result], []),
+    'add_trusted_party' : IDL.Func([IDL.Principal], [Result], []),
+    'admin_force_process' : IDL.Func([IDL.Principal], [Result], []),
+    'admin_get_reconciliation_report' : IDL.Func([], [ReconciliationReportResult], ['query']),
+    'admin_grant_role' : IDL.Func([IDL.Principal, Role], [Result], []),
+    'admin_list_roles' : IDL.Func([], [RolesResult], ['query']),
+    'admin_list_users' : IDL.Func([IDL.Opt(IDL.Principal), IDL.Nat32], [UserPageResult], ['query']),
+    'admin_pause' : IDL.Func([IDL.Opt(PauseScope), IDL.Text], [Result], []),
+    'admin_poll_deposits' : IDL.Func([], [Result], []),
+    'admin_reconcile' : IDL.Func([], [Result], []),
+    'admin_resolve_payout_leg' : IDL.Func([IDL.Principal, IDL.Nat32, LegResolution], [Result], []),
+    'admin_resume' : IDL.Func([IDL.Opt(PauseScope)], [Result], []),
+    'admin_revoke_role' : IDL.Func([IDL.Principal], [Result], []),
+    'admin_snapshot_reserves' : IDL.Func([], [Result], []),
+    'admin_update_config' : IDL.Func([InitArgs], [Result], []),
+    'cancel_timeout_transfer' : IDL.Func([], [Result], []),
+    'deposit' : IDL.Func([IDL.Nat64], [Result], []),
+    'get_account_info' : IDL.Func([], [AccountInfoResult], ['query']),
+    'get_certified_status' : IDL.Func([IDL.Opt(IDL.Principal)], [CertifiedStatusResult], ['query']),
+    'get_checker_status' : IDL.Func([], [CheckerStatus], ['query']),
+    'get_ckbtc_balance' : IDL.Func([], [BalanceResult], []),
+    'get_config' : IDL.Func([], [CanisterConfig], ['query']),
+    'get_deposit_account' : IDL.Func([], [DepositAccountResult], ['query']),
+    'get_pause_status' : IDL.Func([], [PauseState], ['query']),
+    'get_reserves' : IDL.Func([], [IDL.Opt(ReservesSnapshot)], ['query']),
+    'get_reserves_proof' : IDL.Func([], [ReservesProofResult], ['query']),
+    'get_timeout_status' : IDL.Func([], [TimeoutStatusResult], ['query']),
+    'get_transaction_history' : IDL.Func([HistoryQuery], [TransactionHistoryResult], ['query']),
+    'get_user_balance' : IDL.Func([], [BalanceResult], ['query']),
     'greet' : IDL.Func([IDL.Text], [IDL.Text], ['query']),
-    'heartbeat' : IDL.Func([], [IDL.Variant({ 'ok' : HeartbeatResponse, 'err' : IDL.Text })], []),
-    'list_users' : IDL.Func([], [IDL.Vec(IDL.Tuple(IDL.Principal, UserAccount))], ['query']),
-    'register' : IDL.Func([RegisterArgs], [IDL.Variant({ 'ok' : IDL.Text, 'err' : IDL.Text })], []),
-    'deposit' : IDL.Func([IDL.Nat64], [IDL.Variant({ 'ok' : IDL.Text, 'err' : IDL.Text })], []),
-    'set_mock_balance' : IDL.Func([IDL.Nat64], [IDL.Variant({ 'ok' : IDL.Text, 'err' : IDL.Text })], []),
+    'heartbeat' : IDL.Func([], [HeartbeatResult], []),
+    'icrc3_get_blocks' : IDL.Func([IDL.Vec(GetBlocksRequest)], [GetBlocksResult], ['query']),
+    'icrc3_get_tip_certificate' : IDL.Func([], [IDL.Opt(ICRC3DataCertificate)], ['query']),
+    'refresh_allowance' : IDL.Func([], [AllowanceResult], []),
+    'register' : IDL.Func([RegisterArgs], [Result], []),
+    'remove_beneficiary' : IDL.Func([IDL.Principal], [Result], []),
+    'remove_trusted_party' : IDL.Func([IDL.Principal], [Result], []),
+    'set_beneficiaries' : IDL.Func([IDL.Vec(Beneficiary)], [Result], []),
+    'set_custody_mode' : IDL.Func([CustodyMode], [Result], []),
+    'set_mock_balance' : IDL.Func([IDL.Nat64], [Result], []),
+    'sync_balance' : IDL.Func([], [Result], []),
+    'update_contestation_period' : IDL.Func([IDL.Nat64], [Result], []),
+    'update_settings' : IDL.Func([IDL.Opt(IDL.Nat64), IDL.Opt(IDL.Principal), IDL.Opt(IDL.Vec(IDL.Nat8))], [Result], []),
+    'withdraw' : IDL.Func([IDL.Nat64, Account], [Result], []),
+  });
+};
+export const init = ({ IDL }) => {
+  const FeatureFlags = IDL.Record({
+    'allowance_mode' : IDL.Bool,
+    'trusted_party_cancellation' : IDL.Bool,
+    'mock_balance' : IDL.Bool,
+  });
+  const InitArgs = IDL.Record({
+    'ckbtc_index_canister_id' : IDL.Opt(IDL.Principal),
+    'ckbtc_ledger_canister_id' : IDL.Opt(IDL.Principal),
+    'features' : IDL.Opt(FeatureFlags),
+    'default_contestation_period_seconds' : IDL.Opt(IDL.Nat64),
+    'ckbtc_minter_canister_id' : IDL.Opt(IDL.Principal),
+    'history_retention_seconds' : IDL.Opt(IDL.Nat64),
+    'history_max_entries' : IDL.Opt(IDL.Nat64),
+    'check_interval_seconds' : IDL.Opt(IDL.Nat64),
   });
+  return [IDL.Opt(InitArgs)];
 };
-
@@ -280,15 +280,115 @@ export function getPrincipalType(principalText) {
   }
 }
 
+/**
+ * Describe an ICRC-1 TransferError or ICRC-2 TransferFromError variant
+ * @param {Object} error - The variant, e.g. { InsufficientFunds: { balance } }
+ * @returns {string} - Readable description
+ */
+function formatTransferError(error) {
+  const [kind, value] = Object.entries(error)[0];
+  switch (kind) {
+    case 'InsufficientFunds':
+      return `insufficient funds (balance ${value.balance})`;
+    case 'InsufficientAllowance':
+      return `insufficient allowance (${value.allowance} approved)`;
+    case 'BadFee':
+      return `wrong fee, the ledger expects ${value.expected_fee}`;
+    case 'BadBurn':
+      return `amount below the minimum burn of ${value.min_burn_amount}`;
+    case 'Duplicate':
+      return `duplicate of block ${value.duplicate_of}`;
+    case 'CreatedInFuture':
+      return 'transaction created in the future';
+    case 'TooOld':
+      return 'transaction too old';
+    case 'TemporarilyUnavailable':
+      return 'ledger temporarily unavailable';
+    case 'GenericError':
+      return `${value.message} (code ${value.error_code})`;
+    default:
+      return kind;
+  }
+}
+
+/**
+ * Describe a LedgerError variant returned by the canister
+ * @param {Object} error - The variant, e.g. { Transfer: { BadFee: { expected_fee } } }
+ * @returns {string} - Readable description
+ */
+function formatLedgerError(error) {
+  const [kind, value] = Object.entries(error)[0];
+  switch (kind) {
+    case 'CallFailed':
+      return `ledger call failed: ${value}`;
+    case 'Rejected':
+      return `ledger call rejected (code ${value.code}): ${value.message}`;
+    case 'Decode':
+      return `undecodable ledger reply: ${value}`;
+    case 'Transfer':
+    case 'TransferFrom':
+      return formatTransferError(value);
+    default:
+      return kind;
+  }
+}
+
+/**
+ * Describe a DeadManError variant returned in `result.err`, worded like the canister's logs
+ * @param {Object} error - The variant, e.g. { InsufficientBalance: { available, required } }
+ * @returns {string} - User-friendly error message
+ */
+function formatCanisterError(error) {
+  const [kind, value] = Object.entries(error)[0];
+  switch (kind) {
+    case 'NotRegistered':
+      return 'Account not found. Please register first.';
+    case 'AlreadyRegistered':
+      return 'You are already registered.';
+    case 'Unauthorized':
+      return `Not authorized: ${value.reason}`;
+    case 'InvalidArgument':
+      return `Invalid ${value.field}: ${value.reason}`;
+    case 'InsufficientBalance':
+      return `Insufficient balance: ${value.available} ckBTC available, ${value.required} ckBTC required`;
+    case 'Paused':
+      return `${Object.keys(value.scope)[0]} are paused: ${value.reason}`;
+    case 'PayoutInProgress':
+      return 'Payout already in progress';
+    case 'Busy':
+      return 'Another operation on this account is in progress, try again shortly';
+    case 'WrongCustodyMode':
+      return `Not supported in ${Object.keys(value.mode)[0]} custody mode`;
+    case 'FeatureDisabled':
+      return `${value.feature} is disabled on this deployment`;
+    case 'NotFound':
+      return `${value.what} not found`;
+    case 'Conflict':
+      return value.reason;
+    case 'Ledger':
+      return `Ledger error: ${formatLedgerError(value)}`;
+    case 'LedgerOutcomeUnknown':
+      return `Transfer outcome unknown (${formatLedgerError(value)}), retry it unchanged or sync your balance to settle`;
+    case 'Internal':
+      return `Internal error: ${value.message}`;
+    default:
+      return kind;
+  }
+}
+
 /**
  * Format error messages for better user experience
- * @param {Error|string} error - The error to format
+ * @param {Error|string|Object} error - The error to format: a thrown error, a message,
+ *   or the DeadManError variant of a canister result's `err`
  * @returns {string} - User-friendly error message
  */
 export function formatError(error) {
   if (typeof error === 'string') return error;
+  if (error && typeof error === 'object' && !(error instanceof Error)) {
+    return formatCanisterError(error);
+  }
   
-  const message = error.message || 'An unknown error occurred';
+  const message = error?.message || 'An unknown error occurred';
   
   // Common ICP error patterns
   if (message.includes('reject code')) {
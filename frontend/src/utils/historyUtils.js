@@ -0,0 +1,150 @@
+// Helpers for account history entries ({ seq, timestamp, event }) returned by get_transaction_history
+
+const E8S = 100_000_000;
+
+const ckbtc = (amount) => `${(Number(amount) / E8S).toFixed(8)} ckBTC`;
+const account = (acc) => acc.owner.toText();
+const variant = (value) => Object.keys(value)[0];
+
+// Category of each event, used for icons, colors and statistics
+const CATEGORIES = {
+  Registered: 'register',
+  Heartbeat: 'heartbeat',
+  DepositReceived: 'deposit',
+  LegacyBalanceMoved: 'deposit',
+  Withdrawal: 'withdrawal',
+  PayoutStarted: 'transfer',
+  PayoutTransfer: 'transfer',
+  PayoutTransferFailed: 'transfer',
+  TimeoutDetected: 'timeout',
+  TimeoutCancelled: 'timeout_cancelled',
+  BalanceSynced: 'balance_sync',
+  BalanceDriftDetected: 'balance_sync',
+  BalanceDriftResolved: 'balance_sync',
+};
+
+/**
+ * Fetch the caller's whole history, newest first, following `next` from page to page
+ * @param {Object} actor - Deadman switch actor
+ * @returns {Promise<Object>} - { ok: entries } or the canister's { err }
+ */
+export async function fetchHistory(actor) {
+  const entries = [];
+  let before = [];
+  for (;;) {
+    const result = await actor.get_transaction_history({ before, limit: [], from: [], to: [], kinds: [] });
+    if ('err' in result) return result;
+    entries.push(...result.ok.entries);
+    if (result.ok.next.length === 0) return { ok: entries };
+    before = result.ok.next;
+  }
+}
+
+/**
+ * Variant name of an entry's event
+ * @param {Object} entry - History entry
+ * @returns {string} - e.g. 'Heartbeat' or 'PayoutTransfer'
+ */
+export function eventType(entry) {
+  return variant(entry.event);
+}
+
+/**
+ * Category of an entry: register, heartbeat, deposit, withdrawal, transfer, timeout,
+ * timeout_cancelled, balance_sync or update. Entries from before typed events keep theirs.
+ * @param {Object} entry - History entry
+ * @returns {string} - Category
+ */
+export function eventCategory(entry) {
+  const type = eventType(entry);
+  if (type === 'Legacy') return entry.event.Legacy.transaction_type;
+  return CATEGORIES[type] || 'update';
+}
+
+/**
+ * Readable name of an entry's event
+ * @param {Object} entry - History entry
+ * @returns {string} - e.g. 'Payout transfer'
+ */
+export function eventLabel(entry) {
+  const type = eventType(entry);
+  const name = type === 'Legacy'
+    ? entry.event.Legacy.transaction_type.replace(/_/g, ' ')
+    : type.replace(/([a-z])([A-Z])/g, '$1 $2').toLowerCase();
+  return name.charAt(0).toUpperCase() + name.slice(1);
+}
+
+/**
+ * Amount of ckBTC the event moved, in e8s
+ * @param {Object} entry - History entry
+ * @returns {bigint|null} - Amount, or null if the event moved none
+ */
+export function eventAmount(entry) {
+  const [type, value] = Object.entries(entry.event)[0];
+  switch (type) {
+    case 'DepositReceived':
+    case 'Withdrawal':
+    case 'PayoutStarted':
+    case 'PayoutTransfer':
+    case 'PayoutTransferFailed':
+      return value.amount;
+    case 'LegacyBalanceMoved':
+      return value.credited;
+    case 'Legacy':
+      return value.amount.length > 0 ? value.amount[0] : null;
+    default:
+      return null;
+  }
+}
+
+/**
+ * One-line description of the event
+ * @param {Object} entry - History entry
+ * @returns {string} - Description, empty if there is nothing to add to the label
+ */
+export function eventDetails(entry) {
+  const [type, value] = Object.entries(entry.event)[0];
+  switch (type) {
+    case 'Registered':
+      return `Beneficiary ${account(value.beneficiary)}, timeout ${value.timeout}s`;
+    case 'Heartbeat':
+      return `Next heartbeat due ${new Date(Number(value.next_due) / 1_000_000).toLocaleString()}`;
+    case 'DepositReceived': {
+      const from = value.from.length > 0 ? ` from ${account(value.from[0])}` : ' (minted)';
+      const credited = value.credited < value.amount ? `, ${ckbtc(value.credited)} credited` : '';
+      return `Ledger block ${value.block_index}${from}${credited}`;
+    }
+    case 'LegacyBalanceMoved':
+      return `Balance of ${ckbtc(value.legacy)} from an older version, moved from the canister's main account into your deposit account`;
+    case 'Withdrawal':
+    case 'PayoutTransfer':
+      return `To ${account(value.to)}, fee ${ckbtc(value.fee)}, ledger block ${value.block_index}`;
+    case 'PayoutStarted':
+      return `${value.transfers} transfer${value.transfers === 1 ? '' : 's'} planned`;
+    case 'PayoutTransferFailed':
+      return `Attempt ${value.attempt} to ${account(value.to)} failed: ${value.error}`;
+    case 'TimeoutDetected':
+      return `Contestation period of ${value.contestation_period}s started`;
+    case 'TimeoutCancelled':
+      return `Cancelled by ${value.by.toText()}`;
+    case 'BalanceSynced':
+      return `${ckbtc(value.previous)} → ${ckbtc(value.current)}`;
+    case 'BalanceDriftDetected':
+    case 'BalanceDriftResolved':
+      return `Tracked ${ckbtc(value.tracked)}, ledger ${ckbtc(value.ledger)}`;
+    case 'TimeoutChanged':
+    case 'ContestationPeriodChanged':
+      return `${value.old}s → ${value.new}s`;
+    case 'BeneficiariesChanged':
+      return `${value.old.length} → ${value.new.length} beneficiar${value.new.length === 1 ? 'y' : 'ies'}`;
+    case 'CustodyModeChanged':
+      return `${variant(value.old)} → ${variant(value.new)}`;
+    case 'TrustedPartyAdded':
+    case 'TrustedPartyRemoved':
+      return value.principal.toText();
+    case 'Legacy':
+      return value.details;
+    default:
+      return '';
+  }
+}
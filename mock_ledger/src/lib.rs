@@ -0,0 +1,585 @@
+//! Mock ckBTC ledger for local demos.
+//!
+//! Implements the parts of ICRC-1 and ICRC-2 the dead man switch uses, with the
+//! same error semantics as the real ledger (fees, insufficient funds, allowances,
+//! deduplication on `created_at_time`), plus a `mint` endpoint anyone can call so
+//! demo accounts can be funded. It also answers the index canister's
+//! `get_account_transactions`, so it can stand in for the ckBTC index as well.
+//! State is kept on the heap and is lost on upgrade.
+
+use candid::{CandidType, Deserialize, Nat};
+use ic_cdk::{api::msg_caller, api::time, init, query, update};
+use icrc_ledger_types::{
+    icrc1::account::Account,
+    icrc1::transfer::{Memo, TransferArg, TransferError},
+    icrc2::allowance::{Allowance, AllowanceArgs},
+    icrc2::approve::{ApproveArgs, ApproveError},
+    icrc2::transfer_from::{TransferFromArgs, TransferFromError},
+    icrc3::transactions::{Approve, Mint, Transaction, Transfer},
+};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+// Same as the ckBTC ledger: 10 satoshi per transfer
+const DEFAULT_FEE: u64 = 10;
+
+// ICRC-1 deduplication window and the permitted clock drift, in nanoseconds
+const TX_WINDOW_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
+const PERMITTED_DRIFT_NANOS: u64 = 60 * 1_000_000_000;
+
+thread_local! {
+    static STATE: RefCell<LedgerState> = RefCell::default();
+}
+
+#[derive(Default)]
+struct LedgerState {
+    fee: u64,
+    balances: BTreeMap<Account, u64>,
+    allowances: BTreeMap<(Account, Account), StoredAllowance>, // (owner, spender)
+    blocks: Vec<Block>,
+    // Transactions that carried a created_at_time, for deduplication
+    recent: BTreeMap<TxKey, u64>,
+}
+
+struct StoredAllowance {
+    amount: u64,
+    expires_at: Option<u64>,
+}
+
+/// A ledger entry, kept so block indices are real and monotonic
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct Block {
+    pub kind: String, // "mint", "transfer", "approve"
+    pub from: Option<Account>,
+    pub to: Option<Account>,
+    pub spender: Option<Account>,
+    pub amount: u64,
+    pub fee: u64,
+    pub memo: Option<Memo>,
+    pub timestamp: u64,
+}
+
+/// Everything that makes two requests "the same transaction" under ICRC-1 deduplication
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct TxKey {
+    kind: &'static str,
+    caller: Account,
+    from: Account,
+    to: Account,
+    amount: u64,
+    fee: Option<u64>,
+    memo: Option<Memo>,
+    created_at_time: u64,
+}
+
+impl Block {
+    /// The block as the index canister reports it
+    fn to_transaction(&self) -> Transaction {
+        let memo = self.memo.clone();
+        match (self.kind.as_str(), self.from, self.to, self.spender) {
+            ("mint", _, Some(to), _) => Transaction::mint(
+                Mint {
+                    amount: self.amount.into(),
+                    to,
+                    memo,
+                    created_at_time: None,
+                    fee: None,
+                },
+                self.timestamp,
+            ),
+            ("approve", Some(from), _, Some(spender)) => Transaction::approve(
+                Approve {
+                    from,
+                    spender,
+                    amount: self.amount.into(),
+                    expected_allowance: None,
+                    expires_at: None,
+                    memo,
+                    fee: Some(self.fee.into()),
+                    created_at_time: None,
+                },
+                self.timestamp,
+            ),
+            (_, Some(from), Some(to), spender) => Transaction::transfer(
+                Transfer {
+                    amount: self.amount.into(),
+                    from,
+                    to,
+                    spender,
+                    memo,
+                    fee: Some(self.fee.into()),
+                    created_at_time: None,
+                },
+                self.timestamp,
+            ),
+            _ => unreachable!("blocks are only built by the endpoints below"),
+        }
+    }
+
+    fn involves(&self, account: &Account) -> bool {
+        [self.from, self.to, self.spender].contains(&Some(*account))
+    }
+}
+
+#[derive(CandidType, Deserialize, Debug, Default)]
+pub struct MockLedgerArgs {
+    pub fee: Option<u64>,
+    pub initial_balances: Vec<(Account, u64)>,
+}
+
+/// Shared failure modes of transfer, transfer_from and approve, mapped into each
+/// endpoint's own error type
+enum CommonError {
+    BadFee(u64),
+    TooOld,
+    CreatedInFuture(u64),
+    Duplicate(u64),
+    InsufficientFunds(u64),
+    AmountTooLarge,
+}
+
+impl From<CommonError> for TransferError {
+    fn from(e: CommonError) -> Self {
+        match e {
+            CommonError::BadFee(fee) => TransferError::BadFee { expected_fee: fee.into() },
+            CommonError::TooOld => TransferError::TooOld,
+            CommonError::CreatedInFuture(ledger_time) => TransferError::CreatedInFuture { ledger_time },
+            CommonError::Duplicate(block) => TransferError::Duplicate { duplicate_of: block.into() },
+            CommonError::InsufficientFunds(balance) => TransferError::InsufficientFunds { balance: balance.into() },
+            CommonError::AmountTooLarge => TransferError::GenericError {
+                error_code: Nat::from(1u64),
+                message: "Amount does not fit in 64 bits".to_string(),
+            },
+        }
+    }
+}
+
+impl From<CommonError> for TransferFromError {
+    fn from(e: CommonError) -> Self {
+        match e {
+            CommonError::BadFee(fee) => TransferFromError::BadFee { expected_fee: fee.into() },
+            CommonError::TooOld => TransferFromError::TooOld,
+            CommonError::CreatedInFuture(ledger_time) => TransferFromError::CreatedInFuture { ledger_time },
+            CommonError::Duplicate(block) => TransferFromError::Duplicate { duplicate_of: block.into() },
+            CommonError::InsufficientFunds(balance) => {
+                TransferFromError::InsufficientFunds { balance: balance.into() }
+            }
+            CommonError::AmountTooLarge => TransferFromError::GenericError {
+                error_code: Nat::from(1u64),
+                message: "Amount does not fit in 64 bits".to_string(),
+            },
+        }
+    }
+}
+
+impl From<CommonError> for ApproveError {
+    fn from(e: CommonError) -> Self {
+        match e {
+            CommonError::BadFee(fee) => ApproveError::BadFee { expected_fee: fee.into() },
+            CommonError::TooOld => ApproveError::TooOld,
+            CommonError::CreatedInFuture(ledger_time) => ApproveError::CreatedInFuture { ledger_time },
+            CommonError::Duplicate(block) => ApproveError::Duplicate { duplicate_of: block.into() },
+            CommonError::InsufficientFunds(balance) => ApproveError::InsufficientFunds { balance: balance.into() },
+            CommonError::AmountTooLarge => ApproveError::GenericError {
+                error_code: Nat::from(1u64),
+                message: "Amount does not fit in 64 bits".to_string(),
+            },
+        }
+    }
+}
+
+fn nat_to_u64(n: &Nat) -> Result<u64, CommonError> {
+    u64::try_from(n.0.clone()).map_err(|_| CommonError::AmountTooLarge)
+}
+
+impl LedgerState {
+    fn balance(&self, account: &Account) -> u64 {
+        self.balances.get(account).copied().unwrap_or(0)
+    }
+
+    fn credit(&mut self, account: Account, amount: u64) {
+        let balance = self.balances.entry(account).or_insert(0);
+        *balance = balance.saturating_add(amount);
+    }
+
+    fn debit(&mut self, account: Account, amount: u64) -> Result<(), CommonError> {
+        let balance = self.balance(&account);
+        if balance < amount {
+            return Err(CommonError::InsufficientFunds(balance));
+        }
+        self.balances.insert(account, balance - amount);
+        Ok(())
+    }
+
+    fn check_fee(&self, fee: &Option<Nat>) -> Result<(), CommonError> {
+        match fee {
+            Some(fee) if nat_to_u64(fee)? != self.fee => Err(CommonError::BadFee(self.fee)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Reject stale or future transactions and return the block of an identical earlier one
+    fn check_dedup(&self, key: &Option<TxKey>) -> Result<(), CommonError> {
+        let Some(key) = key else {
+            return Ok(());
+        };
+        let now = time();
+        if key.created_at_time.saturating_add(TX_WINDOW_NANOS + PERMITTED_DRIFT_NANOS) < now {
+            return Err(CommonError::TooOld);
+        }
+        if key.created_at_time > now.saturating_add(PERMITTED_DRIFT_NANOS) {
+            return Err(CommonError::CreatedInFuture(now));
+        }
+        match self.recent.get(key) {
+            Some(block) => Err(CommonError::Duplicate(*block)),
+            None => Ok(()),
+        }
+    }
+
+    fn push_block(&mut self, block: Block, key: Option<TxKey>) -> u64 {
+        let index = self.blocks.len() as u64;
+        self.blocks.push(block);
+        if let Some(key) = key {
+            self.recent.insert(key, index);
+        }
+        index
+    }
+}
+
+fn caller_account(subaccount: Option<[u8; 32]>) -> Account {
+    Account {
+        owner: msg_caller(),
+        subaccount,
+    }
+}
+
+#[init]
+fn init(args: Option<MockLedgerArgs>) {
+    let args = args.unwrap_or_default();
+    STATE.with(|state| {
+        let mut s = state.borrow_mut();
+        s.fee = args.fee.unwrap_or(DEFAULT_FEE);
+        for (account, amount) in args.initial_balances {
+            s.credit(account, amount);
+        }
+    });
+    ic_cdk::println!("Mock ckBTC ledger initialized");
+}
+
+#[query]
+fn icrc1_name() -> String {
+    "Mock ckBTC".to_string()
+}
+
+#[query]
+fn icrc1_symbol() -> String {
+    "ckBTC".to_string()
+}
+
+#[query]
+fn icrc1_decimals() -> u8 {
+    8
+}
+
+#[query]
+fn icrc1_fee() -> Nat {
+    STATE.with(|state| Nat::from(state.borrow().fee))
+}
+
+#[query]
+fn icrc1_total_supply() -> Nat {
+    STATE.with(|state| Nat::from(state.borrow().balances.values().sum::<u64>()))
+}
+
+#[query]
+fn icrc1_minting_account() -> Option<Account> {
+    None
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct SupportedStandard {
+    pub name: String,
+    pub url: String,
+}
+
+#[query]
+fn icrc1_supported_standards() -> Vec<SupportedStandard> {
+    vec![
+        SupportedStandard {
+            name: "ICRC-1".to_string(),
+            url: "https://github.com/dfinity/ICRC-1/tree/main/standards/ICRC-1".to_string(),
+        },
+        SupportedStandard {
+            name: "ICRC-2".to_string(),
+            url: "https://github.com/dfinity/ICRC-1/tree/main/standards/ICRC-2".to_string(),
+        },
+    ]
+}
+
+#[query]
+fn icrc1_balance_of(account: Account) -> Nat {
+    STATE.with(|state| Nat::from(state.borrow().balance(&account)))
+}
+
+#[update]
+fn icrc1_transfer(args: TransferArg) -> Result<Nat, TransferError> {
+    let from = caller_account(args.from_subaccount);
+    let amount = nat_to_u64(&args.amount)?;
+
+    STATE.with(|state| {
+        let mut s = state.borrow_mut();
+        s.check_fee(&args.fee)?;
+        let key = args.created_at_time.map(|created_at_time| TxKey {
+            kind: "transfer",
+            caller: from,
+            from,
+            to: args.to,
+            amount,
+            fee: args.fee.as_ref().and_then(|fee| nat_to_u64(fee).ok()),
+            memo: args.memo.clone(),
+            created_at_time,
+        });
+        s.check_dedup(&key)?;
+
+        let fee = s.fee;
+        s.debit(from, amount.saturating_add(fee))?;
+        s.credit(args.to, amount);
+        let block = Block {
+            kind: "transfer".to_string(),
+            from: Some(from),
+            to: Some(args.to),
+            spender: None,
+            amount,
+            fee,
+            memo: args.memo,
+            timestamp: time(),
+        };
+        Ok(Nat::from(s.push_block(block, key)))
+    })
+}
+
+#[update]
+fn icrc2_approve(args: ApproveArgs) -> Result<Nat, ApproveError> {
+    let owner = caller_account(args.from_subaccount);
+    let amount = nat_to_u64(&args.amount)?;
+    let now = time();
+
+    STATE.with(|state| {
+        let mut s = state.borrow_mut();
+        s.check_fee(&args.fee)?;
+        if args.expires_at.is_some_and(|expires_at| expires_at <= now) {
+            return Err(ApproveError::Expired { ledger_time: now });
+        }
+        let key = args.created_at_time.map(|created_at_time| TxKey {
+            kind: "approve",
+            caller: owner,
+            from: owner,
+            to: args.spender,
+            amount,
+            fee: args.fee.as_ref().and_then(|fee| nat_to_u64(fee).ok()),
+            memo: args.memo.clone(),
+            created_at_time,
+        });
+        s.check_dedup(&key)?;
+
+        let current = s
+            .allowances
+            .get(&(owner, args.spender))
+            .filter(|a| a.expires_at.is_none_or(|expires_at| expires_at > now))
+            .map(|a| a.amount)
+            .unwrap_or(0);
+        if let Some(expected) = &args.expected_allowance {
+            if nat_to_u64(expected)? != current {
+                return Err(ApproveError::AllowanceChanged {
+                    current_allowance: current.into(),
+                });
+            }
+        }
+
+        let fee = s.fee;
+        s.debit(owner, fee)?;
+        s.allowances.insert(
+            (owner, args.spender),
+            StoredAllowance {
+                amount,
+                expires_at: args.expires_at,
+            },
+        );
+        let block = Block {
+            kind: "approve".to_string(),
+            from: Some(owner),
+            to: None,
+            spender: Some(args.spender),
+            amount,
+            fee,
+            memo: args.memo,
+            timestamp: now,
+        };
+        Ok(Nat::from(s.push_block(block, key)))
+    })
+}
+
+#[query]
+fn icrc2_allowance(args: AllowanceArgs) -> Allowance {
+    let now = time();
+    STATE.with(|state| {
+        let s = state.borrow();
+        match s
+            .allowances
+            .get(&(args.account, args.spender))
+            .filter(|a| a.expires_at.is_none_or(|expires_at| expires_at > now))
+        {
+            Some(a) => Allowance {
+                allowance: a.amount.into(),
+                expires_at: a.expires_at,
+            },
+            None => Allowance {
+                allowance: Nat::from(0u64),
+                expires_at: None,
+            },
+        }
+    })
+}
+
+#[update]
+fn icrc2_transfer_from(args: TransferFromArgs) -> Result<Nat, TransferFromError> {
+    let spender = caller_account(args.spender_subaccount);
+    let amount = nat_to_u64(&args.amount)?;
+    let now = time();
+
+    STATE.with(|state| {
+        let mut s = state.borrow_mut();
+        s.check_fee(&args.fee)?;
+        let key = args.created_at_time.map(|created_at_time| TxKey {
+            kind: "transfer_from",
+            caller: spender,
+            from: args.from,
+            to: args.to,
+            amount,
+            fee: args.fee.as_ref().and_then(|fee| nat_to_u64(fee).ok()),
+            memo: args.memo.clone(),
+            created_at_time,
+        });
+        s.check_dedup(&key)?;
+
+        let fee = s.fee;
+        let total = amount.saturating_add(fee);
+        let allowance = s
+            .allowances
+            .get(&(args.from, spender))
+            .filter(|a| a.expires_at.is_none_or(|expires_at| expires_at > now))
+            .map(|a| a.amount)
+            .unwrap_or(0);
+        if allowance < total {
+            return Err(TransferFromError::InsufficientAllowance {
+                allowance: allowance.into(),
+            });
+        }
+
+        s.debit(args.from, total)?;
+        s.credit(args.to, amount);
+        if let Some(a) = s.allowances.get_mut(&(args.from, spender)) {
+            a.amount -= total;
+        }
+        let block = Block {
+            kind: "transfer".to_string(),
+            from: Some(args.from),
+            to: Some(args.to),
+            spender: Some(spender),
+            amount,
+            fee,
+            memo: args.memo,
+            timestamp: now,
+        };
+        Ok(Nat::from(s.push_block(block, key)))
+    })
+}
+
+/// DEMO ONLY: create tokens out of thin air. Anyone may call it.
+#[update]
+fn mint(to: Account, amount: Nat) -> Result<Nat, String> {
+    let amount = u64::try_from(amount.0).map_err(|_| "Amount does not fit in 64 bits".to_string())?;
+    let caller = msg_caller();
+
+    STATE.with(|state| {
+        let mut s = state.borrow_mut();
+        s.credit(to, amount);
+        let block = Block {
+            kind: "mint".to_string(),
+            from: None,
+            to: Some(to),
+            spender: None,
+            amount,
+            fee: 0,
+            memo: None,
+            timestamp: time(),
+        };
+        let index = s.push_block(block, None);
+        ic_cdk::println!("Minted {} to {} for {}", amount, to, caller);
+        Ok(Nat::from(index))
+    })
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct GetAccountTransactionsArgs {
+    pub account: Account,
+    pub start: Option<Nat>, // Only transactions older than this id; the newest if None
+    pub max_results: Nat,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct TransactionWithId {
+    pub id: Nat,
+    pub transaction: Transaction,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct GetTransactions {
+    pub balance: Nat,
+    pub transactions: Vec<TransactionWithId>,
+    pub oldest_tx_id: Option<Nat>,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+pub struct GetTransactionsErr {
+    pub message: String,
+}
+
+/// Index canister stand-in: the account's transactions, newest first
+#[query]
+fn get_account_transactions(args: GetAccountTransactionsArgs) -> Result<GetTransactions, GetTransactionsErr> {
+    let start = match &args.start {
+        Some(start) => u64::try_from(start.0.clone()).unwrap_or(u64::MAX),
+        None => u64::MAX,
+    };
+    let max_results = usize::try_from(args.max_results.0).unwrap_or(usize::MAX);
+
+    STATE.with(|state| {
+        let s = state.borrow();
+        let mut involved = s
+            .blocks
+            .iter()
+            .enumerate()
+            .filter(|(_, block)| block.involves(&args.account))
+            .map(|(id, block)| (id as u64, block));
+        let oldest_tx_id = involved.next().map(|(id, _)| Nat::from(id));
+        let transactions = s
+            .blocks
+            .iter()
+            .enumerate()
+            .rev()
+            .map(|(id, block)| (id as u64, block))
+            .filter(|(id, block)| *id < start && block.involves(&args.account))
+            .take(max_results)
+            .map(|(id, block)| TransactionWithId {
+                id: Nat::from(id),
+                transaction: block.to_transaction(),
+            })
+            .collect();
+        Ok(GetTransactions {
+            balance: Nat::from(s.balance(&args.account)),
+            transactions,
+            oldest_tx_id,
+        })
+    })
+}